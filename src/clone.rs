@@ -1,13 +1,36 @@
-use git2::{build::RepoBuilder, ErrorCode, FetchOptions};
+use git2::{build::RepoBuilder, ErrorCode, FetchOptions, Repository};
 use crate::{data::{CloneMode, ConfigFile, LocalRepo, RepoDefn}, remote_callbacks::configure_callbacks};
-use std::{error::Error, fs::create_dir_all, path::PathBuf};
+use crate::error::{ErrorKind, RepoError, Stage};
+use std::{error::Error, fs::create_dir_all, path::{Path, PathBuf}};
 use log::{info, warn};
-use crate::gitutils::clean_repo_by_path;
+use crate::gitutils::{checkout_branch, clean_repo_by_path, detect_default_branch};
+
+//Works out which branch we should be working from, and makes sure that it is checked out.
+//If the RepoDefn already knows its default branch (e.g. from the Github API) then that is used, otherwise
+//we take it from the remote HEAD of the clone.
+fn resolve_default_branch(repo:&Repository, src:&RepoDefn) -> Result<String, Box<dyn Error>> {
+    match src.main_branch_name.as_ref() {
+        Some(branch)=>{
+            checkout_branch(repo, branch)?;
+            Ok(branch.to_owned())
+        },
+        None=>detect_default_branch(repo),
+    }
+}
+
+fn failed_clone(src:RepoDefn, clone_path:&Path, err:&(dyn Error + 'static)) -> Box<LocalRepo> {
+    Box::new(LocalRepo {
+        defn: src,
+        local_path: clone_path.to_owned().into(),
+        last_error: Some(RepoError::from_error(Stage::Clone, err)),
+    })
+}
 
 //Clones the given repo to the current directory
-//This will only return an error if there is a system error creating the directory; otherwise, it will retrun a LocalRepo object containing the error description.
+//The repo is cloned at its default branch, which is recorded in the `main_branch_name` of the returned LocalRepo's defn.
+//If anything goes wrong, including creating the directory, then the LocalRepo that is returned contains the error description.
 //Check for this with LocalRepo::is_failed
-pub fn clone_repo<'a, 'b>(client:&'a mut RepoBuilder<'b>, src:RepoDefn, branch:&str, path_override:Option<String>, mode:&'b CloneMode, app_config:&ConfigFile) -> Result<Box<LocalRepo>, Box<dyn Error>> {
+pub fn clone_repo<'b>(client:&mut RepoBuilder<'b>, mut src:RepoDefn, path_override:Option<String>, mode:&'b CloneMode, app_config:&ConfigFile) -> Box<LocalRepo> {
     let clone_path = match path_override {
         Some(p)=>{
             let mut buf = PathBuf::new();
@@ -22,47 +45,56 @@ pub fn clone_repo<'a, 'b>(client:&'a mut RepoBuilder<'b>, src:RepoDefn, branch:&
         }
     };
 
-    let clone_uri = match mode {
-        CloneMode::Ssh => src.clone_uri_ssh(),
-        CloneMode::Https => src.clone_uri_https(),
-    };
+    let clone_uri = src.clone_uri(mode.clone());
 
     let mut opts:FetchOptions<'b> = FetchOptions::new();
     opts.remote_callbacks(configure_callbacks(Some(mode), app_config));
     client.fetch_options(opts);
 
     info!("⬇️ Cloning {} into {}...", &clone_uri, clone_path.to_string_lossy());
-    create_dir_all(clone_path.as_path())?;
+    if let Err(e) = create_dir_all(clone_path.as_path()) {
+        return failed_clone(src, &clone_path, &e);
+    }
 
-    match client.branch(branch).clone(&clone_uri, clone_path.as_path()) {
-        Ok(_) => Ok( Box::new(LocalRepo {
-            defn: src,
-            local_path: clone_path.to_owned().into(),
-            last_error: None,
-        }) ),
+    match client.clone(&clone_uri, clone_path.as_path()) {
+        Ok(repo) => match resolve_default_branch(&repo, &src) {
+            Ok(branch)=>{
+                info!("🌳 Default branch of {} is {}", src, branch);
+                src.main_branch_name = Some(branch);
+                Box::new(LocalRepo {
+                    defn: src,
+                    local_path: clone_path.to_owned().into(),
+                    last_error: None,
+                })
+            },
+            Err(e)=>failed_clone(src, &clone_path, e.as_ref()),
+        },
         Err(ref e@ git2::Error{..}) if e.code()==ErrorCode::Exists=>{
             //If we couldn't clone because there was already something there, that's OK
             warn!("👉 {}", e.message());
-            match clean_repo_by_path(clone_path.as_path(), "main") {
-                Ok(_) => 
-                    Ok( Box::new(LocalRepo {
+            let branch_result = Repository::open(clone_path.as_path())
+                .map_err(|e| e.into())
+                .and_then(|repo| match src.main_branch_name.as_ref() {
+                    Some(branch)=>Ok(branch.to_owned()),
+                    None=>detect_default_branch(&repo),
+                });
+
+            match branch_result.and_then(|branch| clean_repo_by_path(clone_path.as_path(), &branch).map(|_| branch)) {
+                Ok(branch) => {
+                    src.main_branch_name = Some(branch);
+                    Box::new(LocalRepo {
                         defn: src,
                         local_path: clone_path.to_owned().into(),
                         last_error: None,
-                    }) ),
-                Err(other) => {
-                    Ok( Box::new(LocalRepo {
-                        defn: src,
-                        local_path: clone_path.to_owned().into(),
-                        last_error: Some(other.to_string())
-                    }) )
-                }
+                    })
+                },
+                Err(other) => failed_clone(src, &clone_path, other.as_ref()),
             }
         },
-        Err(other)=>Ok( Box::new(LocalRepo {
+        Err(other)=>Box::new(LocalRepo {
             defn: src,
             local_path: clone_path.to_owned().into(),
-            last_error: Some(other.message().to_owned())
-        }) )
+            last_error: Some(RepoError::new(Stage::Clone, ErrorKind::of(&other), other.message().to_owned())),
+        }),
     }
 }
@@ -0,0 +1,487 @@
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+use std::collections::BTreeMap;
+use log::info;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::crossterm::execute;
+use ratatui::crossterm::terminal::{disable_raw_mode, LeaveAlternateScreen};
+use ratatui::layout::{Constraint, Layout, Rect};
+use ratatui::style::{Color, Modifier, Style, Stylize};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Paragraph, Row, Table, TableState, Wrap};
+use ratatui::{DefaultTerminal, Frame};
+
+use crate::data::{read_datafile, write_datafile, BaseStateDefn, DataElement, PatchedRepo};
+use crate::pool::{capture_log, captured_log};
+use crate::progress::{current_stage, transfers, Transfer};
+use crate::reset::{change_repos, select_repos, RepoAction};
+use crate::status::{last_error, pr_url, status_text, RepoStatus};
+
+//How often the screen is redrawn, and the state file checked for changes
+const TICK:Duration = Duration::from_millis(250);
+
+//How the run is going, so that the dashboard can say so
+#[derive(Clone)]
+enum RunStatus {
+    Running,
+    Finished(Result<(), String>),
+}
+
+/**
+ * A full-screen view of the campaign, which is drawn on its own thread while the pipeline runs.  It reads the repos
+ * from the state file whenever that is written, so it always shows what the pipeline has saved, and the log is shown in
+ * a pane of its own rather than being written over it.
+ *
+ * The dashboard can't change the state itself, as the pipeline owns it, so retrying or skipping a repo is sent back to
+ * the pipeline, which picks it up between stages.  Once the run has finished the dashboard stays up, and retrying a
+ * repo then runs the pipeline again.
+ */
+pub struct Dashboard {
+    commands: Receiver<(String, RepoAction)>,
+    stop: Arc<AtomicBool>,
+    status: Arc<Mutex<RunStatus>>,
+    //set when a repo is retried, so that the pipeline is run again for it
+    retried: AtomicBool,
+    ui: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl Dashboard {
+    pub fn start(state_file:&Path) -> Dashboard {
+        let (tx, rx) = mpsc::channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let status = Arc::new(Mutex::new(RunStatus::Running));
+
+        capture_log(true);
+        let ui = {
+            let mut app = App::new(state_file.to_path_buf(), tx, stop.clone(), status.clone());
+            thread::spawn(move || {
+                let mut terminal = ratatui::init();
+                let result = app.run(&mut terminal);
+                ratatui::restore();
+                //the dashboard has gone, so anything else that happens needs to be seen
+                capture_log(false);
+                result
+            })
+        };
+
+        Dashboard { commands: rx, stop, status, retried: AtomicBool::new(false), ui: Some(ui) }
+    }
+
+    //Retries or skips the repo, as asked for from the dashboard
+    fn apply(&self, (repo, action):(String, RepoAction), state:&mut BaseStateDefn) {
+        let changed = select_repos(state, &[repo], None).map(|selected| change_repos(state, &selected, action));
+        if let (Ok(1..), RepoAction::Retry) = (changed, action) {
+            self.retried.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /**
+     * Called between the stages of the pipeline, to apply anything that has been asked for from the dashboard since
+     * the last stage.  Returns an error if the dashboard was closed, to stop the run.
+     */
+    pub fn checkpoint(&self, state:&mut BaseStateDefn, state_file:&Path) -> Result<(), Box<dyn Error>> {
+        let mut changed = false;
+        for command in self.commands.try_iter() {
+            self.apply(command, state);
+            changed = true;
+        }
+        if changed {
+            write_datafile(state_file, state)?;
+        }
+        if self.stop.load(Ordering::Relaxed) {
+            info!("✋ The dashboard was closed, so stopping here");
+            return Err(Box::from("Stopped from the dashboard"));
+        }
+        Ok( () )
+    }
+
+    pub fn finished(&self, result:&Result<(), Box<dyn Error>>) {
+        *self.status.lock().unwrap() = RunStatus::Finished(result.as_ref().map(|_| ()).map_err(|e| e.to_string()));
+    }
+
+    /**
+     * Once the run has finished, waits until the user either retries a repo or closes the dashboard.  Returns true if
+     * the pipeline should be run again.  Skipping a repo in the meantime just updates the state file.
+     */
+    pub fn wait_for_retry(&self, state:&mut BaseStateDefn, state_file:&Path) -> Result<bool, Box<dyn Error>> {
+        loop {
+            if self.retried.swap(false, Ordering::Relaxed) {
+                *self.status.lock().unwrap() = RunStatus::Running;
+                return Ok( true );
+            }
+            if self.stop.load(Ordering::Relaxed) {
+                return Ok( false );
+            }
+            match self.commands.recv_timeout(TICK) {
+                Ok(command)=>{
+                    self.apply(command, state);
+                    write_datafile(state_file, state)?;
+                },
+                Err(RecvTimeoutError::Timeout)=>(),
+                Err(RecvTimeoutError::Disconnected)=>return Ok( false ),
+            }
+        }
+    }
+
+    //Waits for the user to close the dashboard, and gives the terminal back
+    pub fn close(mut self) -> Result<(), Box<dyn Error>> {
+        if let Some(ui) = self.ui.take() {
+            match ui.join() {
+                Ok(result)=>result?,
+                Err(_)=>return Err(Box::from("The dashboard crashed")),
+            }
+        }
+        Ok( () )
+    }
+}
+
+impl Drop for Dashboard {
+    //If the run gives up without closing the dashboard, e.g. on an error, the dashboard is stopped and the terminal given back
+    fn drop(&mut self) {
+        if let Some(ui) = self.ui.take() {
+            self.stop.store(true, Ordering::Relaxed);
+            let _ = ui.join();
+            let _ = disable_raw_mode();
+            let _ = execute!(io::stdout(), LeaveAlternateScreen);
+        }
+    }
+}
+
+//Gets rid of the terminal colour codes in a log line, as the dashboard has its own idea of what goes where
+fn strip_ansi(line:&str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c=='\u{1b}' {
+            //an escape sequence is ESC [ then parameters, up to a letter
+            if chars.next()==Some('[') {
+                for c in chars.by_ref() {
+                    if c.is_ascii_alphabetic() || c=='~' {
+                        break;
+                    }
+                }
+            }
+        } else {
+            stripped.push(c);
+        }
+    }
+    stripped
+}
+
+fn patched_of(elmt:&DataElement) -> Option<&PatchedRepo> {
+    match elmt {
+        DataElement::PRdRepo(repo)=>Some(&repo.branched.patched),
+        DataElement::BranchedRepo(repo)=>Some(&repo.patched),
+        DataElement::PatchedRepo(repo)=>Some(repo),
+        _=>None,
+    }
+}
+
+//What goes in the last column for a repo: how its transfer is going if one is, otherwise its PR or why it failed
+fn progress_text(elmt:&DataElement, transfers:&BTreeMap<String, Transfer>) -> String {
+    match (transfers.get(&elmt.defn().to_string()), pr_url(elmt), last_error(elmt)) {
+        (Some(transfer), _, _)=>transfer.to_string(),
+        (None, Some(url), _)=>url.to_string(),
+        (None, None, Some(err))=>err.lines().next().unwrap_or_default().to_string(),
+        (None, None, None)=>String::new(),
+    }
+}
+
+//How many repos are at each stage, leaving out the ones that nothing is at
+fn count_text(state:&BaseStateDefn) -> String {
+    RepoStatus::ALL.iter()
+        .map(|status| (status, state.data.repos.iter().filter(|elmt| RepoStatus::of(elmt)==*status).count()))
+        .filter(|(_, count)| *count > 0)
+        .map(|(status, count)| format!("{} {}", status, count))
+        .collect::<Vec<String>>()
+        .join(" · ")
+}
+
+/**
+ * Everything that we know about the repo, for the detail pane: where it got to, the last error in full, the PR, and
+ * what each step of the patch said
+ */
+fn detail_lines(elmt:&DataElement) -> Vec<String> {
+    let mut lines = vec![
+        elmt.defn().to_string(),
+        format!("Status: {}", status_text(elmt)),
+    ];
+    match elmt {
+        DataElement::LocalRepo(repo)=>lines.push(format!("Clone: {}", repo.local_path.display())),
+        DataElement::PatchedRepo(repo)=>lines.push(format!("Clone: {}", repo.repo.local_path.display())),
+        DataElement::BranchedRepo(repo)=>lines.push(format!("Branch: {}", repo.branch_name)),
+        DataElement::PRdRepo(repo)=>lines.push(format!("Branch: {}", repo.branched.branch_name)),
+        DataElement::RemoteRepo(_)=>(),
+    }
+    if let Some(url) = pr_url(elmt) {
+        lines.push(format!("PR: {}", url));
+    }
+    if let Some(err) = last_error(elmt) {
+        lines.push(String::new());
+        lines.push("Last error:".to_string());
+        lines.extend(err.lines().map(|line| line.to_string()));
+    }
+    if let Some(patched) = patched_of(elmt) {
+        lines.push(String::new());
+        lines.push(format!("Patch {}; {} files changed",
+            patched.outcome.map(|o| o.to_string()).unwrap_or("ran".to_string()), patched.changes));
+        if patched.steps.is_empty() {
+            lines.extend(patched.output.trim().lines().map(|line| format!("  {}", line)));
+        }
+        for (n, step) in patched.steps.iter().enumerate() {
+            lines.push(format!("Step {} ({}) {}", n + 1, step.step, step.outcome));
+            lines.extend(step.output.trim().lines().map(|line| format!("  {}", line)));
+        }
+    }
+    lines
+}
+
+//Opens the URL in whatever the desktop uses for them
+fn open_url(url:&str) -> io::Result<()> {
+    let opener = if cfg!(target_os = "macos") { "open" } else if cfg!(windows) { "explorer" } else { "xdg-open" };
+    Command::new(opener).arg(url).stdin(Stdio::null()).stdout(Stdio::null()).stderr(Stdio::null()).spawn()?;
+    Ok( () )
+}
+
+/**
+ * The dashboard itself, which lives on the UI thread
+ */
+struct App {
+    state_file: PathBuf,
+    //when the state file was last changed, and how big it was, so that it is only read again when it has changed
+    seen: Option<(SystemTime, u64)>,
+    state: Option<BaseStateDefn>,
+    table: TableState,
+    //the last thing that a key did, shown at the bottom
+    message: String,
+    commands: Sender<(String, RepoAction)>,
+    stop: Arc<AtomicBool>,
+    status: Arc<Mutex<RunStatus>>,
+}
+
+impl App {
+    fn new(state_file:PathBuf, commands:Sender<(String, RepoAction)>, stop:Arc<AtomicBool>, status:Arc<Mutex<RunStatus>>) -> App {
+        App {
+            state_file,
+            seen: None,
+            state: None,
+            table: TableState::default().with_selected(Some(0)),
+            message: String::new(),
+            commands,
+            stop,
+            status,
+        }
+    }
+
+    fn run(&mut self, terminal:&mut DefaultTerminal) -> io::Result<()> {
+        while !self.stop.load(Ordering::Relaxed) {
+            self.reload();
+            terminal.draw(|frame| self.draw(frame))?;
+            if event::poll(TICK)? {
+                if let Event::Key(key) = event::read()? {
+                    if key.kind==KeyEventKind::Press {
+                        self.on_key(key.code, key.modifiers);
+                    }
+                }
+            }
+        }
+        Ok( () )
+    }
+
+    //Reads the state file again if it has been written since we last looked
+    fn reload(&mut self) {
+        let seen = fs::metadata(&self.state_file).ok().and_then(|m| m.modified().ok().map(|t| (t, m.len())));
+        if seen.is_none() || seen==self.seen {
+            return;
+        }
+        match read_datafile(&self.state_file) {
+            Ok(state)=>{
+                self.state = Some(state);
+                self.seen = seen;
+            },
+            Err(e)=>self.message = format!("Unable to read {}: {}", self.state_file.display(), e),
+        }
+    }
+
+    fn repos(&self) -> &[DataElement] {
+        self.state.as_ref().map(|s| s.data.repos.as_slice()).unwrap_or_default()
+    }
+
+    fn selected_repo(&self) -> Option<&DataElement> {
+        self.table.selected().and_then(|i| self.repos().get(i))
+    }
+
+    fn on_key(&mut self, code:KeyCode, modifiers:KeyModifiers) {
+        let last = self.repos().len().saturating_sub(1);
+        match code {
+            KeyCode::Char('q') | KeyCode::Esc=>self.stop.store(true, Ordering::Relaxed),
+            KeyCode::Char('c') if modifiers.contains(KeyModifiers::CONTROL)=>self.stop.store(true, Ordering::Relaxed),
+            KeyCode::Down | KeyCode::Char('j')=>self.table.select_next(),
+            KeyCode::Up | KeyCode::Char('k')=>self.table.select_previous(),
+            KeyCode::PageDown=>self.table.scroll_down_by(10),
+            KeyCode::PageUp=>self.table.scroll_up_by(10),
+            KeyCode::Home=>self.table.select_first(),
+            KeyCode::End=>self.table.select(Some(last)),
+            KeyCode::Char(key @ ('r' | 's' | 'o'))=>self.on_repo_key(key),
+            _=>(),
+        }
+        //selecting past the end leaves the selection off the list, which we never want
+        if self.table.selected().is_some_and(|i| i > last) {
+            self.table.select(Some(last));
+        }
+    }
+
+    fn on_repo_key(&mut self, key:char) {
+        let Some(elmt) = self.selected_repo() else {
+            return;
+        };
+        let repo = elmt.defn().to_string();
+        let running = matches!(*self.status.lock().unwrap(), RunStatus::Running);
+        self.message = match key {
+            'r'=>{
+                let _ = self.commands.send((repo.to_owned(), RepoAction::Retry));
+                if running { format!("{} will be retried after the current stage", repo) } else { format!("Retrying {}", repo) }
+            },
+            's'=>{
+                let _ = self.commands.send((repo.to_owned(), RepoAction::Skip));
+                if running { format!("{} will be skipped after the current stage", repo) } else { format!("Skipped {}", repo) }
+            },
+            _=>match pr_url(elmt) {
+                Some(url)=>match open_url(url) {
+                    Ok(_)=>format!("Opened {}", url),
+                    Err(e)=>format!("Unable to open {}: {}", url, e),
+                },
+                None=>format!("{} does not have a PR", repo),
+            },
+        };
+    }
+
+    fn draw(&mut self, frame:&mut Frame) {
+        let [header, main, log, footer] = Layout::vertical([
+            Constraint::Length(1),
+            Constraint::Min(6),
+            Constraint::Length(10),
+            Constraint::Length(1),
+        ]).areas(frame.area());
+        let [list, detail] = Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)]).areas(main);
+
+        let status = self.status.lock().unwrap().clone();
+        let running = match (status, current_stage()) {
+            (RunStatus::Running, Some(stage))=>format!("Running the {} stage", stage),
+            (RunStatus::Running, None)=>"Running".to_string(),
+            (RunStatus::Finished(Ok(_)), _)=>"Finished".to_string(),
+            (RunStatus::Finished(Err(e)), _)=>format!("Finished: {}", e),
+        };
+        let counts = self.state.as_ref().map(count_text).unwrap_or_default();
+        frame.render_widget(Line::from(format!(" batchpatch · {} · {}", running, counts)).bold(), header);
+
+        self.draw_repos(frame, list);
+
+        let lines = self.selected_repo().map(detail_lines).unwrap_or_default();
+        let detail_pane = Paragraph::new(lines.into_iter().map(Line::from).collect::<Vec<Line>>())
+            .wrap(Wrap { trim: false })
+            .block(Block::bordered().title(" Detail "));
+        frame.render_widget(detail_pane, detail);
+
+        let captured = captured_log();
+        let shown = captured.len().saturating_sub(log.height.saturating_sub(2) as usize);
+        let log_pane = Paragraph::new(captured[shown..].iter().map(|line| Line::from(strip_ansi(line))).collect::<Vec<Line>>())
+            .block(Block::bordered().title(" Log "));
+        frame.render_widget(log_pane, log);
+
+        let keys = "↑↓ select · r retry · s skip · o open PR · q quit";
+        let footer_text = if self.message.is_empty() { keys.to_string() } else { format!("{} · {}", keys, self.message) };
+        frame.render_widget(Line::from(format!(" {}", footer_text)).style(Style::new().fg(Color::DarkGray)), footer);
+    }
+
+    fn draw_repos(&mut self, frame:&mut Frame, area:Rect) {
+        let transfers = transfers();
+        let rows:Vec<Row> = self.repos().iter().map(|elmt| {
+            let colour = match RepoStatus::of(elmt) {
+                RepoStatus::Failed=>Color::Red,
+                RepoStatus::PRd=>Color::Green,
+                RepoStatus::Skipped | RepoStatus::NoChanges=>Color::DarkGray,
+                _=>Color::Reset,
+            };
+            Row::new([elmt.defn().to_string(), status_text(elmt), progress_text(elmt, &transfers)]).fg(colour)
+        }).collect();
+        let title = format!(" Repos ({}) ", rows.len());
+
+        let table = Table::new(rows, [Constraint::Percentage(35), Constraint::Length(20), Constraint::Fill(1)])
+            .header(Row::new(["REPO", "STATUS", "PROGRESS"]).add_modifier(Modifier::BOLD))
+            .row_highlight_style(Style::new().add_modifier(Modifier::REVERSED))
+            .block(Block::bordered().title(title));
+        frame.render_stateful_widget(table, area, &mut self.table);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::data::{LocalRepo, PatchOutcome, PatchStepResult, RepoDefn};
+    use crate::error::{ErrorKind, RepoError, Stage};
+
+    #[test]
+    fn test_strip_ansi() {
+        assert_eq!(strip_ansi("\u{1b}[32m[*]\u{1b}[0m \u{1b}[1morg/repo:\u{1b}[0m Cloning"), "[*] org/repo: Cloning");
+        assert_eq!(strip_ansi("no colour"), "no colour");
+    }
+
+    #[test]
+    fn test_progress_text() -> Result<(), Box<dyn Error>> {
+        let failed = DataElement::LocalRepo(LocalRepo {
+            defn: RepoDefn::new("org/repo")?,
+            local_path: Path::new("org/repo").into(),
+            last_error: Some(RepoError::new(Stage::Clone, ErrorKind::Network, "timed out\nretrying".to_string())),
+        });
+        assert_eq!(progress_text(&failed, &BTreeMap::new()), "clone failed (network error): timed out");
+
+        let mut transfers = BTreeMap::new();
+        transfers.insert("org/repo".to_string(), Transfer { stage: Stage::Clone, done: 1, total: 4, bytes: 2048 });
+        assert_eq!(progress_text(&failed, &transfers), "clone 25% (1/4 objects, 2.0 KiB)");
+        Ok( () )
+    }
+
+    #[test]
+    fn test_detail_lines() -> Result<(), Box<dyn Error>> {
+        let patched = DataElement::PatchedRepo(PatchedRepo {
+            repo: LocalRepo {
+                defn: RepoDefn::new("org/repo")?,
+                local_path: Path::new("org/repo").into(),
+                last_error: None,
+            },
+            changes: 0,
+            output: "conflict in README.md".to_string(),
+            success: false,
+            outcome: Some(PatchOutcome::Conflicted),
+            steps: vec![PatchStepResult {
+                step: "diff fix.diff".to_string(),
+                outcome: PatchOutcome::Conflicted,
+                output: "conflict in README.md".to_string(),
+            }],
+            error: None,
+            review: None,
+        });
+        assert_eq!(detail_lines(&patched), vec![
+            "org/repo",
+            "Status: failed (patch)",
+            "Clone: org/repo",
+            "",
+            "Last error:",
+            "patch conflicted: diff fix.diff conflict in README.md",
+            "",
+            "Patch conflicted; 0 files changed",
+            "Step 1 (diff fix.diff) conflicted",
+            "  conflict in README.md",
+        ]);
+        Ok( () )
+    }
+}
@@ -1,12 +1,19 @@
+use std::collections::BTreeMap;
 use std::error::Error;
+use std::ffi::OsString;
 use std::io::Write;
-use std::{fs::File, path::Path};
+use std::{fs::{self, File, TryLockError}, path::{Path, PathBuf}};
 use serde::{Serialize, Deserialize};
 use std::fmt;
 use regex::Regex;
 use log::info;
 
+use crate::error::RepoError;
+use crate::history::RepoEvent;
+use crate::migrate::{migrate, SCHEMA_VERSION};
+
 #[derive(Clone, Serialize, Deserialize, Debug)]
+#[allow(clippy::enum_variant_names)]
 pub enum DataElement {
     PRdRepo(PRdRepo),
     BranchedRepo(BranchedRepo),
@@ -15,6 +22,53 @@ pub enum DataElement {
     RemoteRepo(RepoDefn),
 }
 
+impl DataElement {
+    //Returns the definition of the repo that this element refers to, whatever stage it is at
+    pub fn defn(&self) -> &RepoDefn {
+        match self {
+            DataElement::PRdRepo(repo)=>&repo.branched.patched.repo.defn,
+            DataElement::BranchedRepo(repo)=>&repo.patched.repo.defn,
+            DataElement::PatchedRepo(repo)=>&repo.repo.defn,
+            DataElement::LocalRepo(repo)=>&repo.defn,
+            DataElement::RemoteRepo(defn)=>defn,
+        }
+    }
+
+    /**
+     * Records a failure against this repo at whatever stage it is at, for when the stage went wrong in a way that it
+     * couldn't record itself (i.e. it panicked).  A repo that had not been cloned yet becomes a failed LocalRepo at
+     * the usual clone path, so that it is cloned again next time.
+     */
+    pub fn fail(self, err:RepoError) -> DataElement {
+        match self {
+            DataElement::PRdRepo(repo)=>DataElement::PRdRepo(repo),
+            DataElement::BranchedRepo(mut repo)=>{
+                repo.last_error = Some(err);
+                DataElement::BranchedRepo(repo)
+            },
+            DataElement::PatchedRepo(mut repo)=>{
+                repo.success = false;
+                repo.outcome = Some(PatchOutcome::Failed);
+                repo.output = err.message.to_owned();
+                repo.error = Some(err);
+                DataElement::PatchedRepo(repo)
+            },
+            DataElement::LocalRepo(mut repo)=>{
+                repo.last_error = Some(err);
+                DataElement::LocalRepo(repo)
+            },
+            DataElement::RemoteRepo(defn)=>{
+                let local_path = Path::new(&defn.owner).join(&defn.name);
+                DataElement::LocalRepo(LocalRepo {
+                    defn,
+                    local_path: local_path.into(),
+                    last_error: Some(err),
+                })
+            },
+        }
+    }
+}
+
 
 pub enum CloneMode {
     Ssh,
@@ -58,6 +112,12 @@ pub struct RepoDefn {
     pub owner:String,
     pub name:String,
     pub main_branch_name: Option<String>,
+    //Paths in the repo that matched the code search which selected it, if any
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub search_matches: Vec<String>,
+    //Skipped repos are left wherever they had got to, and none of the stages touch them
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub skipped: bool,
 }
 
 impl fmt::Display for RepoDefn {
@@ -91,11 +151,11 @@ impl RepoDefn {
         match (url_re.captures(from), simple_re.captures(from)) {
             (Some(caps), _)=>{
                 let (_, [org, repo]) = caps.extract();
-                Ok(RepoDefn { owner: org.to_string(), name: repo.to_string(), main_branch_name: None})
+                Ok(RepoDefn { owner: org.to_string(), name: repo.to_string(), main_branch_name: None, search_matches: vec![], skipped: false})
             },
             (_, Some(caps))=>{
                 let (_, [org, repo]) = caps.extract();
-                Ok(RepoDefn { owner: org.to_string(), name: repo.to_string(), main_branch_name: None})
+                Ok(RepoDefn { owner: org.to_string(), name: repo.to_string(), main_branch_name: None, search_matches: vec![], skipped: false})
             }
             (None, None)=>Err(Box::from("Line was not in a valid format")),
         }
@@ -106,13 +166,72 @@ impl RepoDefn {
 pub struct LocalRepo {
     pub defn: RepoDefn,
     pub local_path:Box<Path>,
-    pub last_error:Option<String>,
+    pub last_error:Option<RepoError>,
 }
 
 impl LocalRepo {
     pub fn is_failed(&self) -> bool {
         self.last_error.is_some()
     }
+
+    //Whether the clone failed in a way that is worth trying again
+    pub fn can_retry(&self) -> bool {
+        self.last_error.as_ref().is_some_and(|e| e.retryable)
+    }
+}
+
+/**
+ * How a patch went.  `ThreeWay` means that some files would not apply directly, but could be merged using the
+ * base versions named in the diff; `Fuzzy` means that some could only be applied once context lines were dropped from
+ * their hunks. `Conflicted` means that merge was not clean, so nothing was changed.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum PatchOutcome {
+    Clean,
+    ThreeWay,
+    Fuzzy,
+    AlreadyApplied,
+    Conflicted,
+    Failed,
+}
+
+impl PatchOutcome {
+    pub fn is_success(&self) -> bool {
+        matches!(self, PatchOutcome::Clean | PatchOutcome::ThreeWay | PatchOutcome::Fuzzy | PatchOutcome::AlreadyApplied)
+    }
+}
+
+impl fmt::Display for PatchOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            PatchOutcome::Clean=>"applied cleanly",
+            PatchOutcome::ThreeWay=>"applied with 3-way merge",
+            PatchOutcome::Fuzzy=>"applied with fuzz",
+            PatchOutcome::AlreadyApplied=>"already applied",
+            PatchOutcome::Conflicted=>"conflicted",
+            PatchOutcome::Failed=>"failed",
+        })
+    }
+}
+
+/**
+ * What the user decided about a patched repo when reviewing its changes in interactive mode.  A skipped repo is not
+ * branched or committed, on this run or any later one.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ReviewDecision {
+    Approved,
+    Skipped,
+}
+
+/**
+ * How one step of a multi-step patch went, so that a failure can be traced back to the step that caused it
+ */
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PatchStepResult {
+    pub step:String,
+    pub outcome:PatchOutcome,
+    pub output:String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -120,7 +239,29 @@ pub struct PatchedRepo {
     pub repo:LocalRepo,
     pub changes:usize,
     pub output:String,
-    pub success:bool
+    pub success:bool,
+    #[serde(default)]
+    pub outcome:Option<PatchOutcome>,
+    #[serde(default)]
+    pub steps:Vec<PatchStepResult>,
+    //Only set if the patch could not be run at all, rather than one of the steps failing
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error:Option<RepoError>,
+    //Only set once the changes have been reviewed in interactive mode
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub review:Option<ReviewDecision>,
+}
+
+impl PatchedRepo {
+    //Whether the patch made changes that should go on to be branched and committed
+    pub fn is_ready_to_branch(&self) -> bool {
+        self.success && self.changes>0 && self.review!=Some(ReviewDecision::Skipped) && !self.repo.defn.skipped
+    }
+
+    //Whether the patch could not be run for a reason that is worth trying again
+    pub fn can_retry(&self) -> bool {
+        self.error.as_ref().is_some_and(|e| e.retryable)
+    }
 }
 
 
@@ -129,9 +270,31 @@ pub struct PatchedRepo {
 pub struct BranchedRepo {
     pub patched:PatchedRepo,
     pub branch_name:String,
+    //The commit that the branch was made from, i.e. the tip of the default branch at the time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_commit: Option<String>,
+    //The commit that we made on the branch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
     pub committed: bool,
     pub pushed: bool,
-    pub last_error: Option<String>
+    //The ref that was pushed to the remote, e.g. refs/heads/{branch_name}
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pushed_ref: Option<String>,
+    pub last_error: Option<RepoError>,
+    //How many times we have tried to create a PR for this repo
+    #[serde(default)]
+    pub pr_attempts: u32,
+    //If Github rate limited the last attempt, the unix time at which it said we could try again
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rate_limited_until: Option<u64>,
+}
+
+impl BranchedRepo {
+    //Whether the last stage failed in a way that is worth trying again, or didn't fail at all
+    pub fn can_continue(&self) -> bool {
+        self.last_error.as_ref().map(|e| e.retryable).unwrap_or(true)
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -139,6 +302,11 @@ pub struct BranchedRepo {
 pub struct PRdRepo {
     pub branched: BranchedRepo,
     pub url: String,
+    //The PR's number in the repo, and its id in Github's GraphQL API.  State files from before these were recorded only have the URL
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub number: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node_id: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -148,44 +316,150 @@ pub struct BaseDataDefn {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct BaseStateDefn {
+    //See `migrate`; state files from before this was added are version 0
+    #[serde(default)]
+    pub schema_version: u32,
     pub data:BaseDataDefn,
     pub pr_description: Option<String>,
     pub pr_title: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pr_labels: Vec<String>,
+    //The campaign manifest that this state was last run with, if there was one, as written by `CampaignManifest::to_state`.
+    //It is only kept as a record, so it isn't read back into a manifest
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub manifest: Option<serde_json::Value>,
+    //What has happened to each repo so far, keyed by owner/name
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub history: BTreeMap<String, Vec<RepoEvent>>,
+}
+
+impl BaseStateDefn {
+    pub fn new(repos:Vec<DataElement>) -> BaseStateDefn {
+        BaseStateDefn {
+            schema_version: SCHEMA_VERSION,
+            data: BaseDataDefn {
+                repos,
+            },
+            pr_description: None,
+            pr_title: None,
+            pr_labels: vec![],
+            manifest: None,
+            history: BTreeMap::new(),
+        }
+    }
+
+    /**
+     * Adds the given repos as new RemoteRepo entries, skipping any that are already present in the state.
+     * Returns the number of repos that were added.
+     */
+    pub fn add_remote_repos(&mut self, repos:Vec<RepoDefn>) -> usize {
+        let mut added = 0;
+        for defn in repos {
+            let exists = self.data.repos.iter().any(|r| r.defn().owner==defn.owner && r.defn().name==defn.name);
+            if !exists {
+                self.data.repos.push(DataElement::RemoteRepo(defn));
+                added += 1;
+            }
+        }
+        added
+    }
+
+    //Adds an event to the end of the repo's history
+    pub fn record_event(&mut self, repo:&RepoDefn, event:RepoEvent) {
+        self.history.entry(repo.to_string()).or_default().push(event);
+    }
+
+    pub fn history_of(&self, repo:&RepoDefn) -> &[RepoEvent] {
+        self.history.get(&repo.to_string()).map(|events| events.as_slice()).unwrap_or_default()
+    }
 }
 
 pub fn load_datafile(p:&Path) -> Result<BaseStateDefn, Box<dyn Error>> {
     info!("Loading state from {}...", p.display());
+    read_datafile(p)
+}
+
+//Loads the state without saying so, for when it is read over and over again
+pub fn read_datafile(p:&Path) -> Result<BaseStateDefn, Box<dyn Error>> {
     let file = File::open(p)?;
 
-    let data:BaseStateDefn = serde_json::from_reader(file)?;
+    //older state files are upgraded as they are loaded, and written out in the new format next time
+    let doc:serde_json::Value = serde_json::from_reader(file)?;
+    let data:BaseStateDefn = serde_json::from_value(migrate(doc)?)?;
     Ok(data)
 }
 
-pub fn create_datafile(p:&Path) -> Result<BaseStateDefn, Box<dyn Error>> {
-    info!("Creating new statefile at {}...", p.display());
-    let mut file = File::create(p)?;
-
-    let data = BaseStateDefn {
-        data: BaseDataDefn {
-            repos: vec![],
-        },
-        pr_description: None,
-        pr_title: None,
+/**
+ * Writes the state to a temporary file next to the real one, then renames it over the top.  That way the state file is
+ * always either the old state or the new one, even if we are killed part way through.
+ */
+pub fn write_datafile(p:&Path, data:&BaseStateDefn) -> Result<(), Box<dyn Error>> {
+    info!("🖊️ Writing updated state to {}...", p.display());
+    let dir = match p.parent() {
+        Some(parent) if !parent.as_os_str().is_empty()=>parent,
+        _=>Path::new("."),
     };
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+
     let serialized = serde_json::to_string_pretty(&data)?;
-    file.write(serialized.as_bytes())?;
-    Ok( data )
+    file.write_all(serialized.as_bytes())?;
+    file.as_file().sync_all()?;
+    //the temporary file is only readable by us, so keep the permissions the state file had before
+    if let Ok(meta) = fs::metadata(p) {
+        file.as_file().set_permissions(meta.permissions())?;
+    }
+    file.persist(p)?;
+    Ok( () )
 }
 
-pub fn write_datafile(p:&Path, data:&BaseStateDefn) -> Result<(), Box<dyn Error>> {
-    info!("🖊️ Writing updated state to {}...", p.display());
-    let mut file = File::create(p)?;
+//How many old copies of the state file are kept, as {state}.bak.1 (the newest) to {state}.bak.N
+const STATE_BACKUPS:usize = 3;
 
-    let serialized = serde_json::to_string_pretty(&data)?;
-    file.write(serialized.as_bytes())?;
+//The state file's path with something added on the end, e.g. batchpatch.state.lock
+fn sibling_path(p:&Path, suffix:&str) -> PathBuf {
+    let mut name:OsString = p.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+/**
+ * Keeps a copy of the state file as it is now, moving the older copies along and dropping the oldest.  Does nothing if
+ * there is no state file yet.
+ */
+pub fn backup_datafile(p:&Path) -> Result<(), Box<dyn Error>> {
+    if !p.exists() {
+        return Ok( () );
+    }
+    for n in (1..STATE_BACKUPS).rev() {
+        let older = sibling_path(p, &format!(".bak.{}", n));
+        if older.exists() {
+            fs::rename(&older, sibling_path(p, &format!(".bak.{}", n + 1)))?;
+        }
+    }
+    let backup = sibling_path(p, ".bak.1");
+    fs::copy(p, &backup)?;
+    info!("💾 Backed up the state to {}", backup.display());
     Ok( () )
 }
 
+/**
+ * Stops two runs from using the same state file at once.  The lock is on a separate {state}.lock file, because the
+ * state file itself is replaced every time it is written, and is released when this is dropped (or the process ends).
+ */
+pub struct StateLock {
+    _file: File,
+}
+
+pub fn lock_datafile(p:&Path) -> Result<StateLock, Box<dyn Error>> {
+    let lock_path = sibling_path(p, ".lock");
+    let file = File::options().create(true).truncate(false).write(true).open(&lock_path)?;
+    match file.try_lock() {
+        Ok(())=>Ok( StateLock { _file: file } ),
+        Err(TryLockError::WouldBlock)=>Err(Box::from(format!("{} is being used by another batchpatch run (it has {} locked)", p.display(), lock_path.display()))),
+        Err(TryLockError::Error(e))=>Err(Box::new(e)),
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct ConfigFile {
@@ -205,4 +479,67 @@ pub fn homedir() -> String {
         Ok(v)=>v,
         Err(_)=>"".to_string(),
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_write_datafile() -> Result<(), Box<dyn Error>> {
+        let dir = TempDir::new()?;
+        let p = dir.path().join("test.state");
+        let mut state = BaseStateDefn::new(vec![]);
+        write_datafile(&p, &state)?;
+        state.add_remote_repos(vec![RepoDefn::new("org/repo")?]);
+        write_datafile(&p, &state)?;
+
+        assert_eq!(load_datafile(&p)?.data.repos.len(), 1);
+        //nothing should be left behind but the state file
+        assert_eq!(fs::read_dir(dir.path())?.count(), 1);
+        Ok( () )
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_write_datafile_permissions() -> Result<(), Box<dyn Error>> {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = TempDir::new()?;
+        let p = dir.path().join("test.state");
+        let state = BaseStateDefn::new(vec![]);
+        write_datafile(&p, &state)?;
+        fs::set_permissions(&p, fs::Permissions::from_mode(0o644))?;
+        write_datafile(&p, &state)?;
+        assert_eq!(fs::metadata(&p)?.permissions().mode() & 0o777, 0o644);
+        Ok( () )
+    }
+
+    #[test]
+    fn test_backup_datafile() -> Result<(), Box<dyn Error>> {
+        let dir = TempDir::new()?;
+        let p = dir.path().join("test.state");
+        backup_datafile(&p)?;
+        assert_eq!(fs::read_dir(dir.path())?.count(), 0);
+
+        for n in 0..5 {
+            fs::write(&p, format!("{}", n))?;
+            backup_datafile(&p)?;
+        }
+        assert_eq!(fs::read_to_string(sibling_path(&p, ".bak.1"))?, "4");
+        assert_eq!(fs::read_to_string(sibling_path(&p, ".bak.3"))?, "2");
+        assert!(!sibling_path(&p, ".bak.4").exists());
+        Ok( () )
+    }
+
+    #[test]
+    fn test_lock_datafile() -> Result<(), Box<dyn Error>> {
+        let dir = TempDir::new()?;
+        let p = dir.path().join("test.state");
+        let lock = lock_datafile(&p)?;
+        assert!(lock_datafile(&p).is_err());
+        drop(lock);
+        assert!(lock_datafile(&p).is_ok());
+        Ok( () )
+    }
+}
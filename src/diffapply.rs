@@ -0,0 +1,834 @@
+use std::error::Error;
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::path::Path;
+use git2::{ApplyLocation, ApplyOptions, Diff, Index, IndexEntry, IndexTime, ObjectType, Oid, Patch, Repository, Tree};
+use regex::Regex;
+use log::{debug, info};
+
+use crate::data::PatchOutcome;
+
+/**
+ * Where the changes from a diff should be written to.  `Both` also stages the changes in the index,
+ * which `git apply --index` would do.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ApplyTarget {
+    WorkDir,
+    Both,
+}
+
+impl From<&String> for ApplyTarget {
+    fn from(value: &String) -> Self {
+        match value.to_lowercase().as_str() {
+            "both"=>ApplyTarget::Both,
+            "index"=>ApplyTarget::Both,
+            _=>ApplyTarget::WorkDir,
+        }
+    }
+}
+
+impl From<ApplyTarget> for ApplyLocation {
+    fn from(value: ApplyTarget) -> Self {
+        match value {
+            ApplyTarget::WorkDir=>ApplyLocation::WorkDir,
+            ApplyTarget::Both=>ApplyLocation::Both,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct DiffApplyOptions {
+    //Number of leading path components to remove from the paths in the diff, like `patch -p`
+    pub strip: usize,
+    pub target: ApplyTarget,
+    //If a file won't apply directly, try a three-way merge against the base version named in the diff's index line (like `git apply --3way`),
+    //and if that can't be done then try again with less context, like the fuzz factor of `patch`
+    pub three_way: bool,
+}
+
+impl Default for DiffApplyOptions {
+    fn default() -> Self {
+        DiffApplyOptions {
+            strip: 1,
+            target: ApplyTarget::WorkDir,
+            three_way: false,
+        }
+    }
+}
+
+/**
+ * The overall result of applying a diff to a repo, along with a per-file description of what happened
+ */
+#[derive(Debug)]
+pub struct DiffResult {
+    pub outcome: PatchOutcome,
+    pub output: String,
+}
+
+/**
+ * The part of a diff that refers to a single file, re-written so that libgit2 can parse it.
+ */
+#[derive(Debug, Clone, Default)]
+struct FileSection {
+    old_path: String,
+    path: String,
+    //raw bytes, since the content of the hunks need not be UTF-8
+    text: Vec<u8>,
+    //abbreviated blob ids and file mode from the `index` line, if there was one
+    old_id: Option<String>,
+    new_id: Option<String>,
+    mode: Option<u32>,
+}
+
+#[derive(Debug)]
+enum FileStatus {
+    Clean,
+    AlreadyApplied,
+    //the merged content, or None if the merge deleted the file
+    Merged(Option<Vec<u8>>),
+    //the diff applied once this many lines of context were dropped from each end of each hunk; holds the reduced diff
+    Fuzzed(usize, Vec<u8>),
+    Conflicted,
+    Rejected(Option<String>),
+}
+
+/**
+ * What happened when we tried to apply a single file from the diff
+ */
+#[derive(Debug)]
+struct FileReport {
+    path: String,
+    hunks: usize,
+    //hunk number (counting from 1) and its header, for each hunk that would not apply
+    rejected_hunks: Vec<(usize, String)>,
+    status: FileStatus,
+}
+
+//Removes `strip` leading components from the path. Returns None if there were not enough components to remove.
+fn strip_path(path:&str, strip:usize) -> Option<String> {
+    if path=="/dev/null" {
+        return Some(path.to_owned());
+    }
+    let parts:Vec<&str> = path.split('/').filter(|p| !p.is_empty()).collect();
+    if parts.len() <= strip {
+        None
+    } else {
+        Some(parts[strip..].join("/"))
+    }
+}
+
+//Takes the path from a ---/+++ line, dropping any timestamp that `diff -u` may have put after it
+fn header_path(line_content:&str) -> &str {
+    let without_timestamp = line_content.split('\t').next().unwrap_or(line_content);
+    without_timestamp.trim_end().trim_matches('"')
+}
+
+//Rewrites a path so that it has the single a/ or b/ prefix that libgit2 expects
+fn prefixed(prefix:&str, path:&str) -> String {
+    if path=="/dev/null" {
+        path.to_owned()
+    } else {
+        format!("{}/{}", prefix, path)
+    }
+}
+
+//The paths in a `diff --git` header are separated by a space, but can contain spaces themselves.  Where the old and new
+//paths are the same (i.e. not a rename) we can find the split where both halves match.
+fn split_git_header(paths:&str, strip:usize) -> Option<(String, String)> {
+    let positions:Vec<usize> = paths.match_indices(' ').map(|(idx, _)| idx).collect();
+    let stripped_halves = |idx:usize| match (strip_path(&paths[..idx], strip), strip_path(&paths[idx+1..], strip)) {
+        (Some(old), Some(new))=>Some((old, new)),
+        _=>None,
+    };
+
+    positions.iter()
+        .filter_map(|idx| stripped_halves(*idx))
+        .find(|(old, new)| old==new)
+        .or_else(|| positions.first().and_then(|idx| stripped_halves(*idx)))
+}
+
+fn not_enough_components(path:&str, strip:usize) -> Box<dyn Error> {
+    Box::from(format!("the path {} does not have enough components to strip {} of them", path, strip))
+}
+
+/**
+ * Splits a diff into the sections for each file, and rewrites the paths in each one to have the a/ and b/ prefixes
+ * that libgit2 expects, after removing `strip` components.  Plain unified diffs (without `diff --git` lines) get a git-style
+ * header added.
+ */
+fn normalise_diff(content:&[u8], strip:usize) -> Result<Vec<FileSection>, Box<dyn Error>> {
+    let hunk_header_re = Regex::new(r"^@@ -\d+(?:,(\d+))? \+\d+(?:,(\d+))? @@").unwrap();
+    let rename_re = Regex::new(r"^(rename from|rename to|copy from|copy to) (.*)$").unwrap();
+    let index_re = Regex::new(r"^index ([0-9a-f]+)\.\.([0-9a-f]+)(?: (\d+))?$").unwrap();
+
+    let mut sections:Vec<FileSection> = vec![];
+    let mut current:Option<FileSection> = None;
+    //set when we have seen a `diff --git` line for the current section, so ---/+++ lines are not the start of a new file
+    let mut git_header = false;
+    //the old path from a --- line, waiting for its +++ line
+    let mut pending_old:Option<String> = None;
+    //lines remaining in the current hunk, for the old and new side
+    let mut hunk_remaining:(usize, usize) = (0, 0);
+
+    for line in content.split_inclusive(|b| *b==b'\n') {
+        if hunk_remaining != (0, 0) {
+            match line.first() {
+                Some(b'-')=>hunk_remaining.0 = hunk_remaining.0.saturating_sub(1),
+                Some(b'+')=>hunk_remaining.1 = hunk_remaining.1.saturating_sub(1),
+                Some(b'\\')=>(),
+                _=>{
+                    hunk_remaining.0 = hunk_remaining.0.saturating_sub(1);
+                    hunk_remaining.1 = hunk_remaining.1.saturating_sub(1);
+                }
+            }
+            if let Some(section) = current.as_mut() {
+                section.text.extend_from_slice(line);
+            }
+            continue;
+        }
+
+        //header lines are matched as text; only the hunk content has to be kept byte-for-byte
+        let line_text = String::from_utf8_lossy(line);
+        let trimmed = line_text.trim_end_matches(['\n', '\r']);
+
+        if let Some(paths) = trimmed.strip_prefix("diff --git ") {
+            if let Some(section) = current.take() {
+                sections.push(section);
+            }
+            let (old, new) = split_git_header(paths, strip).ok_or_else(|| not_enough_components(paths, strip))?;
+            current = Some(FileSection {
+                text: format!("diff --git {} {}\n", prefixed("a", &old), prefixed("b", &new)).into_bytes(),
+                old_path: old,
+                path: new,
+                ..Default::default()
+            });
+            git_header = true;
+            pending_old = None;
+        } else if let Some(path) = trimmed.strip_prefix("--- ") {
+            let old = strip_path(header_path(path), strip).ok_or_else(|| not_enough_components(path, strip))?;
+            if git_header {
+                if let Some(section) = current.as_mut() {
+                    writeln!(section.text, "--- {}", prefixed("a", &old))?;
+                }
+            } else {
+                pending_old = Some(old);
+            }
+        } else if let Some(path) = trimmed.strip_prefix("+++ ") {
+            let new = strip_path(header_path(path), strip).ok_or_else(|| not_enough_components(path, strip))?;
+            match (git_header, pending_old.take()) {
+                (true, _)=>{
+                    if let Some(section) = current.as_mut() {
+                        writeln!(section.text, "+++ {}", prefixed("b", &new))?;
+                    }
+                },
+                (false, Some(old))=>{
+                    //a plain unified diff, so we need to make up the git header
+                    if let Some(section) = current.take() {
+                        sections.push(section);
+                    }
+                    let header_old = if old=="/dev/null" { &new } else { &old };
+                    let header_new = if new=="/dev/null" { &old } else { &new };
+                    let mut text = format!("diff --git {} {}\n", prefixed("a", header_old), prefixed("b", header_new));
+                    if old=="/dev/null" {
+                        text.push_str("new file mode 100644\n");
+                    } else if new=="/dev/null" {
+                        text.push_str("deleted file mode 100644\n");
+                    }
+                    writeln!(text, "--- {}", prefixed("a", &old))?;
+                    writeln!(text, "+++ {}", prefixed("b", &new))?;
+                    current = Some(FileSection {
+                        old_path: header_old.to_owned(),
+                        path: header_new.to_owned(),
+                        text: text.into_bytes(),
+                        ..Default::default()
+                    });
+                },
+                (false, None)=>return Err(Box::from(format!("found '+++ {}' without a preceding --- line", path))),
+            }
+            git_header = false;
+        } else if let Some(caps) = hunk_header_re.captures(trimmed) {
+            let count = |idx:usize| caps.get(idx).map(|m| m.as_str().parse::<usize>().unwrap_or(1)).unwrap_or(1);
+            hunk_remaining = (count(1), count(2));
+            match current.as_mut() {
+                Some(section)=>section.text.extend_from_slice(line),
+                None=>return Err(Box::from("found a hunk before any file header")),
+            }
+        } else if let (Some(section), Some(caps)) = (current.as_mut(), index_re.captures(trimmed)) {
+            section.old_id = Some(caps.get(1).unwrap().as_str().to_owned());
+            section.new_id = Some(caps.get(2).unwrap().as_str().to_owned());
+            section.mode = caps.get(3).and_then(|m| u32::from_str_radix(m.as_str(), 8).ok());
+            section.text.extend_from_slice(line);
+        } else if let Some(section) = current.as_mut() {
+            //rename/copy lines don't have the a/ and b/ prefixes, so only strip the components beyond that
+            match rename_re.captures(trimmed) {
+                Some(caps) if strip > 1 => {
+                    let path = caps.get(2).unwrap().as_str();
+                    let stripped = strip_path(path, strip - 1).ok_or_else(|| not_enough_components(path, strip))?;
+                    writeln!(section.text, "{} {}", caps.get(1).unwrap().as_str(), stripped)?;
+                },
+                _=>section.text.extend_from_slice(line),
+            }
+        } else {
+            debug!("Ignoring preamble line '{}'", trimmed);
+        }
+    }
+
+    if let Some(section) = current.take() {
+        sections.push(section);
+    }
+    Ok(sections)
+}
+
+//Tries (without changing anything) to apply the given diff, optionally only the hunk with the given index
+fn check_apply(repo:&Repository, diff:&Diff, location:ApplyLocation, only_hunk:Option<usize>) -> Result<(), git2::Error> {
+    let mut opts = ApplyOptions::new();
+    opts.check(true);
+    let mut hunk_idx = 0;
+    if let Some(wanted) = only_hunk {
+        opts.hunk_callback(move |_| {
+            let keep = hunk_idx==wanted;
+            hunk_idx += 1;
+            keep
+        });
+    }
+    repo.apply(diff, location, Some(&mut opts))
+}
+
+fn check_section(repo:&Repository, section:&FileSection, location:ApplyLocation) -> FileReport {
+    let mut report = FileReport {
+        path: section.path.to_owned(),
+        hunks: 0,
+        rejected_hunks: vec![],
+        status: FileStatus::Clean,
+    };
+
+    let diff = match Diff::from_buffer(&section.text) {
+        Ok(diff)=>diff,
+        Err(e)=>{
+            report.status = FileStatus::Rejected(Some(format!("could not parse the diff: {}", e.message())));
+            return report;
+        }
+    };
+
+    let patch = match Patch::from_diff(&diff, 0) {
+        Ok(patch)=>patch,
+        Err(e)=>{
+            report.status = FileStatus::Rejected(Some(e.message().to_owned()));
+            return report;
+        }
+    };
+    report.hunks = patch.as_ref().map(|p| p.num_hunks()).unwrap_or(0);
+
+    if let Err(e) = check_apply(repo, &diff, location, None) {
+        if report.hunks==0 {
+            //e.g. a binary change, rename or mode change. There are no hunks to blame so the whole file is rejected
+            report.status = FileStatus::Rejected(Some(e.message().to_owned()));
+        } else {
+            for idx in 0..report.hunks {
+                if check_apply(repo, &diff, location, Some(idx)).is_err() {
+                    let header = patch.as_ref()
+                        .and_then(|p| p.hunk(idx).ok())
+                        .map(|(hunk, _)| String::from_utf8_lossy(hunk.header()).trim_end().to_owned())
+                        .unwrap_or_default();
+                    report.rejected_hunks.push((idx + 1, header));
+                }
+            }
+            //if every hunk applies on its own but not all together then there are no particular hunks to blame
+            let msg = if report.rejected_hunks.is_empty() { Some(e.message().to_owned()) } else { None };
+            report.status = FileStatus::Rejected(msg);
+        }
+    }
+    report
+}
+
+fn is_null_id(abbrev:&str) -> bool {
+    abbrev.chars().all(|c| c=='0')
+}
+
+/**
+ * Uses the post-image blob id in the diff's index line to tell whether the working copy of the file already
+ * matches what the diff would produce
+ */
+fn is_already_applied(repo:&Repository, section:&FileSection) -> bool {
+    let (workdir, new_id) = match (repo.workdir(), section.new_id.as_ref()) {
+        (Some(workdir), Some(new_id))=>(workdir, new_id),
+        _=>return false,
+    };
+    let path = workdir.join(&section.path);
+
+    if is_null_id(new_id) {
+        //the diff deletes the file, so it is applied if the file is gone
+        !path.exists()
+    } else {
+        match Oid::hash_file(ObjectType::Blob, &path) {
+            Ok(oid)=>oid.to_string().starts_with(new_id.as_str()),
+            Err(_)=>false,
+        }
+    }
+}
+
+fn index_entry(path:&str, id:Oid, mode:u32) -> IndexEntry {
+    IndexEntry {
+        ctime: IndexTime::new(0, 0),
+        mtime: IndexTime::new(0, 0),
+        dev: 0,
+        ino: 0,
+        mode,
+        uid: 0,
+        gid: 0,
+        file_size: 0,
+        id,
+        flags: 0,
+        flags_extended: 0,
+        path: path.as_bytes().to_vec(),
+    }
+}
+
+//Builds a tree that contains nothing but the given blob at the given path
+fn single_file_tree<'r>(repo:&'r Repository, path:&str, blob:Oid, mode:u32) -> Result<Tree<'r>, Box<dyn Error>> {
+    let mut index = Index::new()?;
+    index.add(&index_entry(path, blob, mode))?;
+    let tree_id = index.write_tree_to(repo)?;
+    Ok(repo.find_tree(tree_id)?)
+}
+
+/**
+ * Does a three-way merge of a single file, like `git apply --3way`.  The base version of the file is the blob named
+ * in the diff's index line, which must exist somewhere in the repo's history; "theirs" is the base with the diff applied and
+ * "ours" is the current working copy.
+ */
+fn try_three_way(repo:&Repository, section:&FileSection) -> Result<FileStatus, Box<dyn Error>> {
+    if section.old_path != section.path {
+        return Err(Box::from("renamed files can't be merged"));
+    }
+    let old_id = match section.old_id.as_ref() {
+        Some(id) if !is_null_id(id) => id,
+        _ => return Err(Box::from("the diff has no base version for this file")),
+    };
+    let workdir = repo.workdir().ok_or("the repo has no working directory")?;
+    let mode = section.mode.unwrap_or(0o100644);
+
+    let base_blob = repo.revparse_single(old_id)
+        .and_then(|obj| obj.peel_to_blob())
+        .map_err(|_| format!("the base version {} is not in this repo's history", old_id))?;
+    let base_tree = single_file_tree(repo, &section.path, base_blob.id(), mode)?;
+
+    let section_diff = Diff::from_buffer(&section.text)?;
+    let mut their_index = repo.apply_to_tree(&base_tree, &section_diff, None)?;
+    let their_tree = repo.find_tree(their_index.write_tree_to(repo)?)?;
+
+    let our_content = std::fs::read(workdir.join(&section.path))?;
+    let our_tree = single_file_tree(repo, &section.path, repo.blob(&our_content)?, mode)?;
+
+    let merged = repo.merge_trees(&base_tree, &our_tree, &their_tree, None)?;
+    if merged.has_conflicts() {
+        return Ok(FileStatus::Conflicted);
+    }
+    match merged.get_path(Path::new(&section.path), 0) {
+        Some(entry)=>Ok(FileStatus::Merged(Some(repo.find_blob(entry.id)?.content().to_vec()))),
+        None=>Ok(FileStatus::Merged(None)),
+    }
+}
+
+//The most lines of context that will be dropped from each end of a hunk, the same as the default for `patch`
+const MAX_FUZZ:usize = 2;
+
+//One hunk of a section, as read by reduce_context
+struct Hunk<'a> {
+    //start and count of the old and then the new lines
+    range: [usize; 4],
+    //whatever follows the @@ markers in the header, usually the name of the enclosing function
+    suffix: Vec<u8>,
+    lines: Vec<&'a [u8]>,
+}
+
+//Writes out a hunk with up to `fuzz` lines of context dropped from each end
+fn write_reduced(output:&mut Vec<u8>, hunk:&Hunk, fuzz:usize) {
+    let is_context = |line:&&&[u8]| line.first()==Some(&b' ');
+    let leading = hunk.lines.iter().take_while(is_context).count().min(fuzz);
+    //a "\ No newline" marker refers to the line before it, so that line has to stay
+    let trailing = if hunk.lines.last().and_then(|l| l.first())==Some(&b'\\') {
+        0
+    } else {
+        hunk.lines.iter().rev().take_while(is_context).count().min(fuzz)
+    };
+    let (leading, trailing) = if leading + trailing >= hunk.lines.len() { (0, 0) } else { (leading, trailing) };
+    let dropped = leading + trailing;
+    let [old_start, old_count, new_start, new_count] = hunk.range;
+    let _ = write!(output, "@@ -{},{} +{},{} @@",
+        old_start + leading, old_count.saturating_sub(dropped), new_start + leading, new_count.saturating_sub(dropped));
+    output.extend_from_slice(&hunk.suffix);
+    for line in &hunk.lines[leading..hunk.lines.len() - trailing] {
+        output.extend_from_slice(line);
+    }
+}
+
+/**
+ * Rewrites a section so that each hunk has up to `fuzz` lines of context removed from its start and end, with the
+ * line numbers in the hunk headers adjusted to match.
+ */
+fn reduce_context(section:&FileSection, fuzz:usize) -> Vec<u8> {
+    let hunk_header_re = regex::bytes::Regex::new(r"^@@ -(\d+)(?:,(\d+))? \+(\d+)(?:,(\d+))? @@(.*)$").unwrap();
+    let mut output:Vec<u8> = vec![];
+    let mut hunk:Option<Hunk> = None;
+
+    for line in section.text.split_inclusive(|b| *b==b'\n') {
+        let trimmed = line.strip_suffix(b"\n").unwrap_or(line);
+        match hunk_header_re.captures(trimmed) {
+            Some(caps)=>{
+                if let Some(previous) = hunk.take() {
+                    write_reduced(&mut output, &previous, fuzz);
+                }
+                let num = |idx:usize| caps.get(idx)
+                    .and_then(|m| std::str::from_utf8(m.as_bytes()).ok())
+                    .and_then(|m| m.parse::<usize>().ok())
+                    .unwrap_or(1);
+                let mut suffix = caps.get(5).map(|m| m.as_bytes().to_vec()).unwrap_or_default();
+                suffix.push(b'\n');
+                hunk = Some(Hunk { range: [num(1), num(2), num(3), num(4)], suffix, lines: vec![] });
+            },
+            None=>match hunk.as_mut() {
+                Some(current)=>current.lines.push(line),
+                None=>output.extend_from_slice(line),
+            },
+        }
+    }
+    if let Some(last) = hunk.take() {
+        write_reduced(&mut output, &last, fuzz);
+    }
+    output
+}
+
+/**
+ * Tries to apply a section with less and less context, up to MAX_FUZZ lines from each end of each hunk.  Returns the
+ * status for the smallest amount of fuzz that worked.
+ */
+fn try_fuzz(repo:&Repository, section:&FileSection, location:ApplyLocation) -> Option<FileStatus> {
+    (1..=MAX_FUZZ).find_map(|fuzz| {
+        let reduced = reduce_context(section, fuzz);
+        let diff = Diff::from_buffer(&reduced).ok()?;
+        check_apply(repo, &diff, location, None).ok()
+            .map(|_| FileStatus::Fuzzed(fuzz, reduced))
+    })
+}
+
+fn describe(reports:&[FileReport], outcome:PatchOutcome) -> String {
+    let mut msg = String::new();
+    for report in reports {
+        let _ = match &report.status {
+            FileStatus::Clean=>writeln!(msg, "applied  {} ({} hunks)", report.path, report.hunks),
+            FileStatus::AlreadyApplied=>writeln!(msg, "already applied {}", report.path),
+            FileStatus::Merged(_)=>writeln!(msg, "merged   {} with 3-way merge", report.path),
+            FileStatus::Fuzzed(fuzz, _)=>writeln!(msg, "applied  {} ({} hunks) with fuzz {}", report.path, report.hunks, fuzz),
+            FileStatus::Conflicted=>writeln!(msg, "conflict {}: the 3-way merge did not resolve cleanly", report.path),
+            FileStatus::Rejected(Some(err))=>writeln!(msg, "rejected {}: {}", report.path, err),
+            FileStatus::Rejected(None)=>writeln!(msg, "rejected {}: {} of {} hunks did not apply", report.path, report.rejected_hunks.len(), report.hunks),
+        };
+        for (num, header) in report.rejected_hunks.iter() {
+            let _ = writeln!(msg, "  hunk {} {}", num, header);
+        }
+    }
+    let _ = if outcome.is_success() {
+        write!(msg, "{} files, {}", reports.len(), outcome)
+    } else {
+        write!(msg, "{} files, {}; nothing was changed", reports.len(), outcome)
+    };
+    msg
+}
+
+fn overall_outcome(reports:&[FileReport]) -> PatchOutcome {
+    if reports.iter().any(|r| matches!(r.status, FileStatus::Rejected(_))) {
+        PatchOutcome::Failed
+    } else if reports.iter().any(|r| matches!(r.status, FileStatus::Conflicted)) {
+        PatchOutcome::Conflicted
+    } else if reports.iter().any(|r| matches!(r.status, FileStatus::Fuzzed(..))) {
+        PatchOutcome::Fuzzy
+    } else if reports.iter().any(|r| matches!(r.status, FileStatus::Merged(_))) {
+        PatchOutcome::ThreeWay
+    } else if reports.iter().all(|r| matches!(r.status, FileStatus::AlreadyApplied)) {
+        PatchOutcome::AlreadyApplied
+    } else {
+        PatchOutcome::Clean
+    }
+}
+
+//Writes the result of a three-way merge into the working directory, and the index too if we were asked to
+fn write_merged(repo:&Repository, path:&str, content:&Option<Vec<u8>>, target:ApplyTarget) -> Result<(), Box<dyn Error>> {
+    let workdir = repo.workdir().ok_or("the repo has no working directory")?;
+    match content {
+        Some(bytes)=>std::fs::write(workdir.join(path), bytes)?,
+        None=>std::fs::remove_file(workdir.join(path))?,
+    }
+    if target==ApplyTarget::Both {
+        let mut index = repo.index()?;
+        match content {
+            Some(_)=>index.add_path(Path::new(path))?,
+            None=>index.remove_path(Path::new(path))?,
+        }
+        index.write()?;
+    }
+    Ok( () )
+}
+
+/**
+ * Applies a diff file to the given repo using libgit2.  Every file is checked before anything is written, so either the
+ * whole diff is applied or nothing is.  Files that the working copy already matches are skipped, and if `three_way` is set
+ * then files that don't apply directly are merged instead, or applied with fuzz if there is no base version to merge with.
+ * Only returns an error if the diff could not be read at all; otherwise the outcome says whether it worked.
+ */
+pub fn apply_diff(patchfile:&Path, opts:&DiffApplyOptions, repo:&Repository) -> Result<DiffResult, Box<dyn Error>> {
+    let content = std::fs::read(patchfile)?;
+    let sections = normalise_diff(&content, opts.strip)?;
+    if sections.is_empty() {
+        return Err(Box::from("the diff file did not contain any changes"));
+    }
+
+    let location:ApplyLocation = opts.target.into();
+    let reports:Vec<FileReport> = sections.iter()
+        .map(|section| {
+            let mut report = check_section(repo, section, location);
+            if matches!(report.status, FileStatus::Rejected(_)) {
+                if is_already_applied(repo, section) {
+                    report.status = FileStatus::AlreadyApplied;
+                    report.rejected_hunks.clear();
+                } else if opts.three_way {
+                    let fallback = match try_three_way(repo, section) {
+                        Ok(status)=>Some(status),
+                        Err(e)=>{
+                            info!("🔀 Could not do a 3-way merge of {}: {}", section.path, e);
+                            try_fuzz(repo, section, location)
+                        },
+                    };
+                    if let Some(status) = fallback {
+                        report.status = status;
+                        report.rejected_hunks.clear();
+                    }
+                }
+            }
+            report
+        })
+        .collect();
+
+    let outcome = overall_outcome(&reports);
+    let output = describe(&reports, outcome);
+
+    if outcome.is_success() {
+        let clean_text:Vec<u8> = sections.iter().zip(reports.iter())
+            .flat_map(|(section, report)| match &report.status {
+                FileStatus::Clean=>section.text.as_slice(),
+                FileStatus::Fuzzed(_, reduced)=>reduced.as_slice(),
+                _=>&[],
+            })
+            .copied()
+            .collect();
+        if !clean_text.is_empty() {
+            let diff = Diff::from_buffer(&clean_text)?;
+            repo.apply(&diff, location, None)?;
+        }
+        for report in reports.iter() {
+            if let FileStatus::Merged(merged_content) = &report.status {
+                write_merged(repo, &report.path, merged_content, opts.target)?;
+            }
+        }
+    }
+
+    Ok(DiffResult {
+        outcome,
+        output,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use git2::Signature;
+    use std::fs;
+    use tempfile::{NamedTempFile, TempDir};
+    use crate::gitutils::test::repo_with_commit;
+
+    fn diff_file(content:&str) -> Result<NamedTempFile, Box<dyn Error>> {
+        let file = NamedTempFile::new()?;
+        fs::write(file.path(), content)?;
+        Ok(file)
+    }
+
+    #[test]
+    fn test_strip_path() {
+        assert_eq!(strip_path("a/src/main.rs", 1), Some("src/main.rs".to_string()));
+        assert_eq!(strip_path("src/main.rs", 0), Some("src/main.rs".to_string()));
+        assert_eq!(strip_path("x/y/src/main.rs", 2), Some("src/main.rs".to_string()));
+        assert_eq!(strip_path("main.rs", 1), None);
+        assert_eq!(strip_path("/dev/null", 3), Some("/dev/null".to_string()));
+    }
+
+    #[test]
+    fn test_normalise_plain_diff() -> Result<(), Box<dyn Error>> {
+        let content = "--- my file.txt\t2024-01-01 10:00:00\n+++ my file.txt\t2024-01-01 10:00:01\n@@ -1,2 +1,2 @@\n hello\n---world\n+there\n";
+        let sections = normalise_diff(content.as_bytes(), 0)?;
+        assert_eq!(sections.len(), 1);
+        assert_eq!(sections[0].path, "my file.txt");
+        assert_eq!(String::from_utf8(sections[0].text.clone())?, "diff --git a/my file.txt b/my file.txt\n--- a/my file.txt\n+++ b/my file.txt\n@@ -1,2 +1,2 @@\n hello\n---world\n+there\n");
+        Ok( () )
+    }
+
+    #[test]
+    fn test_normalise_git_diff_strip() -> Result<(), Box<dyn Error>> {
+        let content = "diff --git a/sub/one.txt b/sub/one.txt\nindex 1234567..89abcde 100644\n--- a/sub/one.txt\n+++ b/sub/one.txt\n@@ -1 +1 @@\n-a\n+b\ndiff --git a/sub/two.txt b/sub/two.txt\n--- a/sub/two.txt\n+++ b/sub/two.txt\n@@ -1 +1 @@\n-c\n+d\n";
+        let sections = normalise_diff(content.as_bytes(), 2)?;
+        assert_eq!(sections.len(), 2);
+        assert_eq!(sections[0].path, "one.txt");
+        assert!(sections[0].text.starts_with(b"diff --git a/one.txt b/one.txt\nindex 1234567..89abcde 100644\n--- a/one.txt\n+++ b/one.txt\n"));
+        assert_eq!(sections[1].path, "two.txt");
+        Ok( () )
+    }
+
+    #[test]
+    fn test_apply_diff_clean() -> Result<(), Box<dyn Error>> {
+        let dir = TempDir::new()?;
+        let repo = repo_with_commit(dir.path(), &[("greeting.txt", "hello\nworld\n")])?;
+        let diff = diff_file("diff --git a/greeting.txt b/greeting.txt\n--- a/greeting.txt\n+++ b/greeting.txt\n@@ -1,2 +1,2 @@\n hello\n-world\n+there\n")?;
+
+        let result = apply_diff(diff.path(), &DiffApplyOptions::default(), &repo)?;
+        assert_eq!(fs::read_to_string(dir.path().join("greeting.txt"))?, "hello\nthere\n");
+        assert_eq!(result.outcome, PatchOutcome::Clean);
+        assert!(result.output.contains("applied  greeting.txt (1 hunks)"));
+        Ok( () )
+    }
+
+    #[test]
+    fn test_apply_diff_not_utf8() -> Result<(), Box<dyn Error>> {
+        let dir = TempDir::new()?;
+        let repo = repo_with_commit(dir.path(), &[("greeting.txt", "hello\nworld\n")])?;
+        //a Latin-1 encoded e-acute, which is not valid UTF-8
+        let diff = NamedTempFile::new()?;
+        fs::write(diff.path(), b"--- a/greeting.txt\n+++ b/greeting.txt\n@@ -1,2 +1,2 @@\n hello\n-world\n+caf\xe9\n")?;
+
+        let result = apply_diff(diff.path(), &DiffApplyOptions::default(), &repo)?;
+        assert_eq!(result.outcome, PatchOutcome::Clean);
+        assert_eq!(fs::read(dir.path().join("greeting.txt"))?, b"hello\ncaf\xe9\n");
+        Ok( () )
+    }
+
+    #[test]
+    fn test_apply_diff_rename() -> Result<(), Box<dyn Error>> {
+        let dir = TempDir::new()?;
+        let repo = repo_with_commit(dir.path(), &[("old.txt", "hello\nworld\n")])?;
+        let diff = diff_file("diff --git a/old.txt b/new.txt\nsimilarity index 50%\nrename from old.txt\nrename to new.txt\nindex ce01362..f4a0f7c 100644\n--- a/old.txt\n+++ b/new.txt\n@@ -1,2 +1,2 @@\n hello\n-world\n+there\n")?;
+
+        apply_diff(diff.path(), &DiffApplyOptions::default(), &repo)?;
+        assert!(!dir.path().join("old.txt").exists());
+        assert_eq!(fs::read_to_string(dir.path().join("new.txt"))?, "hello\nthere\n");
+        Ok( () )
+    }
+
+    const BASE:&str = "1\n2\n3\n4\n5\n6\n7\n8\n9\n";
+
+    //A diff made against BASE, changing line 5. The index line names BASE as the pre-image
+    fn diff_against_base(post_image:&str) -> Result<NamedTempFile, Box<dyn Error>> {
+        let old_id = Oid::hash_object(ObjectType::Blob, BASE.as_bytes())?.to_string();
+        let new_id = Oid::hash_object(ObjectType::Blob, post_image.as_bytes())?.to_string();
+        diff_file(&format!("diff --git a/lines.txt b/lines.txt\nindex {}..{} 100644\n--- a/lines.txt\n+++ b/lines.txt\n@@ -2,7 +2,7 @@\n 2\n 3\n 4\n-5\n+FIVE\n 6\n 7\n 8\n", &old_id[..7], &new_id[..7]))
+    }
+
+    //Commits BASE, then commits a change to it so that the diff no longer applies directly
+    fn drifted_repo(dir:&Path, drifted:&str) -> Result<Repository, Box<dyn Error>> {
+        let repo = repo_with_commit(dir, &[("lines.txt", BASE)])?;
+        fs::write(dir.join("lines.txt"), drifted)?;
+        {
+            let mut index = repo.index()?;
+            index.add_path(Path::new("lines.txt"))?;
+            index.write()?;
+            let tree = repo.find_tree(index.write_tree()?)?;
+            let sig = Signature::now("Test User", "test@example.com")?;
+            let parent = repo.head()?.peel_to_commit()?;
+            repo.commit(Some("HEAD"), &sig, &sig, "drift", &tree, &[&parent])?;
+        }
+        Ok(repo)
+    }
+
+    #[test]
+    fn test_apply_diff_three_way() -> Result<(), Box<dyn Error>> {
+        let dir = TempDir::new()?;
+        let repo = drifted_repo(dir.path(), "1\ntwo\n3\n4\n5\n6\n7\n8\n9\n")?;
+        let diff = diff_against_base("1\n2\n3\n4\nFIVE\n6\n7\n8\n9\n")?;
+
+        let without = apply_diff(diff.path(), &DiffApplyOptions::default(), &repo)?;
+        assert_eq!(without.outcome, PatchOutcome::Failed);
+
+        let opts = DiffApplyOptions { three_way: true, ..Default::default() };
+        let result = apply_diff(diff.path(), &opts, &repo)?;
+        assert_eq!(result.outcome, PatchOutcome::ThreeWay);
+        assert_eq!(fs::read_to_string(dir.path().join("lines.txt"))?, "1\ntwo\n3\n4\nFIVE\n6\n7\n8\n9\n");
+        Ok( () )
+    }
+
+    #[test]
+    fn test_reduce_context() {
+        let section = FileSection {
+            text: b"diff --git a/lines.txt b/lines.txt\n--- a/lines.txt\n+++ b/lines.txt\n@@ -2,7 +2,7 @@ fn main\n 2\n 3\n 4\n-5\n+FIVE\n 6\n 7\n 8\n".to_vec(),
+            ..Default::default()
+        };
+        assert_eq!(String::from_utf8_lossy(&reduce_context(&section, 2)),
+            "diff --git a/lines.txt b/lines.txt\n--- a/lines.txt\n+++ b/lines.txt\n@@ -4,3 +4,3 @@ fn main\n 4\n-5\n+FIVE\n 6\n");
+    }
+
+    #[test]
+    fn test_apply_diff_fuzz() -> Result<(), Box<dyn Error>> {
+        let dir = TempDir::new()?;
+        //the base version named by the diff was never committed here, so it can't be merged
+        let drifted = "1\n2\nthree\n4\n5\n6\n7\n8\n9\n";
+        let repo = repo_with_commit(dir.path(), &[("lines.txt", drifted)])?;
+        let diff = diff_against_base("1\n2\n3\n4\nFIVE\n6\n7\n8\n9\n")?;
+
+        let opts = DiffApplyOptions { three_way: true, ..Default::default() };
+        let result = apply_diff(diff.path(), &opts, &repo)?;
+        assert_eq!(result.outcome, PatchOutcome::Fuzzy);
+        assert!(result.output.contains("applied  lines.txt (1 hunks) with fuzz 2"));
+        assert_eq!(fs::read_to_string(dir.path().join("lines.txt"))?, "1\n2\nthree\n4\nFIVE\n6\n7\n8\n9\n");
+        Ok( () )
+    }
+
+    #[test]
+    fn test_apply_diff_three_way_conflict() -> Result<(), Box<dyn Error>> {
+        let dir = TempDir::new()?;
+        let drifted = "1\n2\n3\n4\ncinq\n6\n7\n8\n9\n";
+        let repo = drifted_repo(dir.path(), drifted)?;
+        let diff = diff_against_base("1\n2\n3\n4\nFIVE\n6\n7\n8\n9\n")?;
+
+        let opts = DiffApplyOptions { three_way: true, ..Default::default() };
+        let result = apply_diff(diff.path(), &opts, &repo)?;
+        assert_eq!(result.outcome, PatchOutcome::Conflicted);
+        //no conflict markers should have been written
+        assert_eq!(fs::read_to_string(dir.path().join("lines.txt"))?, drifted);
+        Ok( () )
+    }
+
+    #[test]
+    fn test_apply_diff_already_applied() -> Result<(), Box<dyn Error>> {
+        let dir = TempDir::new()?;
+        let post_image = "1\n2\n3\n4\nFIVE\n6\n7\n8\n9\n";
+        let repo = drifted_repo(dir.path(), post_image)?;
+        let diff = diff_against_base(post_image)?;
+
+        let result = apply_diff(diff.path(), &DiffApplyOptions::default(), &repo)?;
+        assert_eq!(result.outcome, PatchOutcome::AlreadyApplied);
+        assert_eq!(fs::read_to_string(dir.path().join("lines.txt"))?, post_image);
+        Ok( () )
+    }
+
+    #[test]
+    fn test_apply_diff_rejected_hunk() -> Result<(), Box<dyn Error>> {
+        let dir = TempDir::new()?;
+        let repo = repo_with_commit(dir.path(), &[("greeting.txt", "hello\nworld\n")])?;
+        let diff = diff_file("diff --git a/greeting.txt b/greeting.txt\n--- a/greeting.txt\n+++ b/greeting.txt\n@@ -1,2 +1,2 @@\n hello\n-world\n+there\n@@ -10,2 +10,2 @@\n not\n-here\n+at all\n")?;
+
+        let result = apply_diff(diff.path(), &DiffApplyOptions::default(), &repo)?;
+        assert_eq!(result.outcome, PatchOutcome::Failed);
+        assert!(result.output.contains("rejected greeting.txt: 1 of 2 hunks did not apply"));
+        assert!(result.output.contains("hunk 2 @@ -10,2 +10,2 @@"));
+        //nothing should have been changed
+        assert_eq!(fs::read_to_string(dir.path().join("greeting.txt"))?, "hello\nworld\n");
+        Ok( () )
+    }
+}
@@ -0,0 +1,403 @@
+use octorust::{auth::Credentials, types::{MinimalRepository, Order, RepoSearchResultItem, ReposListOrgSort, ReposListOrgType, SearchCodeSort, SearchReposSort}, Client};
+use serde::{Deserialize, Serialize};
+use tokio::runtime::Runtime;
+use std::error::Error;
+use log::{debug, info, warn};
+
+use crate::data::RepoDefn;
+
+//The search API will not return more than 1000 results for any query, however we page through it
+const SEARCH_PAGE_SIZE:i64 = 100;
+const SEARCH_MAX_RESULTS:i64 = 1000;
+
+/**
+ * Describes which repos we want to find on Github.
+ * If `code_search` is given, then the repos containing matching code are used (restricted to `org` if that is set).
+ * Otherwise `org` and `team` select the starting set of repos; if neither is given then the repository search API is used
+ * with `topic` and `language` as qualifiers.  The remaining fields are filters that are applied to whatever comes back.
+ */
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DiscoveryQuery {
+    pub org: Option<String>,
+    pub team: Option<String>,
+    pub topics: Vec<String>,
+    pub language: Option<String>,
+    pub exclude_archived: bool,
+    pub exclude_forks: bool,
+    pub exclude_templates: bool,
+    pub code_search: Option<String>,
+}
+
+/**
+ * The parts of a Github repository that we care about for discovery.  The different Github endpoints return
+ * different types, so we boil them down to this.
+ */
+#[derive(Debug, Clone)]
+struct RepoCandidate {
+    owner: String,
+    name: String,
+    default_branch: String,
+    archived: bool,
+    fork: bool,
+    is_template: bool,
+    is_admin: bool,
+    topics: Vec<String>,
+    language: String,
+    matched_paths: Vec<String>,
+}
+
+fn owner_from(full_name:&str, owner_login:Option<&str>) -> String {
+    match owner_login {
+        Some(login) if !login.is_empty() => login.to_owned(),
+        _ => full_name.split('/').next().unwrap_or_default().to_owned(),
+    }
+}
+
+impl From<MinimalRepository> for RepoCandidate {
+    fn from(repo: MinimalRepository) -> Self {
+        RepoCandidate {
+            owner: owner_from(&repo.full_name, repo.owner.as_ref().map(|o| o.login.as_str())),
+            name: repo.name,
+            default_branch: repo.default_branch,
+            archived: repo.archived,
+            fork: repo.fork,
+            is_template: repo.is_template,
+            is_admin: repo.permissions.map(|p| p.admin).unwrap_or(false),
+            topics: repo.topics,
+            language: repo.language,
+            matched_paths: vec![],
+        }
+    }
+}
+
+impl From<RepoSearchResultItem> for RepoCandidate {
+    fn from(repo: RepoSearchResultItem) -> Self {
+        RepoCandidate {
+            owner: owner_from(&repo.full_name, repo.owner.as_ref().map(|o| o.login.as_str())),
+            name: repo.name,
+            default_branch: repo.default_branch,
+            archived: repo.archived,
+            fork: repo.fork,
+            is_template: false, //the search API does not tell us this, we use the template: qualifier instead
+            is_admin: repo.permissions.map(|p| p.admin).unwrap_or(false),
+            topics: repo.topics,
+            language: repo.language,
+            matched_paths: vec![],
+        }
+    }
+}
+
+impl From<RepoCandidate> for RepoDefn {
+    fn from(candidate: RepoCandidate) -> Self {
+        RepoDefn {
+            owner: candidate.owner,
+            name: candidate.name,
+            main_branch_name: if candidate.default_branch.is_empty() { None } else { Some(candidate.default_branch) },
+            search_matches: candidate.matched_paths,
+            skipped: false,
+        }
+    }
+}
+
+impl DiscoveryQuery {
+    /**
+     * Builds a query string for the Github repository search API.  The exclusions only narrow a search down, so without
+     * a topic or language to search for there is no query, rather than one that matches every repo on Github.
+     */
+    fn search_qualifiers(&self) -> Option<String> {
+        let mut parts:Vec<String> = self.topics.iter().map(|t| format!("topic:{}", t)).collect();
+        if let Some(lang) = self.language.as_ref() {
+            parts.push(format!("language:{}", lang));
+        }
+        if parts.is_empty() {
+            return None;
+        }
+        if self.exclude_archived {
+            parts.push("archived:false".to_string());
+        }
+        if self.exclude_forks {
+            parts.push("fork:false".to_string());
+        }
+        if self.exclude_templates {
+            parts.push("template:false".to_string());
+        }
+        Some(parts.join(" "))
+    }
+
+    //Builds a query string for the Github code search API, adding in the org if it was not already given
+    fn code_search_query(&self) -> Option<String> {
+        self.code_search.as_ref().map(|q| match self.org.as_ref() {
+            Some(org) if !q.split_whitespace().any(|term| term.starts_with("org:")) => format!("{} org:{}", q, org),
+            _ => q.to_owned(),
+        })
+    }
+
+    fn matches(&self, candidate:&RepoCandidate) -> bool {
+        if self.exclude_archived && candidate.archived {
+            return false;
+        }
+        if self.exclude_forks && candidate.fork {
+            return false;
+        }
+        if self.exclude_templates && candidate.is_template {
+            return false;
+        }
+        if self.team.is_some() && !candidate.is_admin {
+            return false;
+        }
+        if !self.topics.iter().all(|t| candidate.topics.iter().any(|ct| ct.eq_ignore_ascii_case(t))) {
+            return false;
+        }
+        match self.language.as_ref() {
+            Some(lang)=>candidate.language.eq_ignore_ascii_case(lang),
+            None=>true,
+        }
+    }
+}
+
+async fn search_repos(gh_client:&Client, query:&DiscoveryQuery) -> Result<Vec<RepoCandidate>, Box<dyn Error>> {
+    let q = match query.search_qualifiers() {
+        Some(q)=>q,
+        None=>return Err(Box::from("You need to specify an org, team, topic or language to discover repos")),
+    };
+    info!("🔎 Searching Github for repos matching '{}'", q);
+
+    let mut results:Vec<RepoCandidate> = vec![];
+    let mut page = 1;
+    loop {
+        let response = gh_client.search().repos(&q, SearchReposSort::Noop, Order::Noop, SEARCH_PAGE_SIZE, page).await?;
+        let item_count = response.body.items.len() as i64;
+        results.extend(response.body.items.into_iter().map(RepoCandidate::from));
+
+        if item_count < SEARCH_PAGE_SIZE || page * SEARCH_PAGE_SIZE >= SEARCH_MAX_RESULTS {
+            if response.body.incomplete_results {
+                warn!("⚠️ Github said that the search results were incomplete");
+            }
+            break;
+        }
+        page += 1;
+    }
+    Ok(results)
+}
+
+/**
+ * Code search returns one result per matching file, so we need to gather these up into one candidate per repo
+ * with all of the matching paths attached. The order that repos were first seen in is kept.
+ */
+fn group_code_results(results:Vec<(RepoCandidate, String)>) -> Vec<RepoCandidate> {
+    let mut grouped:Vec<RepoCandidate> = vec![];
+    for (candidate, path) in results {
+        match grouped.iter_mut().find(|c| c.owner==candidate.owner && c.name==candidate.name) {
+            Some(existing)=>{
+                if !existing.matched_paths.contains(&path) {
+                    existing.matched_paths.push(path);
+                }
+            },
+            None=>grouped.push(RepoCandidate { matched_paths: vec![path], ..candidate }),
+        }
+    }
+    for candidate in grouped.iter_mut() {
+        candidate.matched_paths.sort();
+    }
+    grouped
+}
+
+async fn search_code(gh_client:&Client, q:&str) -> Result<Vec<RepoCandidate>, Box<dyn Error>> {
+    info!("🔎 Searching Github for code matching '{}'", q);
+
+    let mut results:Vec<(RepoCandidate, String)> = vec![];
+    let mut page = 1;
+    loop {
+        let response = gh_client.search().code(q, SearchCodeSort::Noop, Order::Noop, SEARCH_PAGE_SIZE, page).await?;
+        let item_count = response.body.items.len() as i64;
+        results.extend(response.body.items.into_iter().map(|item| (RepoCandidate::from(item.repository), item.path)));
+
+        if item_count < SEARCH_PAGE_SIZE || page * SEARCH_PAGE_SIZE >= SEARCH_MAX_RESULTS {
+            if response.body.incomplete_results {
+                warn!("⚠️ Github said that the search results were incomplete");
+            }
+            break;
+        }
+        page += 1;
+    }
+
+    let file_count = results.len();
+    let grouped = group_code_results(results);
+    info!("👀 {} files matched, in {} repos", file_count, grouped.len());
+    Ok(grouped)
+}
+
+async fn find_candidates(gh_client:&Client, query:&DiscoveryQuery) -> Result<Vec<RepoCandidate>, Box<dyn Error>> {
+    if let Some(q) = query.code_search_query() {
+        return search_code(gh_client, &q).await;
+    }
+
+    match (query.org.as_ref(), query.team.as_ref()) {
+        (Some(org), Some(team))=>{
+            info!("🔎 Listing repos that {}/{} administers", org, team);
+            let response = gh_client.teams().list_all_repos_in_org(org, team).await?;
+            Ok(response.body.into_iter().map(RepoCandidate::from).collect())
+        },
+        (Some(org), None)=>{
+            info!("🔎 Listing all repos in {}", org);
+            let response = gh_client.repos().list_all_for_org(org, ReposListOrgType::All, ReposListOrgSort::FullName, Order::Asc).await?;
+            Ok(response.body.into_iter().map(RepoCandidate::from).collect())
+        },
+        (None, Some(_))=>Err(Box::from("You must specify the org that the team belongs to")),
+        (None, None)=>search_repos(gh_client, query).await,
+    }
+}
+
+/**
+ * Asks Github for the repos that match the given query.  The default branch of each repo is filled in
+ * from the API response, so we don't need to work it out when cloning.
+ */
+pub fn discover_repos(query:&DiscoveryQuery, gh_token:&str) -> Result<Vec<RepoDefn>, Box<dyn Error>> {
+    let rt = Runtime::new()?;
+    let client = Client::new(String::from("batchpatch"), Credentials::Token(gh_token.to_string()))?;
+
+    let candidates = rt.block_on(find_candidates(&client, query))?;
+    let total = candidates.len();
+
+    let mut repos:Vec<RepoDefn> = candidates.into_iter()
+        .filter(|c| {
+            let keep = query.matches(c);
+            if !keep {
+                debug!("Skipping {}/{} as it does not match the filters", c.owner, c.name);
+            }
+            keep
+        })
+        .map(RepoDefn::from)
+        .collect();
+    repos.sort_by_key(|a| a.to_string());
+    repos.dedup_by(|a, b| a.owner==b.owner && a.name==b.name);
+
+    info!("👀 Found {} repos, {} matched the filters", total, repos.len());
+    for repo in repos.iter().filter(|r| !r.search_matches.is_empty()) {
+        info!("📄 {}: {}", repo, repo.search_matches.join(", "));
+    }
+    Ok(repos)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn candidate(name:&str) -> RepoCandidate {
+        RepoCandidate {
+            owner: "my-org".to_string(),
+            name: name.to_string(),
+            default_branch: "master".to_string(),
+            archived: false,
+            fork: false,
+            is_template: false,
+            is_admin: false,
+            topics: vec!["backend".to_string()],
+            language: "Rust".to_string(),
+            matched_paths: vec![],
+        }
+    }
+
+    #[test]
+    fn test_filters() {
+        let query = DiscoveryQuery {
+            org: Some("my-org".to_string()),
+            exclude_archived: true,
+            exclude_forks: true,
+            exclude_templates: true,
+            ..Default::default()
+        };
+
+        assert!(query.matches(&candidate("plain")));
+        assert!(!query.matches(&RepoCandidate { archived: true, ..candidate("archived") }));
+        assert!(!query.matches(&RepoCandidate { fork: true, ..candidate("fork") }));
+        assert!(!query.matches(&RepoCandidate { is_template: true, ..candidate("template") }));
+    }
+
+    #[test]
+    fn test_topic_language_and_team_filters() {
+        let query = DiscoveryQuery {
+            org: Some("my-org".to_string()),
+            team: Some("my-team".to_string()),
+            topics: vec!["Backend".to_string()],
+            language: Some("rust".to_string()),
+            ..Default::default()
+        };
+
+        assert!(!query.matches(&candidate("not-admin")));
+        assert!(query.matches(&RepoCandidate { is_admin: true, ..candidate("admin") }));
+        assert!(!query.matches(&RepoCandidate { is_admin: true, language: "Go".to_string(), ..candidate("golang") }));
+        assert!(!query.matches(&RepoCandidate { is_admin: true, topics: vec![], ..candidate("no-topics") }));
+    }
+
+    #[test]
+    fn test_search_qualifiers() {
+        let query = DiscoveryQuery {
+            topics: vec!["backend".to_string()],
+            language: Some("rust".to_string()),
+            exclude_forks: true,
+            ..Default::default()
+        };
+        assert_eq!(query.search_qualifiers().as_deref(), Some("topic:backend language:rust fork:false"));
+    }
+
+    #[test]
+    fn test_search_qualifiers_with_only_exclusions() {
+        let query = DiscoveryQuery {
+            exclude_archived: true,
+            exclude_forks: true,
+            exclude_templates: true,
+            ..Default::default()
+        };
+        assert_eq!(query.search_qualifiers(), None);
+    }
+
+    #[test]
+    fn test_code_search_query() {
+        let query = DiscoveryQuery {
+            org: Some("my-org".to_string()),
+            code_search: Some("path:.github/workflows actions/checkout@v2".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(query.code_search_query(), Some("path:.github/workflows actions/checkout@v2 org:my-org".to_string()));
+
+        let query = DiscoveryQuery {
+            org: Some("my-org".to_string()),
+            code_search: Some("filename:Dockerfile org:other-org".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(query.code_search_query(), Some("filename:Dockerfile org:other-org".to_string()));
+        assert_eq!(DiscoveryQuery::default().code_search_query(), None);
+    }
+
+    #[test]
+    fn test_group_code_results() {
+        let results = vec![
+            (candidate("first"), ".github/workflows/ci.yml".to_string()),
+            (candidate("second"), "Dockerfile".to_string()),
+            (candidate("first"), ".github/workflows/build.yml".to_string()),
+            (candidate("first"), ".github/workflows/ci.yml".to_string()),
+        ];
+
+        let grouped = group_code_results(results);
+        assert_eq!(grouped.len(), 2);
+        assert_eq!(grouped[0].name, "first");
+        assert_eq!(grouped[0].matched_paths, vec![".github/workflows/build.yml", ".github/workflows/ci.yml"]);
+        assert_eq!(grouped[1].name, "second");
+        assert_eq!(grouped[1].matched_paths, vec!["Dockerfile"]);
+
+        let defn:RepoDefn = grouped[0].clone().into();
+        assert_eq!(defn.search_matches.len(), 2);
+    }
+
+    #[test]
+    fn test_candidate_to_defn() {
+        let defn:RepoDefn = candidate("some-repo").into();
+        assert_eq!(defn.to_string(), "my-org/some-repo");
+        assert_eq!(defn.main_branch_name, Some("master".to_string()));
+
+        let defn:RepoDefn = RepoCandidate { default_branch: "".to_string(), ..candidate("empty") }.into();
+        assert_eq!(defn.main_branch_name, None);
+    }
+}
@@ -0,0 +1,127 @@
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+use git2::{DiffFormat, DiffStatsFormat, Repository};
+use log::{info, warn};
+
+use crate::data::{BaseStateDefn, DataElement, LocalRepo};
+use crate::patcher::patch_diff;
+
+/**
+ * The change that the patch made to one repo, as it would be committed
+ */
+pub struct RepoDiff {
+    pub files_changed: usize,
+    pub files: Vec<String>,
+    //git's --stat summary of the change
+    pub stats: String,
+    pub patch: String,
+}
+
+impl RepoDiff {
+    //The stats and then the diff, in a form that `git apply` will take
+    pub fn to_patch_file(&self) -> String {
+        format!("{}\n{}", self.stats, self.patch)
+    }
+}
+
+pub fn repo_diff(repo:&LocalRepo) -> Result<RepoDiff, Box<dyn Error>> {
+    let repo_ref = Repository::open(&repo.local_path)?;
+    let diff = patch_diff(&repo_ref)?;
+    let stats = diff.stats()?;
+    let stats_text = stats.to_buf(DiffStatsFormat::FULL | DiffStatsFormat::INCLUDE_SUMMARY, 80)?;
+    let files = diff.deltas()
+        .filter_map(|delta| delta.new_file().path().or(delta.old_file().path()).map(|p| p.to_string_lossy().to_string()))
+        .collect();
+
+    let mut patch = String::new();
+    diff.print(DiffFormat::Patch, |_, _, line| {
+        //content lines come without their +/- prefix, the headers already have theirs
+        if matches!(line.origin(), '+' | '-' | ' ') {
+            patch.push(line.origin());
+        }
+        patch.push_str(&String::from_utf8_lossy(line.content()));
+        true
+    })?;
+
+    Ok( RepoDiff {
+        files_changed: stats.files_changed(),
+        files,
+        stats: stats_text.as_str().unwrap_or_default().to_string(),
+        patch,
+    })
+}
+
+/**
+ * Shows the change that the patch made to each repo that it changed, instead of going on to branch and commit it.  The
+ * diffs are printed, or if `diff_dir` is given then each one is written there as {owner}/{name}.patch.  A later run
+ * without --dry-run carries on from the patched clones.
+ */
+pub fn show_dry_run(state:&BaseStateDefn, diff_dir:Option<&Path>) -> Result<(), Box<dyn Error>> {
+    let mut shown = 0;
+    for elmt in state.data.repos.iter() {
+        let patched = match elmt {
+            DataElement::PatchedRepo(repo) if repo.is_ready_to_branch()=>repo,
+            _=>continue,
+        };
+        let defn = &patched.repo.defn;
+        let diff = match repo_diff(&patched.repo) {
+            Ok(diff)=>diff,
+            Err(e)=>{
+                warn!("⚠️ Unable to get the changes made to {}: {}", defn, e);
+                continue;
+            },
+        };
+
+        match diff_dir {
+            Some(dir)=>{
+                let patch_file = dir.join(&defn.owner).join(format!("{}.patch", defn.name));
+                if let Some(parent) = patch_file.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(&patch_file, diff.to_patch_file())?;
+                info!("📝 {} - {} files changed, written to {}", defn, diff.files_changed, patch_file.display());
+            },
+            None=>{
+                println!("=== {} ===", defn);
+                print!("{}", diff.to_patch_file());
+                println!();
+            },
+        }
+        shown += 1;
+    }
+
+    info!("🔍 Dry run; the patch would change {} repos. Nothing has been branched, committed or pushed", shown);
+    Ok( () )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+    use crate::data::RepoDefn;
+    use crate::gitutils::test::repo_with_commit;
+
+    #[test]
+    fn test_repo_diff() -> Result<(), Box<dyn Error>> {
+        let dir = TempDir::new()?;
+        repo_with_commit(dir.path(), &[("README.md", "hello\n")])?;
+
+        //as the patch would have left it, with a changed file and a new one
+        fs::write(dir.path().join("README.md"), "hello world\n")?;
+        fs::write(dir.path().join("NEW.md"), "new\n")?;
+
+        let local = LocalRepo {
+            defn: RepoDefn::new("org/repo")?,
+            local_path: dir.path().into(),
+            last_error: None,
+        };
+        let diff = repo_diff(&local)?;
+        assert_eq!(diff.files_changed, 2);
+        assert_eq!(diff.files, vec!["NEW.md", "README.md"]);
+        assert!(diff.stats.contains("2 files changed, 2 insertions(+), 1 deletion(-)"));
+        assert!(diff.patch.contains("diff --git a/NEW.md b/NEW.md\nnew file mode 100644\n"));
+        assert!(diff.patch.contains("-hello\n+hello world\n"));
+        Ok( () )
+    }
+}
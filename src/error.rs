@@ -0,0 +1,226 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use octorust::ClientError;
+
+/**
+ * The stages of the pipeline that a repo goes through, in order
+ */
+#[derive(Serialize, Deserialize, ValueEnum, Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub enum Stage {
+    Clone,
+    Patch,
+    Branch,
+    Commit,
+    Push,
+    #[value(name = "pr", alias = "pull-request")]
+    PullRequest,
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Stage::Clone=>"clone",
+            Stage::Patch=>"patch",
+            Stage::Branch=>"branch",
+            Stage::Commit=>"commit",
+            Stage::Push=>"push",
+            Stage::PullRequest=>"pull request",
+        })
+    }
+}
+
+/**
+ * What sort of thing went wrong, worked out from the underlying error where we can
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ErrorKind {
+    //The credentials were missing, wrong, or did not have permission
+    Auth,
+    //The repo, branch or file that we wanted was not there
+    NotFound,
+    //Something that we wanted to create was already there, e.g. the branch or the PR
+    AlreadyExists,
+    //The change could not be merged or applied
+    Conflict,
+    //Couldn't talk to the remote or to Github, or it had a problem of its own
+    Network,
+    RateLimited,
+    //Reading or writing local files, or running a program
+    Io,
+    //The repo is set up in a way that we can't handle, e.g. it has more than one remote
+    Unsupported,
+    //A bug in batchpatch, which made the stage panic
+    Internal,
+    Other,
+}
+
+impl ErrorKind {
+    //Whether it is worth trying again without anything being changed
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, ErrorKind::Network | ErrorKind::RateLimited | ErrorKind::Io | ErrorKind::Other)
+    }
+
+    fn of_io(err:&io::Error) -> ErrorKind {
+        match err.kind() {
+            io::ErrorKind::NotFound=>ErrorKind::NotFound,
+            io::ErrorKind::PermissionDenied=>ErrorKind::Auth,
+            io::ErrorKind::AlreadyExists=>ErrorKind::AlreadyExists,
+            _=>ErrorKind::Io,
+        }
+    }
+
+    fn of_git(err:&git2::Error) -> ErrorKind {
+        match (err.code(), err.class()) {
+            (git2::ErrorCode::Auth | git2::ErrorCode::Certificate, _)=>ErrorKind::Auth,
+            (git2::ErrorCode::NotFound, _)=>ErrorKind::NotFound,
+            (git2::ErrorCode::Exists, _)=>ErrorKind::AlreadyExists,
+            (git2::ErrorCode::Conflict | git2::ErrorCode::MergeConflict | git2::ErrorCode::NotFastForward, _)=>ErrorKind::Conflict,
+            (_, git2::ErrorClass::Net | git2::ErrorClass::Http | git2::ErrorClass::Ssh)=>ErrorKind::Network,
+            (_, git2::ErrorClass::Os)=>ErrorKind::Io,
+            _=>ErrorKind::Other,
+        }
+    }
+
+    fn of_github(err:&ClientError) -> ErrorKind {
+        match err {
+            ClientError::RateLimited{..}=>ErrorKind::RateLimited,
+            ClientError::HttpError{status, error, ..}=>match status.as_u16() {
+                403 | 429 if error.to_lowercase().contains("rate limit")=>ErrorKind::RateLimited,
+                401 | 403=>ErrorKind::Auth,
+                404=>ErrorKind::NotFound,
+                422 if error.contains("already exists")=>ErrorKind::AlreadyExists,
+                429=>ErrorKind::RateLimited,
+                500..=599=>ErrorKind::Network,
+                _=>ErrorKind::Other,
+            },
+            ClientError::ReqwestError(_) | ClientError::ReqwestMiddleWareError(_)=>ErrorKind::Network,
+            ClientError::IoError(e)=>ErrorKind::of_io(e),
+            _=>ErrorKind::Other,
+        }
+    }
+
+    pub fn of(err:&(dyn Error + 'static)) -> ErrorKind {
+        if let Some(e) = err.downcast_ref::<RepoError>() {
+            e.kind
+        } else if let Some(e) = err.downcast_ref::<io::Error>() {
+            ErrorKind::of_io(e)
+        } else if let Some(e) = err.downcast_ref::<git2::Error>() {
+            ErrorKind::of_git(e)
+        } else if let Some(e) = err.downcast_ref::<ClientError>() {
+            ErrorKind::of_github(e)
+        } else {
+            ErrorKind::Other
+        }
+    }
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ErrorKind::Auth=>"authentication error",
+            ErrorKind::NotFound=>"not found",
+            ErrorKind::AlreadyExists=>"already exists",
+            ErrorKind::Conflict=>"conflict",
+            ErrorKind::Network=>"network error",
+            ErrorKind::RateLimited=>"rate limited",
+            ErrorKind::Io=>"I/O error",
+            ErrorKind::Unsupported=>"unsupported",
+            ErrorKind::Internal=>"internal error",
+            ErrorKind::Other=>"error",
+        })
+    }
+}
+
+/**
+ * Why a repo failed, as recorded in the state.  `retryable` says whether the next run should have another go at it;
+ * the others are left alone until something is changed.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RepoError {
+    pub stage: Stage,
+    pub kind: ErrorKind,
+    pub retryable: bool,
+    pub message: String,
+}
+
+impl RepoError {
+    pub fn new(stage:Stage, kind:ErrorKind, message:String) -> RepoError {
+        RepoError {
+            stage,
+            kind,
+            retryable: kind.is_retryable(),
+            message,
+        }
+    }
+
+    //Works out the kind from the underlying error
+    pub fn from_error(stage:Stage, err:&(dyn Error + 'static)) -> RepoError {
+        RepoError::new(stage, ErrorKind::of(err), err.to_string())
+    }
+}
+
+impl fmt::Display for RepoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_fmt(format_args!("{} failed ({}): {}", self.stage, self.kind, self.message))
+    }
+}
+
+impl Error for RepoError {}
+
+/**
+ * Turns the error from something done as part of a stage into a RepoError for that stage, so that the stage can return
+ * a typed error with `?`
+ */
+pub trait AtStage<T> {
+    fn at_stage(self, stage:Stage) -> Result<T, RepoError>;
+}
+
+impl<T, E:Into<Box<dyn Error>>> AtStage<T> for Result<T, E> {
+    fn at_stage(self, stage:Stage) -> Result<T, RepoError> {
+        self.map_err(|e| RepoError::from_error(stage, e.into().as_ref()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_error_kind_of() {
+        let not_found:Box<dyn Error> = Box::new(io::Error::new(io::ErrorKind::NotFound, "no such file"));
+        assert_eq!(ErrorKind::of(not_found.as_ref()), ErrorKind::NotFound);
+
+        let auth:Box<dyn Error> = Box::new(git2::Error::new(git2::ErrorCode::Auth, git2::ErrorClass::Ssh, "bad key"));
+        assert_eq!(ErrorKind::of(auth.as_ref()), ErrorKind::Auth);
+        let network:Box<dyn Error> = Box::new(git2::Error::new(git2::ErrorCode::GenericError, git2::ErrorClass::Net, "connection reset"));
+        assert_eq!(ErrorKind::of(network.as_ref()), ErrorKind::Network);
+
+        let limited:Box<dyn Error> = Box::new(ClientError::RateLimited{duration: 10});
+        assert_eq!(ErrorKind::of(limited.as_ref()), ErrorKind::RateLimited);
+
+        let other:Box<dyn Error> = Box::from("something else");
+        assert_eq!(ErrorKind::of(other.as_ref()), ErrorKind::Other);
+    }
+
+    #[test]
+    fn test_at_stage() {
+        let missing:Result<(), git2::Error> = Err(git2::Error::new(git2::ErrorCode::NotFound, git2::ErrorClass::Reference, "no such branch"));
+        let err = missing.at_stage(Stage::Commit).unwrap_err();
+        assert_eq!((err.stage, err.kind, err.retryable), (Stage::Commit, ErrorKind::NotFound, false));
+
+        //an error that was already typed keeps its kind
+        let unsupported:Result<(), Box<dyn Error>> = Err(Box::new(RepoError::new(Stage::Clone, ErrorKind::Unsupported, "two remotes".to_string())));
+        let err = unsupported.at_stage(Stage::Push).unwrap_err();
+        assert_eq!((err.stage, err.kind, err.retryable), (Stage::Push, ErrorKind::Unsupported, false));
+    }
+
+    #[test]
+    fn test_retryable() {
+        assert!(RepoError::new(Stage::Push, ErrorKind::Network, "timed out".to_string()).retryable);
+        assert!(!RepoError::new(Stage::Push, ErrorKind::Auth, "denied".to_string()).retryable);
+        assert!(!RepoError::new(Stage::Branch, ErrorKind::AlreadyExists, "exists".to_string()).retryable);
+    }
+}
@@ -31,7 +31,7 @@ impl GitConfigParserState {
     fn save_current_keys_to_state(&mut self, section_name:&str) {
         let update = match self.full_state.get(section_name) {
             Some(existing_section)=>
-                existing_section.into_iter()
+                existing_section.iter()
                     .chain(&self.current_keys)
                     .map(|(k,v)| (k.to_owned(), v.to_owned()))
                     .collect(),
@@ -46,11 +46,8 @@ impl GitConfigParserState {
         let prev_section = self.current_section.to_owned();
         self.current_section = Some(section_name.to_owned());
 
-        match &prev_section {
-            Some(section_name) => {
-                self.save_current_keys_to_state(section_name);
-            },
-            None=> (),
+        if let Some(section_name) = &prev_section {
+            self.save_current_keys_to_state(section_name);
         }
     }
 
@@ -140,7 +137,7 @@ impl GitConfig {
             user: None,
         };
 
-        cfg.user = parser.full_state.get("user").map(|raw_user_data| {
+        cfg.user = parser.full_state.get("user").and_then(|raw_user_data| {
             match (
                 raw_user_data.get("name"),
                 raw_user_data.get("email"),
@@ -155,7 +152,7 @@ impl GitConfig {
                 },
                 _=> None,
             }
-        }).flatten();
+        });
 
         Ok( cfg )
     }
@@ -172,6 +169,7 @@ pub fn load_users_git_config() -> Result<GitConfig, Box<dyn Error>> {
     }
 }
 
+#[cfg(test)]
 mod test {
     use super::*;
 
@@ -1,97 +1,293 @@
-use octorust::{auth::Credentials, types::PullsCreateRequest, Client};
-use tokio::runtime::Runtime;
+use octorust::{auth::Credentials, types::{IssuesAddLabelsRequestOneOf, PullRequestData, PullsCreateRequest}, Client};
+use tokio::{runtime::Runtime, sync::Semaphore, task::JoinSet};
+use std::collections::HashMap;
 use std::error::Error;
-use crate::data::{BaseDataDefn, BranchedRepo, PRdRepo};
-use log::{info, error};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use crate::data::{write_datafile, BranchedRepo, PRdRepo, PatchedRepo, RepoDefn};
+use crate::error::{ErrorKind, RepoError, Stage};
+use crate::history::RepoEvent;
+use crate::ratelimit::{rate_limit_wait, unix_now, RateLimiter};
+use crate::gitutils::changed_files;
+use crate::template::render_template;
+use log::{info, warn, error};
 
 use crate::data::{BaseStateDefn, DataElement};
 
-pub async fn create_pull_request(gh_client: &Client, branched: &BranchedRepo, maybe_pr_title:Option<&String>, maybe_pr_description:Option<&String>) -> Result<String, Box<dyn Error>> {
+/**
+ * Returns the default branch of the given repo. If we already found it out when cloning then that is used,
+ * otherwise we ask Github.
+ */
+async fn get_base_branch(gh_client: &Client, repo: &RepoDefn) -> Result<String, Box<dyn Error>> {
+    match repo.main_branch_name.as_ref() {
+        Some(branch)=>Ok(branch.to_owned()),
+        None=>{
+            let response = gh_client.repos().get(&repo.owner, &repo.name).await?;
+            Ok(response.body.default_branch)
+        }
+    }
+}
+
+//One line per patch step with how it went, or just the overall outcome for states from before patches had steps
+fn patch_summary(patched: &PatchedRepo) -> String {
+    if patched.steps.is_empty() {
+        let outcome = match patched.outcome {
+            Some(outcome)=>outcome.to_string(),
+            None if patched.success=>"applied".to_string(),
+            None=>"failed".to_string(),
+        };
+        format!("- patch {}", outcome)
+    } else {
+        patched.steps.iter()
+            .map(|step| format!("- {}: {}", step.step, step.outcome))
+            .collect::<Vec<String>>()
+            .join("\n")
+    }
+}
+
+/**
+ * Lists the files that the branch changed.  This reads the clone with git2, which blocks, so it is done on a thread
+ * that is allowed to.
+ */
+async fn branch_files(branched: &BranchedRepo) -> Vec<String> {
+    let (repo, branch) = (branched.patched.repo.clone(), branched.branch_name.clone());
+    let result = tokio::task::spawn_blocking(move || changed_files(&repo, &branch).map_err(|e| e.to_string())).await;
+    match result.map_err(|e| e.to_string()).and_then(|files| files) {
+        Ok(files)=>files,
+        Err(e)=>{
+            warn!("⚠️ Unable to list the files changed in {}: {}", branched.patched.repo.defn, e);
+            vec![]
+        },
+    }
+}
+
+//The changed files as a Markdown list for the PR description, or on one line for the title
+fn files_changed_text(files: &[String], one_line: bool) -> String {
+    if one_line {
+        files.join(", ")
+    } else {
+        files.iter().map(|f| format!("- `{}`", f)).collect::<Vec<String>>().join("\n")
+    }
+}
+
+/**
+ * Works out the values for the placeholders in the PR title and description, see `template::PLACEHOLDERS`.  Everything
+ * is on one line for the title.
+ */
+fn template_values(branched: &BranchedRepo, base_branch: &str, files: &[String], for_title: bool) -> HashMap<&'static str, String> {
+    let repo = &branched.patched.repo;
+    let patch_output = branched.patched.output.trim_end();
+
+    HashMap::from([
+        ("owner", repo.defn.owner.to_owned()),
+        ("name", repo.defn.name.to_owned()),
+        ("repo", repo.defn.to_string()),
+        ("default_branch", base_branch.to_string()),
+        ("branch", branched.branch_name.to_owned()),
+        ("files_changed", files_changed_text(files, for_title)),
+        ("files_changed_count", files.len().to_string()),
+        ("patch_summary", if for_title { patch_summary(&branched.patched).replace('\n', "; ") } else { patch_summary(&branched.patched) }),
+        ("patch_output", if for_title { patch_output.lines().next().unwrap_or_default().to_string() } else { patch_output.to_string() }),
+    ])
+}
+
+pub async fn create_pull_request(gh_client: &Client, branched: &BranchedRepo, maybe_pr_title:Option<&String>, maybe_pr_description:Option<&String>, labels:&[String]) -> Result<PullRequestData, Box<dyn Error>> {
     let repo = &branched.patched.repo.defn;
-    let base_branch = repo.main_branch_name.as_ref().map(|s| s.as_str()).unwrap_or("main");
-    let pr_title = maybe_pr_title.map(|s| s.as_str()).unwrap_or("(chore): Batchpatch operations");
-    let pr_description = maybe_pr_description.map(|s| s.as_str()).unwrap_or("Batchpatch applied some operations, please see the commit list for details");
+    let base_branch = get_base_branch(gh_client, repo).await?;
+    let files = branch_files(branched).await;
+    let pr_title = render_template(maybe_pr_title.map(|s| s.as_str()).unwrap_or("(chore): Batchpatch operations"), &template_values(branched, &base_branch, &files, true));
+    let pr_description = render_template(maybe_pr_description.map(|s| s.as_str()).unwrap_or("Batchpatch applied some operations, please see the commit list for details"), &template_values(branched, &base_branch, &files, false));
+    let pr_body = if repo.search_matches.is_empty() {
+        pr_description
+    } else {
+        let matches:Vec<String> = repo.search_matches.iter().map(|p| format!("- `{}`", p)).collect();
+        format!("{}\n\nThis repo was selected because of matches in:\n{}", pr_description, matches.join("\n"))
+    };
 
     info!("🏗️ Creating pull request for pushed branch {} on {}", branched.branch_name, repo);
     let req = PullsCreateRequest {
-        base: base_branch.to_string(),
-        body: pr_description.to_string(),
+        base: base_branch,
+        body: pr_body,
         draft: Some(false),
         head: branched.branch_name.clone(),
         issue: 0,   //hmmm the octokit main docs say that this field is optional?? Supplying 0 seems to do the right thing.
         maintainer_can_modify: Some(true),
-        title: pr_title.to_string(),
+        title: pr_title,
     };
 
     let response = gh_client.pulls().create(&repo.owner, &repo.name, &req).await?;
 
-    Ok( response.body.html_url )
+    //The PR is there by now, so failing to label it is not worth failing the repo over
+    if !labels.is_empty() {
+        let label_req = IssuesAddLabelsRequestOneOf::StringVector(labels.to_vec());
+        if let Err(e) = gh_client.issues().add_labels(&repo.owner, &repo.name, response.body.number, &label_req).await {
+            warn!("⚠️ Unable to add labels to {}: {}", response.body.html_url, e);
+        }
+    }
+
+    Ok( response.body )
+}
+
+//How many times a repo is retried after being rate limited, in one run
+const MAX_RATE_LIMIT_RETRIES:u32 = 5;
+//If Github wants us to wait longer than this then we give up for this run, and the repo is tried again next time
+const MAX_RATE_LIMIT_WAIT:Duration = Duration::from_secs(15 * 60);
+
+struct PrDetails {
+    title: Option<String>,
+    description: Option<String>,
+    labels: Vec<String>,
 }
 
 /**
- * Octorust is async and needs to be run inside an appropriate runtime.
- * This sets up the runtime for the purposes of the octorust operation and cleans it up again
- * afterwards.
- * Note that we consume the incoming Vec<DataElement> and pass back a new one in the success response.
+ * Creates the PR for one repo, waiting and trying again if Github rate limits us.  A repo that is still rate limited
+ * at the end is left as a BranchedRepo with `rate_limited_until` set, so that the next run picks it up again.
  */
-fn exec_pr_in_runtime(repos:Vec<DataElement>, gh_token: &str, maybe_pr_title:Option<&String>, maybe_pr_description:Option<&String>) -> Result<Vec<DataElement>, Box<dyn Error>> {
-    let rt = Runtime::new()?;
-    let client = Client::new(String::from("batchpatch"), Credentials::Token(gh_token.to_string()))?;
-
-    let updated_repos = rt.block_on(async move {
-        let mut updates_list:Vec<DataElement> = vec![];
-
-        for elmt in repos {
-            let updated = match elmt {
-                DataElement::BranchedRepo(branched) if branched.committed && branched.pushed => {
-                    match create_pull_request(&client, &branched, maybe_pr_title, maybe_pr_description).await {
-                        Ok(pr_url)=>
-                            DataElement::PRdRepo(
-                                PRdRepo {
-                                    branched: branched,
-                                    url: pr_url,
-                                }
-                            ),
-                        Err(e)=>{
-                            DataElement::BranchedRepo(BranchedRepo {
-                                patched: branched.patched,
-                                branch_name: branched.branch_name,
-                                committed: branched.committed,
-                                pushed: branched.pushed,
-                                last_error: Some(e.to_string())
-                            })
-                        }
-                    }
-                },
-                other @_ => other
-            };
-            updates_list.push(updated);
+async fn create_pull_request_with_retries(client: &Client, mut branched: BranchedRepo, details: &PrDetails, limiter: &RateLimiter) -> DataElement {
+    let repo = branched.patched.repo.defn.to_string();
+
+    //If we were rate limited last time, and it hasn't worn off yet, then hold off
+    if let Some(until) = branched.rate_limited_until {
+        let remaining = Duration::from_secs(until.saturating_sub(unix_now()));
+        if remaining > MAX_RATE_LIMIT_WAIT {
+            warn!("⏳ {} is rate limited for another {}s, leaving it for the next run", repo, remaining.as_secs());
+            return DataElement::BranchedRepo(branched);
         }
-        updates_list
-    });
+        limiter.pause_for(remaining);
+    }
 
-    Ok( updated_repos )
+    let mut retries = 0;
+    loop {
+        limiter.wait().await;
+        branched.pr_attempts += 1;
+        let result = create_pull_request(client, &branched, details.title.as_ref(), details.description.as_ref(), &details.labels).await;
+        match result {
+            Ok(pr)=>{
+                info!("🎉 Created {} for {}", pr.html_url, repo);
+                branched.last_error = None;
+                branched.rate_limited_until = None;
+                return DataElement::PRdRepo(PRdRepo {
+                    branched,
+                    url: pr.html_url,
+                    number: Some(pr.number),
+                    node_id: Some(pr.node_id),
+                });
+            },
+            Err(e)=>{
+                let wait = rate_limit_wait(e.as_ref());
+                //Github doesn't always make it obvious that a 403 is a rate limit, but if it told us how long to wait then it is
+                let kind = if wait.is_some() { ErrorKind::RateLimited } else { ErrorKind::of(e.as_ref()) };
+                branched.last_error = Some(RepoError::new(Stage::PullRequest, kind, e.to_string()));
+                branched.rate_limited_until = wait.map(|w| unix_now() + w.as_secs());
+                match wait {
+                    Some(wait) if retries < MAX_RATE_LIMIT_RETRIES && wait <= MAX_RATE_LIMIT_WAIT=>{
+                        retries += 1;
+                        warn!("⏳ Rate limited creating the PR for {}, waiting {}s before trying again ({} of {})", repo, wait.as_secs(), retries, MAX_RATE_LIMIT_RETRIES);
+                        limiter.pause_for(wait);
+                    },
+                    Some(wait)=>{
+                        warn!("⏳ Still rate limited creating the PR for {} (for {}s), it will be tried again on the next run", repo, wait.as_secs());
+                        return DataElement::BranchedRepo(branched);
+                    },
+                    None=>{
+                        error!("👎 Unable to create the PR for {}: {}", repo, branched.last_error.as_ref().map(|e| e.to_string()).unwrap_or_default());
+                        return DataElement::BranchedRepo(branched);
+                    },
+                }
+            },
+        }
+    }
 }
 
 /**
- * Pass in the current app state to raise PRs for all applicable repos.
- * Either returns an updated state, or an error if we were not able to communicate with GH.
- * If at least one repo succeeded, then a success is returned and the state of the individual repos is updated
- * to reflect their success/failure. A global error is only returned if we were unable to _start_ the PR
- * creation operation
+ * Octorust is async and needs to be run inside an appropriate runtime.
+ * This creates PRs for up to `jobs` repos at once, and writes the state out (with the repo's history) as each one
+ * finishes.  The tasks run on the runtime, but we wait for them here so that writing the state file, which blocks,
+ * is not done on the runtime.
  */
-pub fn create_all_pull_requests(state:BaseStateDefn, gh_token: &str) -> Result<BaseStateDefn, Box<dyn Error>> {
-    match exec_pr_in_runtime(state.data.repos, gh_token, state.pr_title.as_ref(), state.pr_description.as_ref()) {
-        Ok(new_repos)=>Ok( BaseStateDefn {
-            data: BaseDataDefn {
-                repos: new_repos,
+fn exec_pr_in_runtime(rt:&Runtime, client:Client, state:&mut BaseStateDefn, state_file:&Path, jobs:usize) -> Result<(), Box<dyn Error>> {
+    let details = Arc::new(PrDetails {
+        title: state.pr_title.to_owned(),
+        description: state.pr_description.to_owned(),
+        labels: state.pr_labels.to_owned(),
+    });
+    let client = Arc::new(client);
+    let limiter = Arc::new(RateLimiter::default());
+    let permits = Arc::new(Semaphore::new(jobs.max(1)));
+
+    let mut tasks = JoinSet::new();
+    //so that we know which repo a task was for if it panics
+    let mut task_repos = HashMap::new();
+    for (i, elmt) in state.data.repos.iter().enumerate() {
+        if let DataElement::BranchedRepo(branched) = elmt {
+            if branched.committed && branched.pushed && branched.can_continue() && !branched.patched.repo.defn.skipped {
+                let branched = branched.clone();
+                let (client, details, limiter, permits) = (client.clone(), details.clone(), limiter.clone(), permits.clone());
+                let task = tasks.spawn_on(async move {
+                    let _permit = permits.acquire_owned().await;
+                    let (at, started, attempts) = (unix_now(), Instant::now(), branched.pr_attempts);
+                    let updated = create_pull_request_with_retries(&client, branched, &details, &limiter).await;
+                    //a repo that was still rate limited from last time was not tried at all, so there is nothing to record
+                    let event = match &updated {
+                        DataElement::BranchedRepo(branched) if branched.pr_attempts==attempts=>None,
+                        _=>Some(RepoEvent::new(Stage::PullRequest, &updated, at, started.elapsed())),
+                    };
+                    (updated, event)
+                }, rt.handle());
+                task_repos.insert(task.id(), i);
+            }
+        }
+    }
+
+    while let Some(finished) = rt.block_on(tasks.join_next_with_id()) {
+        match finished {
+            Ok((id, (updated, event)))=>{
+                if let Some(event) = event {
+                    state.record_event(updated.defn(), event);
+                }
+                state.data.repos[task_repos[&id]] = updated;
             },
-            pr_description: state.pr_description,
-            pr_title: state.pr_title
-        }),
+            Err(e)=>{
+                let i = task_repos[&e.id()];
+                error!("💥 Internal error creating the PR for {}: {}", state.data.repos[i].defn(), e);
+                let failed = state.data.repos[i].clone().fail(RepoError::new(Stage::PullRequest, ErrorKind::Internal, e.to_string()));
+                state.record_event(failed.defn(), RepoEvent::new(Stage::PullRequest, &failed, unix_now(), Duration::ZERO));
+                state.data.repos[i] = failed;
+            },
+        }
+        write_datafile(state_file, state)?;
+    }
+    Ok( () )
+}
+
+/**
+ * Raises PRs for all applicable repos in the state, with up to `jobs` being created at once, and updates the state
+ * (and the state file) with how each one went.
+ * A global error is only returned if we were unable to _start_ the PR creation operation, or to save the state.
+ */
+pub fn create_all_pull_requests(state:&mut BaseStateDefn, state_file:&Path, gh_token: &str, jobs:usize) -> Result<(), Box<dyn Error>> {
+    let setup = || -> Result<(Runtime, Client), Box<dyn Error>> {
+        Ok( (Runtime::new()?, Client::new(String::from("batchpatch"), Credentials::Token(gh_token.to_string()))?) )
+    };
+
+    match setup() {
+        Ok((rt, client))=>exec_pr_in_runtime(&rt, client, state, state_file, jobs),
         Err(e)=>{
-            error!("💩 Unable to communicate with Github: {}", e.to_string());
+            error!("💩 Unable to communicate with Github: {}", e);
             Err(Box::from("unable to communicate with Github to create a PR"))
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_files_changed_text() {
+        let files = vec!["README.md".to_string(), "src/lib.rs".to_string()];
+        assert_eq!(files_changed_text(&files, false), "- `README.md`\n- `src/lib.rs`");
+        assert_eq!(files_changed_text(&files, true), "README.md, src/lib.rs");
+    }
+}
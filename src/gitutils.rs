@@ -1,15 +1,16 @@
 use crate::data::{ConfigFile, LocalRepo};
+use crate::error::{AtStage, ErrorKind, RepoError, Stage};
 use git2::{build::{RepoBuilder,CheckoutBuilder}, BranchType, Cred, IndexAddOption, RemoteCallbacks, Repository, Signature};
 use std::error::Error;
 use log::{error,debug,info,warn};
 use std::path::Path;
 
-pub fn build_git_client(config: &ConfigFile) -> RepoBuilder {
+pub fn build_git_client(config: &ConfigFile) -> RepoBuilder<'_> {
     let mut gitclient = git2::build::RepoBuilder::new();
 
     //Do we have a github access token? If so then set it
     let fetch_opts = config.github_access_token.as_ref().map(|tok| {
-        println!("INFO Configuring token authentication");
+        info!("Configuring token authentication");
         let mut callbacks = RemoteCallbacks::new();
         callbacks.credentials(|_url, username_from_url, _allowed_types| {
             Cred::userpass_plaintext(
@@ -27,6 +28,43 @@ pub fn build_git_client(config: &ConfigFile) -> RepoBuilder {
     gitclient
 }
 
+/**
+ * Works out the default branch of a cloned repo. This is taken from the remote's HEAD (e.g. refs/remotes/origin/HEAD)
+ * if we have it, otherwise from whatever branch is currently checked out.
+ */
+pub fn detect_default_branch(repo:&Repository) -> Result<String, Box<dyn Error>> {
+    for remote_name in repo.remotes()?.iter().flatten() {
+        let remote_head = format!("refs/remotes/{}/HEAD", remote_name);
+        if let Ok(reference) = repo.find_reference(&remote_head) {
+            let prefix = format!("refs/remotes/{}/", remote_name);
+            if let Some(branch) = reference.symbolic_target().and_then(|t| t.strip_prefix(&prefix)) {
+                debug!("Remote HEAD for {} points to {}", remote_name, branch);
+                return Ok(branch.to_owned());
+            }
+        }
+    }
+
+    let head = repo.head()?;
+    match head.shorthand() {
+        Some(branch) if head.is_branch() => Ok(branch.to_owned()),
+        _ => Err(Box::new(RepoError::new(Stage::Clone, ErrorKind::Unsupported, "could not determine the default branch, HEAD is not a branch".to_string()))),
+    }
+}
+
+/**
+ * Makes sure that the given branch is checked out, creating a local branch from the remote-tracking one
+ * if we don't have it yet.
+ */
+pub fn checkout_branch(repo:&Repository, branch:&str) -> Result<(), Box<dyn Error>> {
+    if repo.find_branch(branch, BranchType::Local).is_err() {
+        let remote_branch = repo.find_branch(&format!("origin/{}", branch), BranchType::Remote)?;
+        let commit = remote_branch.get().peel_to_commit()?;
+        let mut local_branch = repo.branch(branch, &commit, false)?;
+        local_branch.set_upstream(Some(&format!("origin/{}", branch)))?;
+    }
+    clean_repo(repo, branch, true)
+}
+
 pub fn clean_repo_by_path(clone_path: &Path, branch:&str) -> Result<(), Box<dyn Error>> {
     let repo = Repository::open(clone_path)?;
     clean_repo(&repo, branch, true)
@@ -50,6 +88,7 @@ pub fn clean_repo(repo:&Repository, branch:&str, reset_head:bool) -> Result<(),
             debug!("target oid is {}", oid);
             let obj = repo.find_object(oid, None)?;
             if reset_head {
+                repo.set_head(&format!("refs/heads/{}", branch))?;
                 repo.reset(&obj, git2::ResetType::Hard, Some(&mut cb))?;
             }
             repo.checkout_tree(&obj, Some(&mut cb))?;   //we need to do this to actually remove untracked files
@@ -62,63 +101,202 @@ pub fn clean_repo(repo:&Repository, branch:&str, reset_head:bool) -> Result<(),
     }
 }
 
-pub fn do_branch(repo: &LocalRepo, branch_name:&str) -> Result<(), Box<dyn Error>> {
-    let repo_ref = Repository::open(&repo.local_path)?;
+//Throws away any changes to the working directory and index, including new files, leaving the current branch as it is
+pub fn discard_changes(repo:&Repository) -> Result<(), Box<dyn Error>> {
+    let mut cb = CheckoutBuilder::new();
+    cb.remove_untracked(true);
+    cb.recreate_missing(true);
+    cb.force();
+
+    let head = repo.head()?.peel_to_commit()?;
+    repo.reset(head.as_object(), git2::ResetType::Hard, Some(&mut cb))?;
+    repo.checkout_head(Some(&mut cb))?;   //we need to do this to actually remove untracked files
+    Ok( () )
+}
+
+//Deletes the local branch, if it is there.  It must not be checked out.
+pub fn delete_branch(repo:&Repository, branch:&str) -> Result<(), Box<dyn Error>> {
+    match repo.find_branch(branch, BranchType::Local) {
+        Ok(mut branch_ref)=>{
+            info!("🪓 Deleting branch {}", branch);
+            Ok( branch_ref.delete()? )
+        },
+        Err(e) if e.code()==git2::ErrorCode::NotFound=>Ok( () ),
+        Err(e)=>Err(Box::from(e)),
+    }
+}
 
-    //Use the current HEAD as the parent of the new commit
-    let head = repo_ref.head()?;
-    let head_commit =head.peel_to_commit()?;
+/**
+ * Creates the branch on the given repo, from the tip of its default branch.  Returns the id of the commit that it was
+ * made from.
+ */
+pub fn do_branch(repo: &LocalRepo, branch_name:&str) -> Result<String, RepoError> {
+    let repo_ref = Repository::open(&repo.local_path).at_stage(Stage::Branch)?;
+
+    //Use the tip of the repo's default branch as the parent of the new commit, or the current HEAD if we don't know it
+    let base_commit = match repo.defn.main_branch_name.as_ref() {
+        Some(main_branch)=>repo_ref.find_branch(main_branch, BranchType::Local).and_then(|b| b.get().peel_to_commit()),
+        None=>repo_ref.head().and_then(|h| h.peel_to_commit()),
+    }.at_stage(Stage::Branch)?;
 
-    repo_ref.branch(branch_name, &head_commit, false)?;
+    repo_ref.branch(branch_name, &base_commit, false).at_stage(Stage::Branch)?;
 
-    Ok ( () )
+    Ok ( base_commit.id().to_string() )
 }
 
 /**
  * do_commit creates a new branch on the given repo and commits the current working state with the given commit log.
+ * Returns the id of the new commit.
  * See https://stackoverflow.com/questions/27672722/libgit2-commit-example
  */
-pub fn do_commit(repo: &LocalRepo, sig:&Signature, branch_name:&str, commit_log:&str) -> Result<(), Box<dyn Error>> {
-    let repo_ref = Repository::open(&repo.local_path)?;
+pub fn do_commit(repo: &LocalRepo, sig:&Signature, branch_name:&str, commit_log:&str) -> Result<String, RepoError> {
+    let repo_ref = Repository::open(&repo.local_path).at_stage(Stage::Commit)?;
 
     //Use the tip of the given branch as the parent of the new commit
-    let parent_oid = match repo_ref.find_branch(branch_name, git2::BranchType::Local)?.into_reference().target() {
+    let parent_oid = match repo_ref.find_branch(branch_name, git2::BranchType::Local).at_stage(Stage::Commit)?.into_reference().target() {
         Some(oid)=>Ok(oid),
         None=>{
             error!("branch reference did not point to an object");
-            Err( Box::<(dyn Error + 'static)>::from("the branch was not properly created"))
+            Err( RepoError::new(Stage::Commit, ErrorKind::NotFound, "the branch was not properly created".to_string()))
         }
     }?;
 
     //Get the current index and write it to a tree
-    let mut index = repo_ref.index()?;
-    index.add_all(["*", ".*", "**"].iter(), IndexAddOption::DEFAULT, None)?;
+    let mut index = repo_ref.index().at_stage(Stage::Commit)?;
+    index.add_all(["*", ".*", "**"].iter(), IndexAddOption::DEFAULT, None).at_stage(Stage::Commit)?;
     // let tree = repo_ref.find_branch(branch_name, git2::BranchType::Local)?.get().peel_to_tree()?;
     // let diffs = repo_ref.diff_index_to_workdir(None, None)?;
     // repo_ref.apply(&diffs, git2::ApplyLocation::Index, None)?;
     //let mut new_index = repo_ref.apply_to_tree(&tree, &diffs, None)?;
 
-    let oid = repo_ref.index()?.write_tree()?;
-    let tree = repo_ref.find_tree(oid)?;
+    let oid = repo_ref.index().and_then(|mut i| i.write_tree()).at_stage(Stage::Commit)?;
+    let tree = repo_ref.find_tree(oid).at_stage(Stage::Commit)?;
     let reference_name = format!("refs/heads/{}", branch_name);
 
     //The result needs to be created as a local here in order to keep the borrow-checker happy at function cleanup
-    let result = match repo_ref.find_object(parent_oid, None)?.into_commit() {
+    let result = match repo_ref.find_object(parent_oid, None).at_stage(Stage::Commit)?.into_commit() {
         Ok(parent_commit)=>{
             debug!("Parent commit is {}", parent_commit.id());
             let parents = [&parent_commit];
 
-            repo_ref.commit(Some(&reference_name), &sig, &sig, commit_log, &tree, &parents)?;
+            let commit_oid = repo_ref.commit(Some(&reference_name), sig, sig, commit_log, &tree, &parents).at_stage(Stage::Commit)?;
 
             //clean up after ourselves - reset the branch to clean out any workingdir changes. don't reset HEAD or that will point mainbranch to the update which we don't want.
-            clean_repo(&repo_ref, branch_name, false)?;
-            Ok( () )
+            clean_repo(&repo_ref, branch_name, false).at_stage(Stage::Commit)?;
+            Ok( commit_oid.to_string() )
         },
         Err(_)=>{
             error!("The branch {} did not point to a commit", oid);
-            Err( Box::from("the branch was not properly created"))
+            Err( RepoError::new(Stage::Commit, ErrorKind::NotFound, "the branch was not properly created".to_string()))
         }
     };
 
     result
 }
+
+/**
+ * Lists the files that the commit at the tip of the given branch changed, compared to its parent
+ */
+pub fn changed_files(repo: &LocalRepo, branch_name:&str) -> Result<Vec<String>, Box<dyn Error>> {
+    let repo_ref = Repository::open(&repo.local_path)?;
+    let commit = repo_ref.find_branch(branch_name, BranchType::Local)?.get().peel_to_commit()?;
+    let parent_tree = match commit.parent_count() {
+        0=>None,
+        _=>Some(commit.parent(0)?.tree()?),
+    };
+
+    let diff = repo_ref.diff_tree_to_tree(parent_tree.as_ref(), Some(&commit.tree()?), None)?;
+    let files = diff.deltas()
+        .filter_map(|delta| delta.new_file().path().or(delta.old_file().path()).map(|p| p.to_string_lossy().to_string()))
+        .collect();
+    Ok( files )
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    //Writes the files (as path and content) into the repo and makes them its first commit
+    fn commit_files(repo:Repository, files:&[(&str, &str)]) -> Result<Repository, Box<dyn Error>> {
+        let workdir = repo.workdir().ok_or("the repo has no working directory")?.to_path_buf();
+        {
+            let mut index = repo.index()?;
+            for (path, content) in files {
+                let full_path = workdir.join(path);
+                if let Some(parent) = full_path.parent() {
+                    std::fs::create_dir_all(parent)?;
+                }
+                std::fs::write(full_path, content)?;
+                index.add_path(Path::new(path))?;
+            }
+            index.write()?;
+            let tree = repo.find_tree(index.write_tree()?)?;
+            let sig = Signature::now("Test User", "test@example.com")?;
+            repo.commit(Some("HEAD"), &sig, &sig, "initial", &tree, &[])?;
+        }
+        Ok(repo)
+    }
+
+    //A new repo in the directory, with the files committed to it
+    pub fn repo_with_commit(dir:&Path, files:&[(&str, &str)]) -> Result<Repository, Box<dyn Error>> {
+        commit_files(Repository::init(dir)?, files)
+    }
+
+    fn init_repo_with_commit(dir:&Path, branch:&str) -> Result<Repository, Box<dyn Error>> {
+        let mut opts = git2::RepositoryInitOptions::new();
+        opts.initial_head(branch);
+        commit_files(Repository::init_opts(dir, &opts)?, &[])
+    }
+
+    #[test]
+    fn test_detect_default_branch_from_head() -> Result<(), Box<dyn Error>> {
+        let dir = TempDir::new()?;
+        let repo = init_repo_with_commit(dir.path(), "develop")?;
+
+        assert_eq!(detect_default_branch(&repo)?, "develop");
+        Ok( () )
+    }
+
+    #[test]
+    fn test_changed_files() -> Result<(), Box<dyn Error>> {
+        let dir = TempDir::new()?;
+        let repo = init_repo_with_commit(dir.path(), "main")?;
+        std::fs::write(dir.path().join("README.md"), "hello")?;
+        std::fs::create_dir(dir.path().join("src"))?;
+        std::fs::write(dir.path().join("src").join("lib.rs"), "")?;
+
+        let local = LocalRepo {
+            defn: crate::data::RepoDefn::new("test/repo")?,
+            local_path: dir.path().into(),
+            last_error: None,
+        };
+        let base_commit = do_branch(&local, "patched")?;
+        assert_eq!(base_commit, repo.head()?.peel_to_commit()?.id().to_string());
+        let sig = Signature::now("Test User", "test@example.com")?;
+        let commit = do_commit(&local, &sig, "patched", "a change")?;
+        let tip = repo.find_branch("patched", BranchType::Local)?.get().peel_to_commit()?;
+        assert_eq!(commit, tip.id().to_string());
+        assert_eq!(tip.parent_id(0)?.to_string(), base_commit);
+
+        assert_eq!(changed_files(&local, "patched")?, vec!["README.md", "src/lib.rs"]);
+        assert_eq!(repo.head()?.shorthand(), Some("main"));
+        Ok( () )
+    }
+
+    #[test]
+    fn test_detect_default_branch_from_remote_head() -> Result<(), Box<dyn Error>> {
+        let origin_dir = TempDir::new()?;
+        init_repo_with_commit(origin_dir.path(), "master")?;
+
+        let clone_dir = TempDir::new()?;
+        let cloned = Repository::clone(origin_dir.path().to_str().unwrap(), clone_dir.path())?;
+        //even if something else is checked out, the remote HEAD should win
+        let head_commit = cloned.head()?.peel_to_commit()?;
+        cloned.branch("feature", &head_commit, false)?;
+        cloned.set_head("refs/heads/feature")?;
+
+        assert_eq!(detect_default_branch(&cloned)?, "master");
+        Ok( () )
+    }
+}
@@ -0,0 +1,241 @@
+use std::fmt::{self, Write};
+use std::time::Duration;
+use serde::{Deserialize, Serialize};
+
+use crate::data::{BaseStateDefn, DataElement, PatchOutcome, RepoDefn};
+use crate::error::{ErrorKind, RepoError, Stage};
+use crate::status::write_table;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum EventOutcome {
+    Succeeded,
+    //The patch ran, but didn't change anything, so the repo goes no further
+    NoChanges,
+    Failed,
+    //The repo was changed by the retry, reset or skip commands rather than going through the stage
+    Retried,
+    Reset,
+    Skipped,
+}
+
+impl fmt::Display for EventOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            EventOutcome::Succeeded=>"succeeded",
+            EventOutcome::NoChanges=>"no changes",
+            EventOutcome::Failed=>"failed",
+            EventOutcome::Retried=>"retried",
+            EventOutcome::Reset=>"reset",
+            EventOutcome::Skipped=>"skipped",
+        })
+    }
+}
+
+/**
+ * One stage that a repo went through, and how it went.  The state only keeps the latest snapshot of each repo, so these
+ * are kept alongside it to show what happened on earlier runs, e.g. a push that failed before a retry worked.
+ */
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RepoEvent {
+    //Unix time that the stage started at
+    pub at: u64,
+    pub stage: Stage,
+    pub outcome: EventOutcome,
+    pub duration_ms: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RepoError>,
+    //The commit that we made on the repo's branch, once it has been committed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pr_number: Option<i64>,
+}
+
+/**
+ * Why the repo failed, as a RepoError.  A patch step failing is recorded as the step's outcome rather than as an
+ * error, so for those we make one up from the step.
+ */
+fn error_of(elmt:&DataElement) -> Option<RepoError> {
+    match elmt {
+        DataElement::LocalRepo(repo)=>repo.last_error.clone(),
+        DataElement::PatchedRepo(repo)=>match (repo.error.as_ref(), repo.success) {
+            (Some(e), _)=>Some(e.clone()),
+            (None, true)=>None,
+            (None, false)=>{
+                let kind = if repo.outcome==Some(PatchOutcome::Conflicted) { ErrorKind::Conflict } else { ErrorKind::Other };
+                let message = match repo.steps.iter().find(|s| !s.outcome.is_success()) {
+                    Some(step)=>format!("{}: {}", step.step, step.output.trim()),
+                    None=>repo.output.trim().to_string(),
+                };
+                Some(RepoError::new(Stage::Patch, kind, message))
+            },
+        },
+        DataElement::BranchedRepo(repo)=>repo.last_error.clone(),
+        DataElement::PRdRepo(_) | DataElement::RemoteRepo(_)=>None,
+    }
+}
+
+fn commit_of(elmt:&DataElement) -> Option<String> {
+    match elmt {
+        DataElement::BranchedRepo(repo)=>repo.commit.clone(),
+        DataElement::PRdRepo(repo)=>repo.branched.commit.clone(),
+        _=>None,
+    }
+}
+
+fn pr_number_of(elmt:&DataElement) -> Option<i64> {
+    match elmt {
+        DataElement::PRdRepo(repo)=>repo.number,
+        _=>None,
+    }
+}
+
+impl RepoEvent {
+    //Describes how the stage went from the repo as the stage left it
+    pub fn new(stage:Stage, elmt:&DataElement, at:u64, duration:Duration) -> RepoEvent {
+        let error = error_of(elmt);
+        let outcome = match (&error, elmt) {
+            (Some(_), _)=>EventOutcome::Failed,
+            (None, DataElement::PatchedRepo(repo)) if repo.changes==0=>EventOutcome::NoChanges,
+            (None, _)=>EventOutcome::Succeeded,
+        };
+        RepoEvent {
+            at,
+            stage,
+            outcome,
+            duration_ms: duration.as_millis() as u64,
+            error,
+            commit: commit_of(elmt),
+            pr_number: pr_number_of(elmt),
+        }
+    }
+
+    //Records that the repo was retried, reset or skipped, with the stage that it goes through next
+    pub fn changed(stage:Stage, outcome:EventOutcome, at:u64) -> RepoEvent {
+        RepoEvent { at, stage, outcome, duration_ms: 0, error: None, commit: None, pr_number: None }
+    }
+}
+
+/**
+ * Formats a unix time as a UTC date and time, e.g. 2024-03-01 09:30:00.  See
+ * http://howardhinnant.github.io/date_algorithms.html#civil_from_days for how the date is worked out.
+ */
+fn format_time(unix:u64) -> String {
+    let days = (unix / 86400) as i64;
+    let secs = unix % 86400;
+
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, secs / 3600, secs % 3600 / 60, secs % 60)
+}
+
+//What else there is to say about the event; the error if it failed, otherwise what it produced
+fn event_detail(event:&RepoEvent) -> String {
+    match (event.error.as_ref(), event.pr_number, event.commit.as_ref()) {
+        //only the first line, so that the table stays readable
+        (Some(err), _, _)=>err.to_string().lines().next().unwrap_or_default().to_string(),
+        (None, Some(number), _)=>format!("PR #{}", number),
+        (None, None, Some(commit))=>format!("commit {}", commit),
+        (None, None, None)=>String::new(),
+    }
+}
+
+/**
+ * Lists the events recorded for each repo, oldest first, with the times in UTC.  If `only` is given then just that
+ * repo is shown.
+ */
+pub fn render_history(state:&BaseStateDefn, only:Option<&RepoDefn>) -> Result<String, fmt::Error> {
+    let mut out = String::new();
+    for elmt in state.data.repos.iter() {
+        let defn = elmt.defn();
+        if only.is_some_and(|only| only.to_string()!=defn.to_string()) {
+            continue;
+        }
+        let events = state.history_of(defn);
+        if events.is_empty() {
+            continue;
+        }
+
+        if !out.is_empty() {
+            writeln!(out)?;
+        }
+        writeln!(out, "{}", defn)?;
+        let mut rows = vec![vec!["TIME (UTC)".to_string(), "STAGE".to_string(), "OUTCOME".to_string(), "TOOK".to_string(), "DETAIL".to_string()]];
+        for event in events {
+            rows.push(vec![
+                format_time(event.at),
+                event.stage.to_string(),
+                event.outcome.to_string(),
+                format!("{:.1}s", event.duration_ms as f64 / 1000.0),
+                event_detail(event),
+            ]);
+        }
+        write_table(&mut out, &rows)?;
+    }
+
+    if out.is_empty() {
+        writeln!(out, "No history to show")?;
+    }
+    Ok( out )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::status::test::test_state;
+
+    #[test]
+    fn test_format_time() {
+        assert_eq!(format_time(0), "1970-01-01 00:00:00");
+        assert_eq!(format_time(951782400), "2000-02-29 00:00:00");
+        assert_eq!(format_time(1700000000), "2023-11-14 22:13:20");
+    }
+
+    #[test]
+    fn test_event_of() {
+        let state = test_state();
+        let events:Vec<RepoEvent> = state.data.repos.iter().map(|elmt| RepoEvent::new(Stage::Push, elmt, 100, Duration::from_millis(1500))).collect();
+        assert_eq!(events[1].outcome, EventOutcome::NoChanges);
+        assert_eq!(events[2].outcome, EventOutcome::Succeeded);
+        assert_eq!(events[2].commit.as_deref(), Some("2222222222222222222222222222222222222222"));
+        assert_eq!(events[3].outcome, EventOutcome::Failed);
+        assert_eq!(events[3].error.as_ref().map(|e| e.kind), Some(ErrorKind::Auth));
+        assert_eq!(events[4].pr_number, Some(1));
+        assert_eq!(events[4].duration_ms, 1500);
+    }
+
+    #[test]
+    fn test_render_history() -> Result<(), Box<dyn std::error::Error>> {
+        let mut state = test_state();
+        assert_eq!(render_history(&state, None)?, "No history to show\n");
+
+        let unpushed = state.data.repos[3].clone();
+        let done = state.data.repos[4].clone();
+        state.record_event(unpushed.defn(), RepoEvent::new(Stage::Push, &unpushed, 1700000000, Duration::from_millis(2340)));
+        state.record_event(done.defn(), RepoEvent::new(Stage::Push, &unpushed, 1700000000, Duration::from_millis(100)));
+        state.record_event(done.defn(), RepoEvent::new(Stage::PullRequest, &done, 1700000060, Duration::from_millis(800)));
+
+        let all = render_history(&state, None)?;
+        assert!(all.starts_with("org/unpushed\n"));
+        assert!(all.contains("2023-11-14 22:13:20  push   failed   2.3s  push failed (authentication error): denied\n"));
+        assert!(all.contains("\norg/done\n"));
+        assert!(all.contains("2023-11-14 22:14:20  pull request  succeeded  0.8s  PR #1\n"));
+
+        state.record_event(unpushed.defn(), RepoEvent::changed(Stage::Push, EventOutcome::Retried, 1700000120));
+        let retried = render_history(&state, Some(unpushed.defn()))?;
+        assert!(retried.ends_with("2023-11-14 22:15:20  push   retried  0.0s\n"));
+
+        let done_only = render_history(&state, Some(&RepoDefn::new("org/done")?))?;
+        assert!(!done_only.contains("org/unpushed"));
+        assert_eq!(done_only.lines().count(), 4);
+        Ok( () )
+    }
+}
@@ -1,9 +1,9 @@
 use std::error::Error;
 use std::fs::File;
-use std::io::{self, BufRead};
+use std::io::{self, BufRead, Write};
 use std::path::Path;
 use log::warn;
-use crate::data::{BaseDataDefn, BaseStateDefn, DataElement, RepoDefn};
+use crate::data::{BaseStateDefn, DataElement, RepoDefn};
 
 pub fn read_repo_list(source:&Path, fault_tolerant:bool) -> Result<Box<BaseStateDefn>, Box<dyn Error>> {
     let file = File::open(source)?;
@@ -20,7 +20,7 @@ pub fn read_repo_list(source:&Path, fault_tolerant:bool) -> Result<Box<BaseState
         .map(|err| err.as_ref().unwrap_err())
         .collect();
 
-    if errors.len() > 0 {
+    if !errors.is_empty() {
         warn!("{} lines from {} failed to parse: ", errors.len(), source.display());
         for err in errors {
             warn!("{}", err);
@@ -30,38 +30,45 @@ pub fn read_repo_list(source:&Path, fault_tolerant:bool) -> Result<Box<BaseState
         }
     }
 
-    Ok(Box::new(BaseStateDefn {
-        data: BaseDataDefn {
-            repos: defs.into_iter()
-                .filter(|maybe_defn| maybe_defn.is_ok())
-                .map(|defn| DataElement::RemoteRepo(defn.unwrap()))
-                .collect()
-        },
-        pr_description: None,
-        pr_title: None,
-    }))
+    Ok(Box::new(BaseStateDefn::new(
+        defs.into_iter()
+            .filter(|maybe_defn| maybe_defn.is_ok())
+            .map(|defn| DataElement::RemoteRepo(defn.unwrap()))
+            .collect()
+    )))
 }
 
+/**
+ * Writes out a list of repositories in the format that read_repo_list expects, one {org}/{repo-name} per line
+ */
+pub fn write_repo_list(dest:&Path, repos:&[RepoDefn]) -> Result<(), Box<dyn Error>> {
+    let mut file = File::create(dest)?;
+    for repo in repos {
+        writeln!(file, "{}", repo)?;
+    }
+    Ok( () )
+}
+
+#[cfg(test)]
 mod test {
     use tempfile::NamedTempFile;
-    use std::io::Write;
 
     use crate::data::DataElement;
 
     use super::*;
 
     fn create_fixture(dest:&mut dyn Write) -> Result<(), Box<dyn Error>> {
-        dest.write("my-org/first_repo1\n".as_bytes())?;
-        dest.write("https://github.com/my-org/first_repo2\n".as_bytes())?;
-        dest.write("your0rg/another-repo\n".as_bytes())?;
+        dest.write_all("my-org/first_repo1\n".as_bytes())?;
+        dest.write_all("https://github.com/my-org/first_repo2\n".as_bytes())?;
+        dest.write_all("your0rg/another-repo\n".as_bytes())?;
         Ok( () )
     }
 
     fn create_problematic_fixture(dest:&mut dyn Write) -> Result<(), Box<dyn Error>> {
-        dest.write("my-org/first_repo1\n".as_bytes())?;
-        dest.write("my-org/first_repo2\n".as_bytes())?;
-        dest.write("rogue line here!\n".as_bytes())?;
-        dest.write("your0rg/another-repo\n".as_bytes())?;
+        dest.write_all("my-org/first_repo1\n".as_bytes())?;
+        dest.write_all("my-org/first_repo2\n".as_bytes())?;
+        dest.write_all("rogue line here!\n".as_bytes())?;
+        dest.write_all("your0rg/another-repo\n".as_bytes())?;
         Ok( () )
     }
 
@@ -160,6 +167,22 @@ mod test {
         Ok( () )
     }
 
+    #[test]
+    fn test_write_repo_list_roundtrip() -> Result<(), Box<dyn Error>> {
+        let file = NamedTempFile::new()?;
+        let repos = vec![
+            RepoDefn::new("my-org/first_repo1")?,
+            RepoDefn::new("your0rg/another-repo")?,
+        ];
+        write_repo_list(file.path(), &repos)?;
+
+        let result = read_repo_list(file.path(), false)?;
+        assert_eq!(result.data.repos.len(), 2);
+        assert_eq!(result.data.repos[0].defn().to_string(), "my-org/first_repo1");
+        assert_eq!(result.data.repos[1].defn().to_string(), "your0rg/another-repo");
+        Ok( () )
+    }
+
     #[test]
     fn test_read_repo_list_probs_strict() -> Result<(), Box<dyn Error>> {
         let mut file = NamedTempFile::new()?;
@@ -7,6 +7,25 @@ mod gitconfig;
 mod github;
 mod push;
 mod remote_callbacks;
+mod discover;
+mod diffapply;
+mod replace;
+mod structured;
+mod yamledit;
+mod manifest;
+mod template;
+mod pool;
+mod ratelimit;
+mod error;
+mod migrate;
+mod status;
+mod report;
+mod history;
+mod dryrun;
+mod review;
+mod progress;
+mod reset;
+mod dashboard;
 use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
 use std::error::Error;
@@ -14,63 +33,294 @@ use std::error::Error;
 use crate::data::{load_datafile, homedir};
 use crate::clone::clone_repo;
 
-use clap::Parser;
-use data::{create_datafile, load_configfile, write_datafile, BaseStateDefn, BranchedRepo, CloneMode, DataElement};
-use git2::{Branch, Signature};
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use diffapply::DiffApplyOptions;
+use replace::ReplaceSpec;
+use structured::load_edit_file;
+use manifest::{load_manifest, CampaignManifest, RepoSource};
+use template::check_template;
+use data::{backup_datafile, load_configfile, lock_datafile, write_datafile, BaseStateDefn, BranchedRepo, CloneMode, ConfigFile, DataElement, PatchedRepo, RepoDefn};
+use error::Stage;
+use discover::{discover_repos, DiscoveryQuery};
+use git2::Signature;
 use github::create_all_pull_requests;
 use gitutils::{build_git_client, do_branch, do_commit};
 use gitconfig::{load_users_git_config, GitConfig};
-use list::read_repo_list;
+use list::{read_repo_list, write_repo_list};
 use log::{debug, info, warn, error};
-use octorust::types::{Data, GitCommit};
 use patcher::{run_patch, PatchSource};
+use pool::{init_logging, run_stage};
 use push::do_push;
+use status::{render_status, RepoStatus};
+use report::{render_report, ReportFormat};
+use history::render_history;
+use dryrun::show_dry_run;
+use review::review_repos;
+use reset::{change_repos, select_repos, RepoAction};
+use dashboard::Dashboard;
 
 #[derive(Parser, Debug)]
-#[command(version, about)]
+#[command(version, about, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    #[command(flatten)]
+    run: Option<Args>,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Find repositories to work on from the Github API, and write them to a repo list or state file
+    Discover(DiscoverArgs),
+    /// Show how far each repo in a state file has got
+    Status(StatusArgs),
+    /// Write out how each repo in a state file got on, as Markdown, CSV, HTML or JUnit XML
+    Report(ReportArgs),
+    /// Show what has happened to each repo in a state file, stage by stage, over all of the runs
+    History(HistoryArgs),
+    /// Try repos in a state file again on the next run, from the stage that they failed at. Without --repo or --stage, all of the failed repos are retried
+    Retry(RepoSelectionArgs),
+    /// Send repos in a state file back to an earlier stage, so that they go through it again on the next run
+    Reset(ResetArgs),
+    /// Stop repos in a state file going any further, until they are retried or reset
+    Skip(RepoSelectionArgs),
+    /// Take repos out of a state file altogether. Their clones are left where they are
+    Remove(RepoSelectionArgs),
+}
+
+#[derive(clap::Args, Debug)]
+struct RepoSelectionArgs {
+    #[arg(short, long, help="State file to change")]
+    data_file: String,
+
+    #[arg(short, long, help="Change this repo, as owner/name. Can be given more than once")]
+    repo: Vec<String>,
+
+    #[arg(long, value_enum, help="Change all of the repos that are at this stage, or only those of the --repo ones that are")]
+    stage: Option<RepoStatus>,
+}
+
+#[derive(clap::Args, Debug)]
+struct ResetArgs {
+    #[command(flatten)]
+    repos: RepoSelectionArgs,
+
+    #[arg(long, value_enum, help="The stage to go back to. The repos go through this stage, and the ones after it, again on the next run")]
+    to: Stage,
+}
+
+#[derive(clap::Args, Debug)]
+struct HistoryArgs {
+    #[arg(short, long, help="State file to show the history of")]
+    data_file: String,
+
+    #[arg(short, long, help="Only show the history of this repo, as owner/name")]
+    repo: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct ReportArgs {
+    #[arg(short, long, help="State file to report on")]
+    data_file: String,
+
+    #[arg(short, long, value_enum, default_value_t=ReportFormat::Markdown, help="Format to write the report in")]
+    format: ReportFormat,
+
+    #[arg(short, long, help="Write the report to this file, instead of to the terminal")]
+    output: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct StatusArgs {
+    #[arg(short, long, help="State file to summarise")]
+    data_file: String,
+
+    #[arg(long, action, conflicts_with="stage", help="Only list the repos that have failed")]
+    failed: bool,
+
+    #[arg(long, value_enum, help="Only list the repos that are at this stage")]
+    stage: Option<RepoStatus>,
+}
+
+#[derive(clap::Args, Debug)]
+struct DiscoverArgs {
+    #[arg(short, long, help="Application config file, see docs")]
+    config_file: Option<String>,
+
+    #[arg(long, help="Github organisation to list repositories from")]
+    org: Option<String>,
+
+    #[arg(long, requires="org", help="Only include repositories that this team (in --org) has admin rights on. Specify the team's slug")]
+    team: Option<String>,
+
+    #[arg(long, help="Only include repositories with this topic. Can be specified more than once")]
+    topic: Vec<String>,
+
+    #[arg(long, help="Only include repositories with this primary language")]
+    language: Option<String>,
+
+    #[arg(long, action, help="Leave out archived repositories")]
+    exclude_archived: bool,
+
+    #[arg(long, action, help="Leave out forks")]
+    exclude_forks: bool,
+
+    #[arg(long, action, help="Leave out template repositories")]
+    exclude_templates: bool,
+
+    #[arg(long, conflicts_with_all=["team", "topic", "language"], help="Only include repositories with code matching this Github code search query, e.g. 'path:.github/workflows actions/checkout@v2'. Add qualifiers such as language: to the query itself")]
+    code_search: Option<String>,
+
+    #[arg(short, long, help="Write the repositories to this file, one per line, suitable for --repo-list-file")]
+    output: Option<String>,
+
+    #[arg(short, long, help="Add the repositories to this state file, creating it if it does not exist")]
+    data_file: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
 struct Args {
+    #[arg(long, help="Campaign manifest (TOML or YAML) giving the repos, patch steps, branch name, commit message and PR details, see docs. Any of the other options given as well take priority over it")]
+    manifest: Option<String>,
+
     #[arg(short, long, help="Path to a list of repositories, one per line, in the format {org}/{repo-name}")]
     repo_list_file: Option<String>,
 
-    #[arg(short, long, help="File to use for persisting state. The first time you run, this file is created; subsequent runs use it to pick up where the last one left off")]
-    data_file: String,
+    #[arg(short, long, help="File to use for persisting state. The first time you run, this file is created; subsequent runs use it to pick up where the last one left off. Required unless the manifest gives a state_file")]
+    data_file: Option<String>,
 
     #[arg(short, long, help="Application config file, see docs")]
     config_file: Option<String>,
 
-    #[arg(short, long, help="If you want to apply a .diff file with the patch utility (*nix/Mac only) then specify the path to the .diff here.")]
-    patch_file: Option<String>,
+    #[arg(short, long, help="If you want to apply a .diff file then specify the path to the .diff here. The patch options can be given more than once, and are run in the order given")]
+    patch_file: Vec<String>,
+
+    #[arg(long, default_value_t=1, help="Number of leading path components to remove from the paths in the .diff file, like patch -p. Diffs made by git need 1 (the default)")]
+    strip: usize,
 
-    #[arg(long, help="If you want to run an arbitary script/program on the repo (any platform) then specify the path here. You must use either --patch-file or --patch-script")]
-    patch_script: Option<String>,
+    #[arg(long, default_value="workdir", value_parser=["workdir", "both"], help="Whether to apply the .diff file to the working directory only, or to the index as well")]
+    apply_to: String,
+
+    #[arg(long, action, help="If a file in the .diff does not apply cleanly, try a three-way merge using the base version named in the diff (like git apply --3way)")]
+    three_way: bool,
+
+    #[arg(long, help="If you want to run an arbitary script/program on the repo (any platform) then specify the path here. You must use at least one of --patch-file, --patch-script, --replace-regex or --edit-file")]
+    patch_script: Vec<String>,
+
+    #[arg(long, requires_all=["replace_with", "replace_glob"], help="Find-and-replace this regex in the files matching --replace-glob, instead of using a patch file or script. Can be given more than once")]
+    replace_regex: Vec<String>,
+
+    #[arg(long, help="Replacement text for --replace-regex, one for each --replace-regex in the same order. Capture groups can be referred to as $1 or ${name}")]
+    replace_with: Vec<String>,
+
+    #[arg(long, help="Only find-and-replace in files matching this glob, e.g. '.github/workflows/*.yml'. Can be given more than once. Files ignored by git are always left alone")]
+    replace_glob: Vec<String>,
+
+    #[arg(long, help="Make structured edits (set, delete, append, rename) to JSON, YAML and TOML files, as described in this JSON file. See the docs for the format")]
+    edit_file: Vec<String>,
 
     #[arg(long, help="Optional commit message to use. If this is not specified, then a default will be generated")]
     msg: Option<String>,
 
-    #[arg(long, help="New branch name to create. A repo will fail to patch if this branch already exists.")]
-    branch_name: String,
+    #[arg(long, help="New branch name to create. A repo will fail to patch if this branch already exists. Required unless the manifest gives a branch_name, or the run stops before the branch stage")]
+    branch_name: Option<String>,
+
+    #[arg(long, help="Title for the pull requests. Can use placeholders such as {{repo}}, see docs")]
+    pr_title: Option<String>,
+
+    #[arg(long, help="Description for the pull requests, in Markdown. Can use placeholders such as {{repo}} and {{files_changed}}, see docs")]
+    pr_body: Option<String>,
+
+    #[arg(long, conflicts_with="pr_body", help="Read the description for the pull requests from this Markdown file. It can use the same placeholders as --pr-body")]
+    pr_body_file: Option<String>,
 
     #[arg(long, help="Cloning mode - whether to use SSH (the default) or HTTPS")]
-    mode: String,
+    mode: Option<String>,
+
+    #[arg(long, action, conflicts_with_all=["until", "only"], help="Don't push branches or create PRs")]
+    no_push: bool,
+
+    #[arg(long, value_enum, conflicts_with="only", help="Stop after this stage (clone, patch, branch, commit, push or pr). The next run carries on from there")]
+    until: Option<Stage>,
+
+    #[arg(long, value_enum, help="Only run this stage (clone, patch, branch, commit, push or pr), for the repos that are ready for it")]
+    only: Option<Stage>,
+
+    #[arg(short, long, action, conflicts_with="dry_run", help="After patching, show the changes made to each repo and ask whether to approve or skip it (or edit the changes) before it is branched, committed and pushed")]
+    interactive: bool,
+
+    #[arg(long, action, conflicts_with_all=["until", "only"], help="Clone and patch the repos, then show the changes that the patch made to each one, without branching, committing or pushing anything")]
+    dry_run: bool,
+
+    #[arg(long, requires="dry_run", help="With --dry-run, write each repo's changes to {owner}/{name}.patch in this directory instead of to the terminal")]
+    diff_dir: Option<String>,
+
+    #[arg(long, action, conflicts_with_all=["interactive", "dry_run"], help="Show a full-screen dashboard of the repos while the run goes on, from which they can be retried or skipped, instead of the log")]
+    tui: bool,
 
-    #[arg(long, action, help="Don't push branches or create PRs")]
-    no_push: bool
+    #[arg(short, long, default_value_t=1, help="Number of repos to clone, patch, commit and push at once")]
+    jobs: usize,
+
+    #[arg(long, default_value_t=2, help="Number of pull requests to create at once. Github's secondary rate limits don't like lots of these at the same time; if you are rate limited then batchpatch waits and tries again")]
+    pr_jobs: usize,
+}
+
+/**
+ * A patch step as it was given on the command line.  The patch options can be given more than once, and the steps
+ * are run in the order that they appear in.
+ */
+enum StepArg<'a> {
+    DiffFile(&'a String),
+    ScriptFile(&'a String),
+    //the regex and its replacement
+    Replace(&'a String, &'a String),
+    Edits(&'a String),
+}
+
+impl StepArg<'_> {
+    //Describes the step for the default commit message
+    fn describe(&self) -> String {
+        match self {
+            StepArg::DiffFile(patch_file)=>format!("applied the patch file {}", patch_file),
+            StepArg::ScriptFile(patch_script)=>format!("applied the script {}", patch_script),
+            StepArg::Replace(pattern, replacement)=>format!("replaced /{}/ with '{}'", pattern, replacement),
+            StepArg::Edits(edit_file)=>format!("applied the edits in {}", edit_file),
+        }
+    }
 }
 
-fn get_patch_file(args:&Args) -> Result<PatchSource, Box<dyn Error>> {
-    match (args.patch_file.as_ref(), args.patch_script.as_ref()) {
-        (Some(patch_file), None)=>{
-            let f = Path::new(&patch_file);
+fn get_step_args<'a>(args:&'a Args, matches:&ArgMatches) -> Vec<StepArg<'a>> {
+    let positions = |id:&str| -> Vec<usize> { matches.indices_of(id).map(|i| i.collect()).unwrap_or_default() };
+
+    let mut ordered:Vec<(usize, StepArg)> = vec![];
+    ordered.extend(positions("patch_file").into_iter().zip(args.patch_file.iter().map(StepArg::DiffFile)));
+    ordered.extend(positions("patch_script").into_iter().zip(args.patch_script.iter().map(StepArg::ScriptFile)));
+    ordered.extend(positions("replace_regex").into_iter().zip(args.replace_regex.iter().zip(args.replace_with.iter()).map(|(pattern, replacement)| StepArg::Replace(pattern, replacement))));
+    ordered.extend(positions("edit_file").into_iter().zip(args.edit_file.iter().map(StepArg::Edits)));
+    ordered.sort_by_key(|(position, _)| *position);
+    ordered.into_iter().map(|(_, step)| step).collect()
+}
+
+fn get_patch_step(args:&Args, step:&StepArg) -> Result<PatchSource, Box<dyn Error>> {
+    match step {
+        StepArg::DiffFile(patch_file)=>{
+            let f = Path::new(patch_file);
             if f.exists() {
                 let fullpath = f.canonicalize()?;
-                Ok( PatchSource::DiffFile(fullpath) )
+                let opts = DiffApplyOptions {
+                    strip: args.strip,
+                    target: (&args.apply_to).into(),
+                    three_way: args.three_way,
+                };
+                Ok( PatchSource::DiffFile(fullpath, opts) )
             } else {
                 error!("💩 Patch file does not exist at {}", patch_file);
                 Err(Box::from("Patch file did not exist"))
             }
         },
-        (None, Some(patch_script))=>{
-            let f = Path::new(&patch_script);
+        StepArg::ScriptFile(patch_script)=>{
+            let f = Path::new(patch_script);
             if f.exists() {
                 let fullpath = f.canonicalize()?;
                 Ok( PatchSource::ScriptFile(fullpath) )
@@ -79,15 +329,75 @@ fn get_patch_file(args:&Args) -> Result<PatchSource, Box<dyn Error>> {
                 Err(Box::from("Patch script did not exist"))
             }
         },
-        _ => {
-            error!("💩 You need to specify either --patch-file or --patch-script, not both or neither");
-            Err(Box::from("Incorrect arguments"))
+        StepArg::Replace(pattern, replacement)=>{
+            Ok( PatchSource::Replace(ReplaceSpec {
+                globs: args.replace_glob.to_owned(),
+                pattern: pattern.to_string(),
+                replacement: replacement.to_string(),
+            }) )
+        },
+        StepArg::Edits(edit_file)=>{
+            let f = Path::new(edit_file);
+            if f.exists() {
+                let fullpath = f.canonicalize()?;
+                let spec = load_edit_file(&fullpath)?;
+                Ok( PatchSource::Edits(fullpath, spec) )
+            } else {
+                error!("💩 Edit file does not exist at {}", edit_file);
+                Err(Box::from("Edit file did not exist"))
+            }
+        },
+    }
+}
+
+/**
+ * Patch steps given on the command line replace all of the ones in the manifest, rather than being added to them
+ */
+fn get_patch_steps(args:&Args, matches:&ArgMatches, campaign:&CampaignManifest) -> Result<Vec<PatchSource>, Box<dyn Error>> {
+    if args.replace_regex.len() != args.replace_with.len() {
+        error!("💩 You need to give one --replace-with for each --replace-regex");
+        return Err(Box::from("Incorrect arguments"));
+    }
+    let step_args = get_step_args(args, matches);
+    if !step_args.is_empty() {
+        step_args.iter().map(|step| get_patch_step(args, step)).collect()
+    } else if !campaign.steps.is_empty() {
+        campaign.steps.iter()
+            .map(|step| step.to_patch_source().inspect_err(|e| error!("💩 {}", e)))
+            .collect()
+    } else {
+        error!("💩 You need to specify at least one of --patch-file, --patch-script, --replace-regex or --edit-file, or give steps in the manifest");
+        Err(Box::from("Incorrect arguments"))
+    }
+}
+
+//Builds the repo list for a new state file from whichever sources were given
+fn new_state(repo_source:&RepoSource, cfg:&ConfigFile) -> Result<BaseStateDefn, Box<dyn Error>> {
+    let mut state = match repo_source.list_file.as_ref() {
+        Some(repo_list_str)=>*read_repo_list(Path::new(repo_list_str), false)?, //FIXME - allow fault-tolerance from args
+        None=>BaseStateDefn::new(vec![]),
+    };
+
+    let named:Vec<RepoDefn> = repo_source.names.iter().map(|n| RepoDefn::new(n)).collect::<Result<_, _>>()?;
+    state.add_remote_repos(named);
+
+    if let Some(query) = repo_source.discover.as_ref() {
+        match cfg.github_access_token.as_ref() {
+            Some(gh_token)=>{
+                let discovered = discover_repos(query, gh_token)?;
+                state.add_remote_repos(discovered);
+            },
+            None=>{
+                error!("😲 There is no github access token configured so we can't discover repos");
+                return Err(Box::from("No github access token"));
+            }
         }
     }
+    Ok( state )
 }
 
-fn initialise_state(args:&Args) -> Result<(BaseStateDefn, &Path), Box<dyn Error>> {
-    let p = Path::new(&args.data_file);
+fn initialise_state<'a>(data_file:&'a str, repo_source:&RepoSource, cfg:&ConfigFile) -> Result<(BaseStateDefn, &'a Path), Box<dyn Error>> {
+    let p = Path::new(data_file);
     match load_datafile(p) {
         Ok(data)=>{
             info!("👌 Loaded existing state from {}", p.display());
@@ -97,16 +407,9 @@ fn initialise_state(args:&Args) -> Result<(BaseStateDefn, &Path), Box<dyn Error>
             match e.downcast_ref::<std::io::Error>() {
                 Some(io_err) if io_err.kind()==ErrorKind::NotFound => {
                     info!("🤚 Initialising new state in {}", p.display());
-                    match args.repo_list_file.as_ref() {
-                        Some(repo_list_str)=>{
-                            let repo_list_file = Path::new(repo_list_str);
-                            let new_state = read_repo_list(repo_list_file, false)?; //FIXME - allow fault-tolerance from args
-                            write_datafile(p, &new_state)?;
-                            Ok((*new_state, p))
-                        },
-                        None=>
-                            create_datafile(p).map(|datafile| (datafile, p))
-                    }
+                    let state = new_state(repo_source, cfg)?;
+                    write_datafile(p, &state)?;
+                    Ok((state, p))
                 },
                 Some(_)=>Err(e),
                 None=>Err(e),
@@ -126,22 +429,27 @@ fn dump_user_info(cfg:&GitConfig) {
     }
 }
 
-fn get_commit_msg(args:&Args) -> String {
-    match args.msg.as_ref() {
+fn get_commit_msg(args:&Args, matches:&ArgMatches, campaign:&CampaignManifest) -> String {
+    match args.msg.as_ref().or(campaign.commit_message.as_ref()) {
         Some(custom_msg) => custom_msg.to_owned(),
-        None => match (args.patch_file.as_ref(), args.patch_script.as_ref()) {
-            (Some(patch_file), _)=>format!("Batchpatch applied the patch file {}", patch_file),
-            (_, Some(patch_script))=>format!("Batchpatch applied the script {}", patch_script),
-            _ => format!("Batchpatch applied an operation")
-        } 
+        None => {
+            let step_args = get_step_args(args, matches);
+            let steps:Vec<String> = if step_args.is_empty() {
+                campaign.steps.iter().map(|step| step.describe()).collect()
+            } else {
+                step_args.iter().map(|step| step.describe()).collect()
+            };
+            match steps.len() {
+                0 => "Batchpatch applied an operation".to_string(),
+                1 => format!("Batchpatch {}", steps[0]),
+                n => format!("Batchpatch applied {} patch steps:\n\n{}", n, steps.iter().map(|s| format!("- {}", s)).collect::<Vec<String>>().join("\n")),
+            }
+        }
     }
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    colog::init();
-    let args = Args::parse();
-
-    let cfg_path = (&args.config_file).as_ref()
+fn load_app_config(config_file:Option<&String>) -> Result<ConfigFile, Box<dyn Error>> {
+    let cfg_path = config_file
         .map(|f| Path::new(&f).to_path_buf())
         .unwrap_or_else(|| {
             let mut p = PathBuf::new();
@@ -152,71 +460,427 @@ fn main() -> Result<(), Box<dyn Error>> {
             p
         });
 
-    let clone_mode:CloneMode = (&args.mode).into();
+    info!("Reading config from {}", cfg_path.as_path().display());
+    load_configfile(&cfg_path)
+}
+
+fn discover(args:&DiscoverArgs) -> Result<(), Box<dyn Error>> {
+    if args.output.is_none() && args.data_file.is_none() {
+        error!("💩 You need to specify --output and/or --data-file, otherwise the results would go nowhere");
+        return Err(Box::from("Incorrect arguments"));
+    }
+
+    let cfg = load_app_config(args.config_file.as_ref())?;
+    let gh_token = match cfg.github_access_token.as_ref() {
+        Some(tok)=>tok,
+        None=>{
+            error!("😲 There is no github access token configured so we can't discover repos");
+            return Err(Box::from("No github access token"));
+        }
+    };
+
+    let query = DiscoveryQuery {
+        org: args.org.to_owned(),
+        team: args.team.to_owned(),
+        topics: args.topic.to_owned(),
+        language: args.language.to_owned(),
+        exclude_archived: args.exclude_archived,
+        exclude_forks: args.exclude_forks,
+        exclude_templates: args.exclude_templates,
+        code_search: args.code_search.to_owned(),
+    };
+
+    let repos = discover_repos(&query, gh_token)?;
+
+    if let Some(output) = args.output.as_ref() {
+        write_repo_list(Path::new(output), &repos)?;
+        info!("📝 Wrote {} repos to {}", repos.len(), output);
+    }
+
+    if let Some(data_file) = args.data_file.as_ref() {
+        let p = Path::new(data_file);
+        let _lock = lock_datafile(p)?;
+        let mut state = match load_datafile(p) {
+            Ok(state)=>state,
+            Err(e)=>match e.downcast_ref::<std::io::Error>() {
+                Some(io_err) if io_err.kind()==ErrorKind::NotFound => BaseStateDefn::new(vec![]),
+                _ => return Err(e),
+            }
+        };
+        let added = state.add_remote_repos(repos);
+        write_datafile(p, &state)?;
+        info!("📝 Added {} new repos to {}", added, p.display());
+    }
+    Ok( () )
+}
+
+fn status(args:&StatusArgs) -> Result<(), Box<dyn Error>> {
+    let state = load_datafile(Path::new(&args.data_file))?;
+    let only = if args.failed { Some(RepoStatus::Failed) } else { args.stage };
+    print!("{}", render_status(&state, only)?);
+    Ok( () )
+}
+
+fn report(args:&ReportArgs) -> Result<(), Box<dyn Error>> {
+    let state = load_datafile(Path::new(&args.data_file))?;
+    let report = render_report(&state, args.format)?;
+    match args.output.as_ref() {
+        Some(output)=>{
+            std::fs::write(output, report)?;
+            info!("📝 Wrote the report to {}", output);
+        },
+        None=>print!("{}", report),
+    }
+    Ok( () )
+}
+
+fn history(args:&HistoryArgs) -> Result<(), Box<dyn Error>> {
+    let state = load_datafile(Path::new(&args.data_file))?;
+    let only = match args.repo.as_ref() {
+        Some(repo)=>{
+            let defn = RepoDefn::new(repo)?;
+            if !state.data.repos.iter().any(|elmt| elmt.defn().to_string()==defn.to_string()) {
+                error!("💩 {} is not in {}", defn, args.data_file);
+                return Err(Box::from("Unknown repo"));
+            }
+            Some(defn)
+        },
+        None=>None,
+    };
+    print!("{}", render_history(&state, only.as_ref())?);
+    Ok( () )
+}
 
-    //We need a git config file
-    let git_config = load_users_git_config()?;
-    if git_config.user.is_none() {
-        error!("You must have your user information configured in git before running this. Try git config --global user.name \"FIRST_NAME LAST_NAME\" and/or git config --global user.email \"MY_NAME@example.com\" ");
-        return Err ( Box::from("git was not properly configured"))
+/**
+ * Retries, resets, skips or removes the repos that were asked for.  If no repos were named and no stage was given then
+ * `default_stage` picks the repos, if there is one; we don't do anything to every repo unless asked to.
+ */
+fn change_state(args:&RepoSelectionArgs, action:RepoAction, default_stage:Option<RepoStatus>) -> Result<(), Box<dyn Error>> {
+    let stage = match (args.repo.is_empty(), args.stage, default_stage) {
+        (true, None, None)=>{
+            error!("💩 You need to say which repos with --repo and/or --stage");
+            return Err(Box::from("Incorrect arguments"));
+        },
+        (true, None, default_stage)=>default_stage,
+        (_, stage, _)=>stage,
+    };
+
+    let p = Path::new(&args.data_file);
+    let _lock = lock_datafile(p)?;
+    let mut state = load_datafile(p)?;
+    let selected = select_repos(&state, &args.repo, stage).inspect_err(|e| error!("💩 {}", e))?;
+    if selected.is_empty() {
+        info!("🤷 There are no repos like that in {}", p.display());
+        return Ok( () );
     }
-    dump_user_info(&git_config);
+
+    backup_datafile(p)?;
+    let changed = change_repos(&mut state, &selected, action);
+    write_datafile(p, &state)?;
+    info!("📝 {} of {} repos {}", changed, selected.len(), action);
+    Ok( () )
+}
+
+fn main() -> Result<(), Box<dyn Error>> {
+    init_logging();
+    //we need the matches as well as the parsed arguments, to see what order the patch steps were given in
+    let matches = Cli::command().get_matches();
+    let cli = Cli::from_arg_matches(&matches)?;
+
+    match (cli.command, cli.run) {
+        (Some(Command::Discover(discover_args)), _)=>discover(&discover_args),
+        (Some(Command::Status(status_args)), _)=>status(&status_args),
+        (Some(Command::Report(report_args)), _)=>report(&report_args),
+        (Some(Command::History(history_args)), _)=>history(&history_args),
+        (Some(Command::Retry(retry_args)), _)=>change_state(&retry_args, RepoAction::Retry, Some(RepoStatus::Failed)),
+        (Some(Command::Reset(reset_args)), _)=>change_state(&reset_args.repos, RepoAction::Reset(reset_args.to), None),
+        (Some(Command::Skip(skip_args)), _)=>change_state(&skip_args, RepoAction::Skip, None),
+        (Some(Command::Remove(remove_args)), _)=>change_state(&remove_args, RepoAction::Remove, None),
+        (None, Some(args))=>run(args, &matches),
+        (None, None)=>{
+            error!("💩 Nothing to do, try --help");
+            Err(Box::from("Incorrect arguments"))
+        }
+    }
+}
+
+/**
+ * Puts the manifest into the state, so that the state file records what the campaign was
+ */
+fn embed_manifest(state:&mut BaseStateDefn, manifest:&CampaignManifest) -> Result<(), Box<dyn Error>> {
+    let embedded = manifest.to_state()?;
+    if state.manifest.as_ref().is_some_and(|previous| *previous != embedded) {
+        warn!("⚠️ The manifest has changed since this state file was last run; repos that are already done will not be redone");
+    }
+    state.manifest = Some(embedded);
+    Ok( () )
+}
+
+fn read_pr_body_file(body_file:&str) -> Result<String, Box<dyn Error>> {
+    std::fs::read_to_string(body_file).map_err(|e| {
+        error!("💩 Unable to read the PR description from {}: {}", body_file, e);
+        Box::from(format!("PR description file {} could not be read", body_file))
+    })
+}
+
+/**
+ * Stores the PR title, description and labels in the state, from the command line or the manifest.  Anything that is
+ * not given keeps the value from the last run.  The templates are checked here, but filled in for each repo when its PR
+ * is created.
+ */
+fn set_pr_details(state:&mut BaseStateDefn, args:&Args, campaign:&CampaignManifest) -> Result<(), Box<dyn Error>> {
+    if let Some(title) = args.pr_title.as_ref().or(campaign.pr.title.as_ref()) {
+        state.pr_title = Some(title.to_owned());
+    }
+
+    let body = match (args.pr_body.as_ref(), args.pr_body_file.as_ref()) {
+        (Some(body), _)=>Some(body.to_owned()),
+        (None, Some(body_file))=>Some(read_pr_body_file(body_file)?),
+        (None, None)=>match (campaign.pr.body.as_ref(), campaign.pr.body_file.as_ref()) {
+            (Some(body), _)=>Some(body.to_owned()),
+            (None, Some(body_file))=>Some(read_pr_body_file(body_file)?),
+            (None, None)=>None,
+        },
+    };
+    if body.is_some() {
+        state.pr_description = body;
+    }
+
+    if !campaign.pr.labels.is_empty() {
+        state.pr_labels = campaign.pr.labels.to_owned();
+    }
+
+    for (what, template) in [("title", state.pr_title.as_ref()), ("description", state.pr_description.as_ref())] {
+        if let Some(Err(e)) = template.map(|t| check_template(t)) {
+            error!("💩 The PR {} has {}", what, e);
+            return Err(Box::from("Incorrect PR template"));
+        }
+    }
+    Ok( () )
+}
+
+fn run(args:Args, matches:&ArgMatches) -> Result<(), Box<dyn Error>> {
+    let manifest = match args.manifest.as_ref() {
+        Some(manifest_file)=>Some(load_manifest(Path::new(manifest_file))?),
+        None=>None,
+    };
+    let campaign = manifest.clone().unwrap_or_default();
+
+    let data_file = match args.data_file.as_ref().or(campaign.state_file.as_ref()) {
+        Some(f)=>f.to_owned(),
+        None=>{
+            error!("💩 You need to specify --data-file, or give a state_file in the manifest");
+            return Err(Box::from("Incorrect arguments"));
+        }
+    };
+    //only ask for what the stages that are going to run need; a dry run stops before anything is branched or committed
+    let (first, last) = stage_range(&args);
+    let runs = |stage:Stage| (first..=last).contains(&stage);
+    let branches = runs(Stage::Branch) && !args.dry_run;
+    let commits = runs(Stage::Commit) && !args.dry_run;
+
+    let branch_name = match args.branch_name.as_ref().or(campaign.branch_name.as_ref()) {
+        Some(b)=>b.to_owned(),
+        None if !branches=>String::new(),
+        None=>{
+            error!("💩 You need to specify --branch-name, or give a branch_name in the manifest");
+            return Err(Box::from("Incorrect arguments"));
+        }
+    };
+    let clone_mode:CloneMode = args.mode.as_ref().or(campaign.clone_mode.as_ref())
+        .map(|m| m.into())
+        .unwrap_or(CloneMode::Ssh);
+    //A repo list on the command line replaces all of the manifest's repo sources
+    let repo_source = match args.repo_list_file.as_ref() {
+        Some(repo_list_file)=>RepoSource { list_file: Some(repo_list_file.to_owned()), ..Default::default() },
+        None=>campaign.repos.clone(),
+    };
+
+    //We need a git config file, unless nothing is going to be committed
+    let git_config = if !commits {
+        GitConfig { user: None }
+    } else {
+        let git_config = load_users_git_config()?;
+        if git_config.user.is_none() {
+            error!("You must have your user information configured in git before running this. Try git config --global user.name \"FIRST_NAME LAST_NAME\" and/or git config --global user.email \"MY_NAME@example.com\" ");
+            return Err ( Box::from("git was not properly configured"))
+        }
+        dump_user_info(&git_config);
+        git_config
+    };
    
-    info!("Reading config from {}", cfg_path.as_path().display());
-    let cfg = load_configfile(&cfg_path)?;
+    let cfg = load_app_config(args.config_file.as_ref())?;
 
-    let patch_file = get_patch_file(&args)?;
+    let patch_steps = match runs(Stage::Patch) {
+        true=>get_patch_steps(&args, matches, &campaign)?,
+        false=>vec![],
+    };
 
-    let (mut state, state_file_path) = initialise_state(&args)?;
+    //held until the run finishes, so that nothing else can change the state under us
+    let _lock = lock_datafile(Path::new(&data_file))?;
+    backup_datafile(Path::new(&data_file))?;
+    let (mut state, state_file_path) = initialise_state(&data_file, &repo_source, &cfg)?;
 
-    debug!("{:?}", state);
+    if let Some(manifest) = manifest.as_ref() {
+        embed_manifest(&mut state, manifest)?;
+    }
+    set_pr_details(&mut state, &args, &campaign)?;
+    write_datafile(state_file_path, &state)?;
 
-    let mut repobuilder = build_git_client(&cfg);
+    debug!("{:?}", state);
 
-    if state.data.repos.len()==0 {
-        error!("😮 There are no repos to work on. Try adding --repo-list-file.");
+    if state.data.repos.is_empty() {
+        error!("😮 There are no repos to work on. Try adding --repo-list-file, or repos to the manifest.");
         return Err(Box::from("Nothing to do."));
     }
 
-    let start_length = state.data.repos.len();
-    info!("⬇️ Downloading {} repos...", start_length);
-
-    state.data.repos = state.data.repos
-        .into_iter()
-        .map(|some_repo| match some_repo {
-            //FIXME - should be DRYer
-            DataElement::RemoteRepo(repo)=>{
-                match clone_repo(&mut repobuilder, repo, "main", None, &clone_mode, &cfg) {
-                    Ok(local_repo)=>{
-                        if local_repo.is_failed() {
-                            warn!("❌ {} - {}", local_repo.defn, local_repo.last_error.as_ref().unwrap());
-                        } else {
-                            info!("✅ {}", local_repo.local_path.display() );
-                        }
-                        DataElement::LocalRepo(*local_repo)
-                    },
-                    Err(e)=>panic!("{}", e),
+    let pipeline = Pipeline {
+        jobs: args.jobs,
+        pr_jobs: args.pr_jobs,
+        first,
+        last,
+        interactive: args.interactive,
+        dry_run: args.dry_run,
+        diff_dir: args.diff_dir.as_ref().map(PathBuf::from),
+        commit_log: get_commit_msg(&args, matches, &campaign),
+        cfg,
+        git_config,
+        patch_steps,
+        branch_name,
+        clone_mode,
+        dashboard: args.tui.then(|| Dashboard::start(state_file_path)),
+    };
+    let result = loop {
+        let result = run_pipeline(&pipeline, &mut state, state_file_path);
+        //However the run ended, make sure that the state file has everything that was done
+        write_datafile(state_file_path, &state)?;
+        match pipeline.dashboard.as_ref() {
+            Some(dashboard)=>{
+                dashboard.finished(&result);
+                if !dashboard.wait_for_retry(&mut state, state_file_path)? {
+                    break result;
                 }
             },
-            DataElement::LocalRepo(local_repo) if local_repo.is_failed() =>{
-                match clone_repo(&mut repobuilder, local_repo.defn, "main", None, &clone_mode, &cfg) {
-                    Ok(local_repo)=>{
-                        if local_repo.is_failed() {
-                            warn!("❌ {} - {}", local_repo.defn, local_repo.last_error.as_ref().unwrap());
-                        } else {
-                            info!("✅ {}", local_repo.local_path.display() );
-                        }
-                        DataElement::LocalRepo(*local_repo)
-                    },
-                    Err(e)=>panic!("{}", e),
-                }
-            }
-            other @ _=>other,
-        })
-        .collect();
+            None=>break result,
+        }
+    };
+    if let Some(dashboard) = pipeline.dashboard {
+        dashboard.close()?;
+    }
+    result
+}
 
-    //Update our state on-disk so we can resume
-    write_datafile(state_file_path, &state)?;
+//Creates the branch in a patched repo, recording the commit that it was made from or why it could not be made
+fn branch_repo(patched:PatchedRepo, branch_name:&str) -> BranchedRepo {
+    let (base_commit, last_error) = match do_branch(&patched.repo, branch_name) {
+        Ok(base_commit)=>{
+            info!("Successfully branched repo");
+            (Some(base_commit), None)
+        },
+        Err(e)=>{
+            error!("Unable to branch repo: {}", e);
+            (None, Some(e))
+        }
+    };
+    BranchedRepo {
+        patched,
+        branch_name: branch_name.to_owned(),
+        base_commit,
+        commit: None,
+        committed: false,
+        pushed: false,
+        pushed_ref: None,
+        last_error,
+        pr_attempts: 0,
+        rate_limited_until: None,
+    }
+}
+
+/**
+ * Everything that the stages of the pipeline need to know, apart from the state
+ */
+struct Pipeline {
+    jobs: usize,
+    pr_jobs: usize,
+    //The stages to run, from the first to the last, in pipeline order
+    first: Stage,
+    last: Stage,
+    interactive: bool,
+    dry_run: bool,
+    diff_dir: Option<PathBuf>,
+    cfg: ConfigFile,
+    git_config: GitConfig,
+    patch_steps: Vec<PatchSource>,
+    branch_name: String,
+    clone_mode: CloneMode,
+    commit_log: String,
+    dashboard: Option<Dashboard>,
+}
+
+//The first and last stages to run, from --only, --until or --no-push (which is the same as stopping after the commit)
+fn stage_range(args:&Args) -> (Stage, Stage) {
+    match (args.only, args.until, args.no_push) {
+        (Some(only), _, _)=>(only, only),
+        (None, Some(until), _)=>(Stage::Clone, until),
+        (None, None, true)=>(Stage::Clone, Stage::Commit),
+        (None, None, false)=>(Stage::Clone, Stage::PullRequest),
+    }
+}
+
+//Whether the run stops after this stage, because it was asked to
+fn stops_after(stage:Stage, last:Stage) -> bool {
+    if stage==last {
+        info!("✨ Stopping after the {} stage, as asked. The next run carries on from here", stage);
+    }
+    stage==last
+}
+
+//Between stages, picks up any repos that were retried or skipped from the dashboard, if there is one
+fn checkpoint(dashboard:Option<&Dashboard>, state:&mut BaseStateDefn, state_file_path:&Path) -> Result<(), Box<dyn Error>> {
+    match dashboard {
+        Some(dashboard)=>dashboard.checkpoint(state, state_file_path),
+        None=>Ok( () ),
+    }
+}
+
+/**
+ * Takes each repo in the state as far through clone, patch, branch, commit, push and PR as it will go, running only the
+ * stages from `first` to `last`.  An error is only returned if a whole stage failed, or if there was nothing left for
+ * the next one to do.  The checks between the stages look at where the repos have got to over all of the runs, so a
+ * run that starts part way through checks that there is something for it to do.
+ */
+fn run_pipeline(pipeline:&Pipeline, state:&mut BaseStateDefn, state_file_path:&Path) -> Result<(), Box<dyn Error>> {
+    let Pipeline { jobs, pr_jobs, first, last, interactive, dry_run, diff_dir, cfg, git_config, patch_steps, branch_name, clone_mode, commit_log, dashboard } = pipeline;
+    let dashboard = dashboard.as_ref();
+    let runs = |stage:Stage| (*first..=*last).contains(&stage);
+
+    let start_length = state.data.repos.len();
+    if runs(Stage::Clone) {
+        info!("⬇️ Downloading {} repos...", start_length);
+
+        checkpoint(dashboard, state, state_file_path)?;
+        run_stage(state, state_file_path, *jobs, Stage::Clone,
+            |elmt| match elmt {
+                DataElement::RemoteRepo(_)=>true,
+                DataElement::LocalRepo(local_repo)=>local_repo.can_retry(),
+                _=>false,
+            },
+            |elmt| {
+                let defn = match elmt {
+                    DataElement::RemoteRepo(repo)=>repo,
+                    DataElement::LocalRepo(local_repo)=>local_repo.defn,
+                    other=>return other,
+                };
+                //each clone needs its own builder, as they can't be shared between threads
+                let mut repobuilder = build_git_client(cfg);
+                let local_repo = clone_repo(&mut repobuilder, defn, None, clone_mode, cfg);
+                match local_repo.last_error.as_ref() {
+                    Some(e)=>warn!("❌ {} - {}", local_repo.defn, e),
+                    None=>info!("✅ {}", local_repo.local_path.display()),
+                }
+                DataElement::LocalRepo(*local_repo)
+            })?;
+    }
 
     let local_repos_count = state.data.repos.iter().filter(|r| match r {
         DataElement::LocalRepo(repo)=>!repo.is_failed(), //false if failed to clone
@@ -228,20 +892,25 @@ fn main() -> Result<(), Box<dyn Error>> {
         return Err(Box::from("No repos managed to download"))
     }
 
-    info!("👍 Downloaded {} repos; {} failed", local_repos_count, start_length - local_repos_count);
+    info!("👍 Downloaded {} repos; {} failed", local_repos_count, start_length.saturating_sub(local_repos_count));
+    if stops_after(Stage::Clone, *last) {
+        return Ok( () );
+    }
 
-    state.data.repos = state.data.repos
-        .into_iter()
-        .map(|elmt| match elmt {
-            DataElement::LocalRepo(repo) if !repo.is_failed() =>match run_patch(&patch_file, repo) {
-                Ok(repo)=>DataElement::PatchedRepo(*repo),
-                Err(e)=>panic!("{}", e)
+    if runs(Stage::Patch) {
+        checkpoint(dashboard, state, state_file_path)?;
+        run_stage(state, state_file_path, *jobs, Stage::Patch,
+            |elmt| match elmt {
+                DataElement::LocalRepo(repo)=>!repo.is_failed(),
+                DataElement::PatchedRepo(repo)=>repo.can_retry(),
+                _=>false,
             },
-            other @ _=>other,
-        })
-        //.filter(|repo| repo.success && repo.changes>0)
-        .collect();
-
+            |elmt| match elmt {
+                DataElement::LocalRepo(repo) if !repo.is_failed() =>DataElement::PatchedRepo(*run_patch(patch_steps, repo)),
+                DataElement::PatchedRepo(repo) if repo.can_retry() =>DataElement::PatchedRepo(*run_patch(patch_steps, repo.repo)),
+                other =>other,
+            })?;
+    }
 
     let patched_repos_count = state.data.repos.iter().filter(|elmt| match elmt {
         DataElement::PatchedRepo(repo)=>repo.success && repo.changes>0,
@@ -250,67 +919,49 @@ fn main() -> Result<(), Box<dyn Error>> {
         _ => false,
     }).count();
 
-    //Update our state on-disk so we can resume
-    write_datafile(state_file_path, &state)?;
-
     if patched_repos_count==0 {
         warn!("👎 No repos managed to patch");
         return Err(Box::from("No repos managed to patch"))
     }
 
-    info!("👍 Patched {} repos; {} failed", patched_repos_count, local_repos_count - patched_repos_count);
-
-    state.data.repos = state.data.repos
-        .into_iter()
-        .map(|elmt| match elmt {
-            DataElement::PatchedRepo(repo) if repo.success && repo.changes>0=>match do_branch(&repo.repo, &args.branch_name) {
-                Ok(_)=>{
-                    info!("Successfully branched repo");
-                    DataElement::BranchedRepo(BranchedRepo{
-                        patched: repo,
-                        branch_name: args.branch_name.to_owned(),
-                        committed: false,
-                        pushed: false,
-                        last_error: None,
-                    })
-                },
-                Err(e)=>{
-                    error!("Unable to branch repo: {}", e);
-                    DataElement::BranchedRepo(BranchedRepo{
-                        patched: repo,
-                        branch_name: args.branch_name.to_owned(),
-                        committed: false,
-                        pushed: false,
-                        last_error: Some(e.to_string())
-                    })
-                }
-            },
-            DataElement::BranchedRepo(repo) if repo.last_error.is_some() && repo.committed==false =>
-            match do_branch(&repo.patched.repo, &args.branch_name) {
-                Ok(_)=>{
-                    info!("Successfully branched repo");
-                    DataElement::BranchedRepo(BranchedRepo{
-                        patched: repo.patched,
-                        branch_name: args.branch_name.to_owned(),
-                        committed: false,
-                        pushed: false,
-                        last_error: None,
-                    })
-                },
-                Err(e)=>{
-                    error!("Unable to branch repo: {}", e);
-                    DataElement::BranchedRepo(BranchedRepo{
-                        patched: repo.patched,
-                        branch_name: args.branch_name.to_owned(),
-                        committed: false,
-                        pushed: false,
-                        last_error: Some(e.to_string())
-                    })
-                }
+    info!("👍 Patched {} repos; {} failed", patched_repos_count, local_repos_count.saturating_sub(patched_repos_count));
+    if stops_after(Stage::Patch, *last) {
+        return Ok( () );
+    }
+
+    if *dry_run {
+        return show_dry_run(state, diff_dir.as_deref());
+    }
+
+    if *interactive && runs(Stage::Branch) {
+        review_repos(state, state_file_path)?;
+        let approved = state.data.repos.iter().filter(|elmt| match elmt {
+            DataElement::PatchedRepo(repo)=>repo.is_ready_to_branch(),
+            DataElement::BranchedRepo(_)=>true,
+            DataElement::PRdRepo(_)=>true,
+            _=>false,
+        }).count();
+        if approved==0 {
+            warn!("👎 No repos were approved");
+            return Err(Box::from("No repos were approved"))
+        }
+    }
+
+    if runs(Stage::Branch) {
+        checkpoint(dashboard, state, state_file_path)?;
+        run_stage(state, state_file_path, *jobs, Stage::Branch,
+            |elmt| match elmt {
+                DataElement::PatchedRepo(repo)=>repo.is_ready_to_branch(),
+                DataElement::BranchedRepo(repo)=>repo.last_error.is_some() && repo.can_continue() && !repo.committed,
+                _=>false,
             },
-            other @_ => other
-        })
-        .collect();
+            |elmt| match elmt {
+                DataElement::PatchedRepo(repo) if repo.is_ready_to_branch()=>DataElement::BranchedRepo(branch_repo(repo, branch_name)),
+                DataElement::BranchedRepo(repo) if repo.last_error.is_some() && repo.can_continue() && !repo.committed =>
+                    DataElement::BranchedRepo(branch_repo(repo.patched, branch_name)),
+                other => other
+            })?;
+    }
 
     let branched_repos_count = state.data.repos.iter().filter(|elmt| match elmt {
         DataElement::BranchedRepo(repo)=>repo.last_error.is_none() && !repo.committed,
@@ -318,40 +969,39 @@ fn main() -> Result<(), Box<dyn Error>> {
         _ => false,
     }).count();
 
-    //Update our state on-disk so we can resume
-    write_datafile(state_file_path, &state)?;
+    info!("👍 Branched {} repos; {} failed", branched_repos_count, patched_repos_count.saturating_sub(branched_repos_count));
+    if stops_after(Stage::Branch, *last) {
+        return Ok( () );
+    }
 
-    info!("👍 Branched {} repos; {} failed", branched_repos_count, patched_repos_count - branched_repos_count);
-
-    state.data.repos = state.data.repos
-        .into_iter()
-        .map(|elmt| match elmt {
-            DataElement::BranchedRepo(repo) if !repo.committed && repo.last_error.is_none()=>{
-                //`unwrap` here is safe, because we already errored at the start if this was not set.
-                let sig:Signature = git_config.user.as_ref().unwrap().into();
-                let commit_log = get_commit_msg(&args);
-
-                match do_commit(&repo.patched.repo, &sig, &repo.branch_name, &commit_log){
-                    Ok(_)=>{
-                        let mut updated = repo.clone();
-                        updated.committed = true;
-                        DataElement::BranchedRepo(updated)
-                    },
-                    Err(e)=>{
-                        error!("👎 Unable to commit {}: {}", repo.patched.repo.defn, e.to_string());
-                        let mut updated = repo.clone();
-                        updated.committed = false;
-                        updated.last_error = Some( e.to_string() );
-                        DataElement::BranchedRepo(updated)
-                    }
-                }
-            },
-            other @_=> other
-        })
-        .collect();
+    if runs(Stage::Commit) {
+        checkpoint(dashboard, state, state_file_path)?;
+        run_stage(state, state_file_path, *jobs, Stage::Commit,
+            |elmt| matches!(elmt, DataElement::BranchedRepo(repo) if !repo.committed && repo.last_error.is_none()),
+            |elmt| match elmt {
+                DataElement::BranchedRepo(repo) if !repo.committed && repo.last_error.is_none()=>{
+                    //`unwrap` here is safe, because we already errored at the start if this was not set.
+                    let sig:Signature = git_config.user.as_ref().unwrap().into();
 
-    //Update our state on-disk so we can resume
-    write_datafile(state_file_path, &state)?;
+                    match do_commit(&repo.patched.repo, &sig, &repo.branch_name, commit_log){
+                        Ok(commit)=>{
+                            let mut updated = repo.clone();
+                            updated.commit = Some(commit);
+                            updated.committed = true;
+                            DataElement::BranchedRepo(updated)
+                        },
+                        Err(e)=>{
+                            error!("👎 Unable to commit {}: {}", repo.patched.repo.defn, e);
+                            let mut updated = repo.clone();
+                            updated.committed = false;
+                            updated.last_error = Some(e);
+                            DataElement::BranchedRepo(updated)
+                        }
+                    }
+                },
+                other => other
+            })?;
+    }
 
     let committed_repos_count = state.data.repos.iter().filter(|elmt| match elmt {
         DataElement::BranchedRepo(repo) if repo.committed => true,
@@ -366,60 +1016,110 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     debug!("committed_repos_count = {}, branched_repos_count = {}", committed_repos_count, branched_repos_count);
 
-    info!("👍 Committed {} repos; {} failed", committed_repos_count, committed_repos_count - branched_repos_count);
+    info!("👍 Committed {} repos; {} failed", committed_repos_count, branched_repos_count.saturating_sub(committed_repos_count));
+    if stops_after(Stage::Commit, *last) {
+        return Ok( () );
+    }
 
-    if args.no_push {
-        info!("✨ All done, you selected no-push so keeping changes locally")
-    } else {
-        state.data.repos = state.data.repos
-            .into_iter()
-            .map(|elmt| match elmt {
-                DataElement::BranchedRepo(repo) if repo.committed && !repo.pushed => match do_push(&repo, &cfg) {
-                Ok(_)=>{
+    if runs(Stage::Push) {
+        checkpoint(dashboard, state, state_file_path)?;
+        run_stage(state, state_file_path, *jobs, Stage::Push,
+            |elmt| matches!(elmt, DataElement::BranchedRepo(repo) if repo.committed && !repo.pushed && repo.can_continue()),
+            |elmt| match elmt {
+                DataElement::BranchedRepo(repo) if repo.committed && !repo.pushed && repo.can_continue() => match do_push(&repo, cfg) {
+                Ok(pushed_ref)=>{
                     let mut updated = repo.clone();
 
                     updated.last_error = None;
                     updated.pushed = true;
+                    updated.pushed_ref = Some(pushed_ref);
                     DataElement::BranchedRepo(updated)
                 },
                 Err(e)=>{
-                    error!("👎 Unable to push {}: {}", repo.patched.repo.defn, e.to_string());
+                    error!("👎 Unable to push {}: {}", repo.patched.repo.defn, e);
                     let mut updated = repo.clone();
-                    updated.last_error = Some(e.to_string());
+                    updated.last_error = Some(e);
                     updated.pushed = false;
                     DataElement::BranchedRepo(updated)
                 }
                 },
-                other @_ => other,
-            })
-            .collect();
-
-        //Update our state on-disk so we can resume
-        write_datafile(state_file_path, &state)?;
+                other => other,
+            })?;
+    }
 
-        let pushed_repos_count = state.data.repos.iter().filter(|elmt| match elmt {
-            DataElement::BranchedRepo(repo) if repo.pushed => true,
-            DataElement::PRdRepo(_)=>true,
-            _=>false
-        }).count();
+    let pushed_repos_count = state.data.repos.iter().filter(|elmt| match elmt {
+        DataElement::BranchedRepo(repo) if repo.pushed => true,
+        DataElement::PRdRepo(_)=>true,
+        _=>false
+    }).count();
 
-        if pushed_repos_count==0 {
-            warn!("👎 No repos managed to push");
-            return Err(Box::from("No repos managed to push"))
-        }
+    if pushed_repos_count==0 {
+        warn!("👎 No repos managed to push");
+        return Err(Box::from("No repos managed to push"))
+    }
 
-        info!("👍 Pushed {} repos; {} failed", pushed_repos_count,  committed_repos_count - pushed_repos_count);
+    info!("👍 Pushed {} repos; {} failed", pushed_repos_count,  committed_repos_count.saturating_sub(pushed_repos_count));
+    if stops_after(Stage::Push, *last) {
+        return Ok( () );
+    }
 
-        match cfg.github_access_token.as_ref() {
-            Some(gh_access_token)=>{
-                state = create_all_pull_requests(state, gh_access_token)?;
-                write_datafile(state_file_path, &state)?;
-            },
-            None=>{
-                error!("😲 There is no github access token configured so we can't create pull requests");
-            }
+    checkpoint(dashboard, state, state_file_path)?;
+    match cfg.github_access_token.as_ref() {
+        Some(gh_access_token)=>{
+            create_all_pull_requests(state, state_file_path, gh_access_token, *pr_jobs)?;
+        },
+        None=>{
+            error!("😲 There is no github access token configured so we can't create pull requests");
         }
     }
 
     Ok( () )
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse(argv:&[&str]) -> Result<Args, clap::Error> {
+        let cli = Cli::try_parse_from([&["batchpatch", "-d", "test.state"], argv].concat())?;
+        Ok( cli.run.expect("the run arguments") )
+    }
+
+    #[test]
+    fn test_stage_range() -> Result<(), clap::Error> {
+        assert_eq!(stage_range(&parse(&[])?), (Stage::Clone, Stage::PullRequest));
+        assert_eq!(stage_range(&parse(&["--no-push"])?), (Stage::Clone, Stage::Commit));
+        assert_eq!(stage_range(&parse(&["--until", "branch"])?), (Stage::Clone, Stage::Branch));
+        assert_eq!(stage_range(&parse(&["--only", "pr"])?), (Stage::PullRequest, Stage::PullRequest));
+        assert_eq!(stage_range(&parse(&["--only", "commit"])?), (Stage::Commit, Stage::Commit));
+
+        //only one of them can be given
+        assert!(parse(&["--only", "patch", "--until", "push"]).is_err());
+        assert!(parse(&["--no-push", "--until", "push"]).is_err());
+        assert!(parse(&["--no-push", "--only", "commit"]).is_err());
+        Ok( () )
+    }
+
+    #[test]
+    fn test_stops_after() -> Result<(), clap::Error> {
+        let (_, last) = stage_range(&parse(&[])?);
+        assert!(!stops_after(Stage::Push, last));
+        assert!(stops_after(Stage::PullRequest, last));
+
+        let (_, last) = stage_range(&parse(&["--until", "commit"])?);
+        assert!(!stops_after(Stage::Branch, last));
+        assert!(stops_after(Stage::Commit, last));
+
+        //--no-push stops in the same place as --until commit
+        let (_, last) = stage_range(&parse(&["--no-push"])?);
+        assert!(!stops_after(Stage::Branch, last));
+        assert!(stops_after(Stage::Commit, last));
+
+        //--only stops after the stage that it runs
+        let (first, last) = stage_range(&parse(&["--only", "commit"])?);
+        assert!(stops_after(first, last));
+        assert!(!stops_after(Stage::Branch, last));
+        assert!(!stops_after(Stage::Push, last));
+        Ok( () )
+    }
+}
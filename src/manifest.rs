@@ -0,0 +1,295 @@
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use serde::{Deserialize, Serialize};
+use log::info;
+
+use crate::diffapply::DiffApplyOptions;
+use crate::discover::DiscoveryQuery;
+use crate::patcher::PatchSource;
+use crate::replace::ReplaceSpec;
+use crate::structured::load_edit_file;
+
+/**
+ * Everything about a campaign that would otherwise have to be given on the command line each time.  It is written in
+ * TOML or YAML, see the README for an example.  Anything given on the command line takes priority over the manifest.
+ */
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CampaignManifest {
+    pub state_file: Option<String>,
+    pub branch_name: Option<String>,
+    pub commit_message: Option<String>,
+    pub clone_mode: Option<String>,
+    #[serde(default)]
+    pub repos: RepoSource,
+    #[serde(default)]
+    pub steps: Vec<ManifestStep>,
+    #[serde(default)]
+    pub pr: PrSettings,
+}
+
+/**
+ * Where the repos for a new state file come from.  Repos from all of these are added together.
+ */
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RepoSource {
+    pub list_file: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub names: Vec<String>,
+    pub discover: Option<DiscoveryQuery>,
+}
+
+/**
+ * The PR title and body can use the placeholders in `template::PLACEHOLDERS`.  The body can be given inline or in a
+ * Markdown file, but not both.
+ */
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PrSettings {
+    pub title: Option<String>,
+    pub body: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub body_file: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub labels: Vec<String>,
+}
+
+fn default_strip() -> usize {
+    1
+}
+
+/**
+ * A patch step in the manifest.  These are the same as the patch options on the command line, and run in the order given.
+ */
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ManifestStep {
+    Diff {
+        path: String,
+        #[serde(default = "default_strip")]
+        strip: usize,
+        #[serde(default)]
+        apply_to: Option<String>,
+        #[serde(default)]
+        three_way: bool,
+    },
+    Script {
+        path: String,
+    },
+    Replace {
+        pattern: String,
+        #[serde(default)]
+        replacement: String,
+        globs: Vec<String>,
+    },
+    Edits {
+        path: String,
+    },
+}
+
+fn existing_path(path:&str, what:&str) -> Result<PathBuf, Box<dyn Error>> {
+    let f = Path::new(path);
+    if f.exists() {
+        Ok(f.canonicalize()?)
+    } else {
+        Err(Box::from(format!("{} does not exist at {}", what, path)))
+    }
+}
+
+impl CampaignManifest {
+    //The form that the manifest is recorded in the state file
+    pub fn to_state(&self) -> Result<serde_json::Value, Box<dyn Error>> {
+        Ok(serde_json::to_value(self)?)
+    }
+}
+
+impl ManifestStep {
+    pub fn to_patch_source(&self) -> Result<PatchSource, Box<dyn Error>> {
+        match self {
+            ManifestStep::Diff{path, strip, apply_to, three_way}=>Ok( PatchSource::DiffFile(existing_path(path, "Patch file")?, DiffApplyOptions {
+                strip: *strip,
+                target: apply_to.as_ref().map(|t| t.into()).unwrap_or(DiffApplyOptions::default().target),
+                three_way: *three_way,
+            }) ),
+            ManifestStep::Script{path}=>Ok( PatchSource::ScriptFile(existing_path(path, "Patch script")?) ),
+            ManifestStep::Replace{pattern, replacement, globs}=>Ok( PatchSource::Replace(ReplaceSpec {
+                globs: globs.to_owned(),
+                pattern: pattern.to_owned(),
+                replacement: replacement.to_owned(),
+            }) ),
+            ManifestStep::Edits{path}=>{
+                let fullpath = existing_path(path, "Edit file")?;
+                let spec = load_edit_file(&fullpath)?;
+                Ok( PatchSource::Edits(fullpath, spec) )
+            },
+        }
+    }
+
+    //Describes the step for the default commit message
+    pub fn describe(&self) -> String {
+        match self {
+            ManifestStep::Diff{path, ..}=>format!("applied the patch file {}", path),
+            ManifestStep::Script{path}=>format!("applied the script {}", path),
+            ManifestStep::Replace{pattern, replacement, ..}=>format!("replaced /{}/ with '{}'", pattern, replacement),
+            ManifestStep::Edits{path}=>format!("applied the edits in {}", path),
+        }
+    }
+}
+
+//Makes a path in the manifest relative to the directory the manifest is in, unless it is absolute already
+fn resolve(base:&Path, path:&mut String) {
+    let p = Path::new(path.as_str());
+    if p.is_relative() {
+        *path = base.join(p).to_string_lossy().to_string();
+    }
+}
+
+impl CampaignManifest {
+    fn resolve_paths(&mut self, base:&Path) {
+        if let Some(state_file) = self.state_file.as_mut() {
+            resolve(base, state_file);
+        }
+        if let Some(list_file) = self.repos.list_file.as_mut() {
+            resolve(base, list_file);
+        }
+        if let Some(body_file) = self.pr.body_file.as_mut() {
+            resolve(base, body_file);
+        }
+        for step in self.steps.iter_mut() {
+            match step {
+                ManifestStep::Diff{path, ..} | ManifestStep::Script{path} | ManifestStep::Edits{path}=>resolve(base, path),
+                ManifestStep::Replace{..}=>(),
+            }
+        }
+    }
+}
+
+/**
+ * Loads a campaign manifest.  Files ending in .yml or .yaml are read as YAML, anything else as TOML.
+ * Relative paths in it are taken to be relative to the manifest file, not the current directory.
+ */
+pub fn load_manifest(p:&Path) -> Result<CampaignManifest, Box<dyn Error>> {
+    info!("Loading campaign manifest from {}...", p.display());
+    let content = fs::read_to_string(p)?;
+    let mut manifest:CampaignManifest = match p.extension().and_then(|e| e.to_str()) {
+        Some("yml") | Some("yaml")=>serde_yaml::from_str(&content)?,
+        _=>toml_edit::de::from_str(&content)?,
+    };
+    if manifest.pr.body.is_some() && manifest.pr.body_file.is_some() {
+        return Err(Box::from("the manifest can have a PR body or a body_file, but not both"));
+    }
+    let base = p.canonicalize()?.parent().map(|d| d.to_path_buf()).unwrap_or_default();
+    manifest.resolve_paths(&base);
+    Ok(manifest)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_load_manifest() -> Result<(), Box<dyn Error>> {
+        let dir = TempDir::new()?;
+        fs::write(dir.path().join("upgrade.diff"), "")?;
+        fs::write(dir.path().join("campaign.toml"), r#"
+state_file = "campaign.state"
+branch_name = "upgrade-checkout"
+clone_mode = "https"
+
+[repos]
+names = ["my-org/first", "my-org/second"]
+
+[repos.discover]
+org = "my-org"
+exclude_archived = true
+
+[pr]
+title = "Upgrade actions/checkout"
+labels = ["dependencies", "ci"]
+
+[[steps]]
+type = "diff"
+path = "upgrade.diff"
+three_way = true
+
+[[steps]]
+type = "replace"
+pattern = "actions/checkout@v2"
+replacement = "actions/checkout@v4"
+globs = ["*.yml"]
+"#)?;
+
+        let manifest = load_manifest(&dir.path().join("campaign.toml"))?;
+        let base = dir.path().canonicalize()?;
+        assert_eq!(manifest.state_file, Some(base.join("campaign.state").to_string_lossy().to_string()));
+        assert_eq!(manifest.branch_name.as_deref(), Some("upgrade-checkout"));
+        assert_eq!(manifest.commit_message, None);
+        assert_eq!(manifest.repos.names, vec!["my-org/first", "my-org/second"]);
+        let discover = manifest.repos.discover.as_ref().unwrap();
+        assert_eq!(discover.org.as_deref(), Some("my-org"));
+        assert!(discover.exclude_archived);
+        assert!(!discover.exclude_forks);
+        assert_eq!(manifest.pr.labels, vec!["dependencies", "ci"]);
+
+        assert_eq!(manifest.steps.len(), 2);
+        match manifest.steps[0].to_patch_source()? {
+            PatchSource::DiffFile(path, opts)=>{
+                assert_eq!(path, base.join("upgrade.diff"));
+                assert_eq!(opts.strip, 1);
+                assert!(opts.three_way);
+            },
+            other=>return Err(Box::from(format!("expected a diff step, got {}", other))),
+        }
+        assert_eq!(manifest.steps[1].describe(), "replaced /actions/checkout@v2/ with 'actions/checkout@v4'");
+        Ok( () )
+    }
+
+    #[test]
+    fn test_load_yaml_manifest() -> Result<(), Box<dyn Error>> {
+        let dir = TempDir::new()?;
+        fs::write(dir.path().join("campaign.yaml"), r#"
+branch_name: upgrade-checkout
+commit_message: Upgrade actions/checkout
+repos:
+  list_file: repos.txt
+pr:
+  body: Moves everything onto actions/checkout@v4
+steps:
+  - type: edits
+    path: /abs/edits.json
+"#)?;
+
+        let manifest = load_manifest(&dir.path().join("campaign.yaml"))?;
+        let base = dir.path().canonicalize()?;
+        assert_eq!(manifest.commit_message.as_deref(), Some("Upgrade actions/checkout"));
+        assert_eq!(manifest.repos.list_file, Some(base.join("repos.txt").to_string_lossy().to_string()));
+        assert_eq!(manifest.pr.body.as_deref(), Some("Moves everything onto actions/checkout@v4"));
+        assert!(manifest.pr.labels.is_empty());
+        assert_eq!(manifest.steps[0].describe(), "applied the edits in /abs/edits.json");
+        Ok( () )
+    }
+
+    #[test]
+    fn test_pr_body_file() -> Result<(), Box<dyn Error>> {
+        let dir = TempDir::new()?;
+        fs::write(dir.path().join("campaign.yaml"), "pr:\n  title: Upgrade {{repo}}\n  body_file: pr.md\n")?;
+        let manifest = load_manifest(&dir.path().join("campaign.yaml"))?;
+        let base = dir.path().canonicalize()?;
+        assert_eq!(manifest.pr.title.as_deref(), Some("Upgrade {{repo}}"));
+        assert_eq!(manifest.pr.body_file, Some(base.join("pr.md").to_string_lossy().to_string()));
+
+        fs::write(dir.path().join("both.yaml"), "pr:\n  body: inline\n  body_file: pr.md\n")?;
+        assert!(load_manifest(&dir.path().join("both.yaml")).is_err());
+        Ok( () )
+    }
+
+    #[test]
+    fn test_missing_step_file() -> Result<(), Box<dyn Error>> {
+        let dir = TempDir::new()?;
+        fs::write(dir.path().join("campaign.toml"), "[[steps]]\ntype = \"script\"\npath = \"nope.sh\"\n")?;
+
+        let manifest = load_manifest(&dir.path().join("campaign.toml"))?;
+        assert!(manifest.steps[0].to_patch_source().is_err());
+        Ok( () )
+    }
+}
@@ -0,0 +1,226 @@
+use std::error::Error;
+use serde_json::{Map, Value};
+use log::info;
+
+use crate::error::{ErrorKind, RepoError, Stage};
+
+/**
+ * The version of the state file that this build writes.  Whenever the state changes in a way that older files won't
+ * just load with serde defaults, bump this and add a step to `MIGRATIONS` that upgrades the previous version.
+ */
+pub const SCHEMA_VERSION:u32 = 1;
+
+type Migration = fn(&mut Value) -> Result<(), Box<dyn Error>>;
+
+//MIGRATIONS[n] upgrades a version n document to version n+1
+const MIGRATIONS:[Migration; SCHEMA_VERSION as usize] = [
+    typed_errors,
+];
+
+/**
+ * Upgrades a state document from whatever version it is at to the current one.  Documents from before the state was
+ * versioned are version 0.
+ */
+pub fn migrate(mut doc:Value) -> Result<Value, Box<dyn Error>> {
+    let version = match doc.get("schema_version") {
+        None=>0,
+        Some(v)=>v.as_u64().ok_or("the state file's schema_version is not a number")? as u32,
+    };
+    if version > SCHEMA_VERSION {
+        return Err(Box::from(format!("the state file is version {}, but this batchpatch only understands up to version {}; it was probably written by a newer batchpatch", version, SCHEMA_VERSION)));
+    }
+
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        info!("⬆️ Upgrading the state from version {} to {}", from, from + 1);
+        migration(&mut doc)?;
+    }
+
+    match doc.as_object_mut() {
+        Some(obj)=>{
+            obj.insert("schema_version".to_string(), Value::from(SCHEMA_VERSION));
+            Ok( doc )
+        },
+        None=>Err(Box::from("the state file is not a JSON object")),
+    }
+}
+
+//Applies `f` to each of the repos in the document, as the variant name and its contents
+fn for_each_repo(doc:&mut Value, mut f:impl FnMut(&str, &mut Map<String, Value>) -> Result<(), Box<dyn Error>>) -> Result<(), Box<dyn Error>> {
+    let repos = doc.pointer_mut("/data/repos")
+        .and_then(|r| r.as_array_mut())
+        .ok_or("the state file has no data.repos list")?;
+
+    for repo in repos.iter_mut() {
+        if let Some((variant, contents)) = repo.as_object_mut().and_then(|o| o.iter_mut().next()) {
+            if let Some(contents) = contents.as_object_mut() {
+                f(variant, contents)?;
+            }
+        }
+    }
+    Ok( () )
+}
+
+//The old error kinds were much rougher than the new ones, so only some of them carry over
+fn old_error_kind(kind:Option<&Value>) -> ErrorKind {
+    match kind.and_then(|k| k.as_str()) {
+        Some("Io")=>ErrorKind::Io,
+        Some("Internal")=>ErrorKind::Internal,
+        _=>ErrorKind::Other,
+    }
+}
+
+/**
+ * Errors used to be just a message, sometimes with a rough `error_kind` next to them.  They are now a RepoError, with
+ * the stage that failed and whether it is worth trying again.
+ */
+fn typed_error(obj:&mut Map<String, Value>, message_key:&str, kind_key:&str, stage:Stage) -> Result<(), Box<dyn Error>> {
+    let kind = old_error_kind(obj.remove(kind_key).as_ref());
+    if let Some(Value::String(message)) = obj.get(message_key) {
+        let err = RepoError::new(stage, kind, message.to_owned());
+        obj.insert(message_key.to_string(), serde_json::to_value(err)?);
+    }
+    Ok( () )
+}
+
+fn typed_local_error(local:&mut Map<String, Value>) -> Result<(), Box<dyn Error>> {
+    typed_error(local, "last_error", "error_kind", Stage::Clone)
+}
+
+fn typed_patched_error(patched:&mut Map<String, Value>) -> Result<(), Box<dyn Error>> {
+    if let Some(local) = patched.get_mut("repo").and_then(|r| r.as_object_mut()) {
+        typed_local_error(local)?;
+    }
+    //a patch that couldn't be run at all only had a kind; the message was in the output
+    if let Some(kind) = patched.remove("error_kind") {
+        let kind = old_error_kind(Some(&kind));
+        let message = patched.get("output").and_then(|o| o.as_str()).unwrap_or_default().to_string();
+        patched.insert("error".to_string(), serde_json::to_value(RepoError::new(Stage::Patch, kind, message))?);
+    }
+    Ok( () )
+}
+
+fn typed_branched_error(branched:&mut Map<String, Value>) -> Result<(), Box<dyn Error>> {
+    if let Some(patched) = branched.get_mut("patched").and_then(|p| p.as_object_mut()) {
+        typed_patched_error(patched)?;
+    }
+    //the flags say how far it got, so the stage after that is the one that failed
+    let flag = |name:&str| branched.get(name).and_then(|v| v.as_bool()).unwrap_or(false);
+    let stage = match (flag("committed"), flag("pushed")) {
+        (true, true)=>Stage::PullRequest,
+        (true, false)=>Stage::Push,
+        (false, _)=>Stage::Branch,
+    };
+    typed_error(branched, "lastError", "errorKind", stage)
+}
+
+fn typed_errors(doc:&mut Value) -> Result<(), Box<dyn Error>> {
+    for_each_repo(doc, |variant, contents| match variant {
+        "LocalRepo"=>typed_local_error(contents),
+        "PatchedRepo"=>typed_patched_error(contents),
+        "BranchedRepo"=>typed_branched_error(contents),
+        "PRdRepo"=>match contents.get_mut("branched").and_then(|b| b.as_object_mut()) {
+            Some(branched)=>typed_branched_error(branched),
+            None=>Ok( () ),
+        },
+        _=>Ok( () ),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+    use crate::data::{load_datafile, write_datafile, BaseStateDefn, DataElement};
+
+    //A state file as the first version of batchpatch wrote it, with a repo at each stage
+    const VERSION_0_ORIGINAL:&str = r#"{
+        "data": {"repos": [
+            {"RemoteRepo": {"owner": "org", "name": "remote", "main_branch_name": null}},
+            {"LocalRepo": {"defn": {"owner": "org", "name": "unclonable", "main_branch_name": null}, "local_path": "org/unclonable", "last_error": "could not resolve host"}},
+            {"PatchedRepo": {"repo": {"defn": {"owner": "org", "name": "patched", "main_branch_name": null}, "local_path": "org/patched", "last_error": null}, "changes": 2, "output": "", "success": true}},
+            {"BranchedRepo": {"patched": {"repo": {"defn": {"owner": "org", "name": "unbranched", "main_branch_name": null}, "local_path": "org/unbranched", "last_error": null}, "changes": 1, "output": "", "success": true}, "branchName": "fix", "committed": false, "pushed": false, "lastError": "branch already exists"}},
+            {"BranchedRepo": {"patched": {"repo": {"defn": {"owner": "org", "name": "unpushed", "main_branch_name": null}, "local_path": "org/unpushed", "last_error": null}, "changes": 1, "output": "", "success": true}, "branchName": "fix", "committed": true, "pushed": false, "lastError": "authentication failed"}},
+            {"PRdRepo": {"branched": {"patched": {"repo": {"defn": {"owner": "org", "name": "done", "main_branch_name": "main"}, "local_path": "org/done", "last_error": null}, "changes": 1, "output": "", "success": true}, "branchName": "fix", "committed": true, "pushed": true, "lastError": null}, "url": "https://github.com/org/done/pull/1"}}
+        ]},
+        "pr_description": null,
+        "pr_title": null
+    }"#;
+
+    //Still version 0, but from when errors had an error_kind next to them, and patches could record one
+    const VERSION_0_ERROR_KINDS:&str = r#"{
+        "data": {"repos": [
+            {"LocalRepo": {"defn": {"owner": "org", "name": "unclonable", "main_branch_name": null}, "local_path": "org/unclonable", "last_error": "Permission denied", "error_kind": "Io"}},
+            {"PatchedRepo": {"repo": {"defn": {"owner": "org", "name": "unpatchable", "main_branch_name": null}, "local_path": "org/unpatchable", "last_error": null}, "changes": 0, "output": "could not find repository", "success": false, "outcome": "Failed", "steps": [], "error_kind": "Git"}},
+            {"BranchedRepo": {"patched": {"repo": {"defn": {"owner": "org", "name": "unpr", "main_branch_name": null}, "local_path": "org/unpr", "last_error": null}, "changes": 1, "output": "", "success": true, "outcome": "Clean", "steps": []}, "branchName": "fix", "committed": true, "pushed": true, "lastError": "stage panicked", "errorKind": "Internal", "prAttempts": 2}}
+        ]},
+        "pr_description": "desc",
+        "pr_title": "title",
+        "pr_labels": ["dependencies"]
+    }"#;
+
+    fn load_str(json:&str) -> Result<BaseStateDefn, Box<dyn Error>> {
+        let dir = TempDir::new()?;
+        let p = dir.path().join("test.state");
+        std::fs::write(&p, json)?;
+        load_datafile(&p)
+    }
+
+    //Writes the state out and reads it back in, which should not change it
+    fn round_trip(state:&BaseStateDefn) -> Result<(), Box<dyn Error>> {
+        let dir = TempDir::new()?;
+        let p = dir.path().join("test.state");
+        write_datafile(&p, state)?;
+        let reloaded = load_datafile(&p)?;
+        assert_eq!(reloaded.schema_version, SCHEMA_VERSION);
+        assert_eq!(serde_json::to_value(&reloaded)?, serde_json::to_value(state)?);
+        Ok( () )
+    }
+
+    fn error_of(elmt:&DataElement) -> Option<&RepoError> {
+        match elmt {
+            DataElement::LocalRepo(repo)=>repo.last_error.as_ref(),
+            DataElement::PatchedRepo(repo)=>repo.error.as_ref(),
+            DataElement::BranchedRepo(repo)=>repo.last_error.as_ref(),
+            DataElement::PRdRepo(repo)=>repo.branched.last_error.as_ref(),
+            DataElement::RemoteRepo(_)=>None,
+        }
+    }
+
+    #[test]
+    fn test_migrate_original() -> Result<(), Box<dyn Error>> {
+        let state = load_str(VERSION_0_ORIGINAL)?;
+        assert_eq!(state.schema_version, SCHEMA_VERSION);
+        let errors:Vec<Option<&RepoError>> = state.data.repos.iter().map(error_of).collect();
+        assert_eq!(errors, vec![
+            None,
+            Some(&RepoError::new(Stage::Clone, ErrorKind::Other, "could not resolve host".to_string())),
+            None,
+            Some(&RepoError::new(Stage::Branch, ErrorKind::Other, "branch already exists".to_string())),
+            Some(&RepoError::new(Stage::Push, ErrorKind::Other, "authentication failed".to_string())),
+            None,
+        ]);
+        round_trip(&state)
+    }
+
+    #[test]
+    fn test_migrate_error_kinds() -> Result<(), Box<dyn Error>> {
+        let state = load_str(VERSION_0_ERROR_KINDS)?;
+        let errors:Vec<Option<&RepoError>> = state.data.repos.iter().map(error_of).collect();
+        assert_eq!(errors[0], Some(&RepoError::new(Stage::Clone, ErrorKind::Io, "Permission denied".to_string())));
+        assert_eq!(errors[1], Some(&RepoError::new(Stage::Patch, ErrorKind::Other, "could not find repository".to_string())));
+        assert_eq!(errors[2], Some(&RepoError::new(Stage::PullRequest, ErrorKind::Internal, "stage panicked".to_string())));
+        assert_eq!(state.pr_labels, vec!["dependencies"]);
+        round_trip(&state)
+    }
+
+    #[test]
+    fn test_current_version() -> Result<(), Box<dyn Error>> {
+        let state = load_str(VERSION_0_ORIGINAL)?;
+        let current = serde_json::to_string(&state)?;
+        assert_eq!(serde_json::to_value(&load_str(&current)?)?, serde_json::to_value(&state)?);
+
+        let newer = current.replace(&format!("\"schema_version\":{}", SCHEMA_VERSION), &format!("\"schema_version\":{}", SCHEMA_VERSION + 1));
+        assert!(load_str(&newer).is_err());
+        Ok( () )
+    }
+}
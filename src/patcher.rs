@@ -1,46 +1,45 @@
 use std::fmt::Display;
 use std::path::PathBuf;
-use std::{ffi::OsString, path::Path};
+use std::path::Path;
 use std::error::Error;
 use std::process::Command;
-use log::{info, debug, error};
-use git2::{Index, IndexAddOption, IndexEntry, Repository};
-use octorust::repos::Repos;
-use octorust::types::Repo;
+use log::{error, info};
+use git2::{Diff, DiffOptions, Repository};
 
-use crate::data::{LocalRepo, PatchedRepo};
+use crate::data::{LocalRepo, PatchOutcome, PatchStepResult, PatchedRepo};
+use crate::error::{RepoError, Stage};
+use crate::diffapply::{apply_diff, DiffApplyOptions};
+use crate::gitutils::discard_changes;
+use crate::replace::{apply_replace, ReplaceSpec};
+use crate::structured::{apply_edits, EditSpec};
 
 pub enum PatchSource {
-    DiffFile(PathBuf),
-    ScriptFile(PathBuf)
+    DiffFile(PathBuf, DiffApplyOptions),
+    ScriptFile(PathBuf),
+    Replace(ReplaceSpec),
+    Edits(PathBuf, EditSpec),
 }
 
 impl Display for PatchSource {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            PatchSource::DiffFile(path)=>f.write_fmt(format_args!("diff {}",path.display())),
-            PatchSource::ScriptFile(path)=>f.write_fmt(format_args!("script {}", path.display()))
+            PatchSource::DiffFile(path, _)=>f.write_fmt(format_args!("diff {}",path.display())),
+            PatchSource::ScriptFile(path)=>f.write_fmt(format_args!("script {}", path.display())),
+            PatchSource::Replace(spec)=>f.write_fmt(format_args!("replace /{}/ in {}", spec.pattern, spec.globs.join(", "))),
+            PatchSource::Edits(path, _)=>f.write_fmt(format_args!("edits {}", path.display())),
         }
     }
 }
 
-fn apply_patch_file(patchfile: &Path, target: &LocalRepo) -> Result<String, Box<dyn Error>> {
-    let mut patch_cmd_builder = OsString::new();
-    patch_cmd_builder.push("patch -t --forward -p1 < ");
-    patch_cmd_builder.push(patchfile.as_os_str());
-    let patch_cmd = patch_cmd_builder.to_str().unwrap();
-
-    debug!("{}", patch_cmd);
-
+fn apply_patch_script(script_file: &Path, target: &LocalRepo) -> Result<String, Box<dyn Error>> {
     let result = Command::new("sh")
-        .args(["-c", patch_cmd])
+        .args(["-c", script_file.to_str().unwrap()])
         .current_dir(target.local_path.as_ref())
         .output()?;
 
     let stdout_msg = String::from_utf8(result.stdout).unwrap_or("(invalid utf from terminal)".to_string());
     let stderr_msg = String::from_utf8(result.stderr).unwrap_or("(invalid utf from terminal)".to_string());
     let msg = format!("{}\n{}", stdout_msg, stderr_msg);
-
     if result.status.success() {
         Ok(msg)
     } else {
@@ -48,58 +47,199 @@ fn apply_patch_file(patchfile: &Path, target: &LocalRepo) -> Result<String, Box<
     }
 }
 
-fn apply_patch_script(script_file: &Path, target: &LocalRepo) -> Result<String, Box<dyn Error>> {
-    let result = Command::new("sh")
-        .args(["-c", script_file.to_str().unwrap()])
-        .current_dir(target.local_path.as_ref())
-        .output()?;
+/**
+ * The changes that the patch made to the clone, compared to the commit it started from.  New files are included, as
+ * they are added when the changes are committed.
+ */
+pub fn patch_diff(repo: &Repository) -> Result<Diff<'_>, git2::Error> {
+    let head_tree = repo.head()?.peel_to_tree()?;
+    let mut opts = DiffOptions::new();
+    opts.include_untracked(true)
+        .recurse_untracked_dirs(true)
+        .show_untracked_content(true);
+    repo.diff_tree_to_workdir_with_index(Some(&head_tree), Some(&mut opts))
+}
 
-    let stdout_msg = String::from_utf8(result.stdout).unwrap_or("(invalid utf from terminal)".to_string());
-    let stderr_msg = String::from_utf8(result.stderr).unwrap_or("(invalid utf from terminal)".to_string());
-    let msg = format!("{}\n{}", stdout_msg, stderr_msg);
-    if result.status.success() {
-        Ok(msg)
+//How many files the patch changed, counting the new files that it made
+pub fn assess_changes(repo: &Repository) -> Result<usize, Box<dyn Error>>{
+    let stats = patch_diff(repo)?.stats()?;
+    Ok(stats.files_changed())
+}
+
+//Runs a single step of the patch, returning how it went and what it said
+fn run_step(step: &PatchSource, target: &LocalRepo, repo: &Repository) -> (PatchOutcome, String) {
+    let result = match step {
+        PatchSource::DiffFile(path, opts)=>apply_diff(path, opts, repo).map(|r| (r.outcome, r.output)),
+        PatchSource::ScriptFile(path)=>apply_patch_script(path, target).map(|msg| (PatchOutcome::Clean, msg)),
+        PatchSource::Replace(spec)=>apply_replace(spec, repo).map(|msg| (PatchOutcome::Clean, msg)),
+        PatchSource::Edits(_, spec)=>apply_edits(spec, repo).map(|msg| (PatchOutcome::Clean, msg)),
+    };
+    result.unwrap_or_else(|error| (PatchOutcome::Failed, error.to_string()))
+}
+
+/**
+ * Works out the outcome of the patch as a whole from the outcomes of the steps that ran. The first failure wins;
+ * otherwise the patch was only "already applied" if every step was.
+ */
+fn overall_outcome(steps: &[PatchStepResult]) -> PatchOutcome {
+    if let Some(failed) = steps.iter().find(|s| !s.outcome.is_success()) {
+        failed.outcome
+    } else if steps.iter().all(|s| s.outcome==PatchOutcome::AlreadyApplied) {
+        PatchOutcome::AlreadyApplied
+    } else if steps.iter().any(|s| s.outcome==PatchOutcome::Fuzzy) {
+        PatchOutcome::Fuzzy
+    } else if steps.iter().any(|s| s.outcome==PatchOutcome::ThreeWay) {
+        PatchOutcome::ThreeWay
     } else {
-        Err(Box::from(msg))
+        PatchOutcome::Clean
     }
 }
 
-fn assess_changes(repo: &Repository) -> Result<usize, Box<dyn Error>>{
-    let diffs = repo.diff_tree_to_workdir_with_index(None, None)?;
-    let stats = diffs.stats()?;
-    Ok(stats.files_changed())
+//For when the patch could not be run at all
+fn failed_patch(target: LocalRepo, steps: Vec<PatchStepResult>, err: &(dyn Error + 'static)) -> Box<PatchedRepo> {
+    error!("😞 Unable to patch {}: {}", target.defn, err);
+    Box::new(PatchedRepo {
+        repo: target,
+        changes: 0,
+        success: false,
+        output: err.to_string(),
+        outcome: Some(PatchOutcome::Failed),
+        steps,
+        error: Some(RepoError::from_error(Stage::Patch, err)),
+        review: None,
+    })
 }
 
-pub fn run_patch(patchfile: &PatchSource, target: LocalRepo) -> Result<Box<PatchedRepo>, Box<dyn Error>> {
-    info!("💉 Patching {} with {}", target.defn, patchfile );
-
-    let result = match patchfile {
-        PatchSource::DiffFile(path)=>apply_patch_file(path, &target),
-        PatchSource::ScriptFile(path)=>apply_patch_script(path, &target)
+/**
+ * Runs each of the patch steps on the repo in turn.  If a step fails then the ones after it are not run, and the
+ * changes made by the steps before it are thrown away so that the clone is left as it was.
+ * The outcome and output of every step that ran is recorded in the returned PatchedRepo, as is anything that stopped
+ * the patch from being run at all.
+ */
+pub fn run_patch(steps: &[PatchSource], target: LocalRepo) -> Box<PatchedRepo> {
+    let repo = match Repository::open(target.local_path.as_ref()) {
+        Ok(repo)=>repo,
+        Err(e)=>return failed_patch(target, vec![], &e),
     };
 
-    let repo = Repository::open(target.local_path.as_ref())?;
-
-    match result {
-        Ok(msg)=>{
-            let file_updates = assess_changes(&repo)?;
-            info!("👌 Patched successfully; {} files were updated", file_updates);
-
-            Ok( Box::new(PatchedRepo {
-                repo: target,
-                changes: file_updates,
-                success: true,
-                output: msg
-            }))
-        },
-        Err(error)=>{
-            info!("😞 Patch did not apply; {}", error);
-            Ok( Box::new(PatchedRepo {
-                repo: target,
-                changes: 0,
-                success: false,
-                output: error.to_string()
-            }))
-        },
+    let mut results:Vec<PatchStepResult> = vec![];
+    for (n, step) in steps.iter().enumerate() {
+        info!("💉 Patching {} with {} (step {} of {})", target.defn, step, n + 1, steps.len());
+        let (outcome, output) = run_step(step, &target, &repo);
+        if outcome.is_success() {
+            info!("👌 Step {} {}", n + 1, outcome);
+        } else {
+            info!("😞 Step {} {}; {}", n + 1, outcome, output);
+        }
+        results.push(PatchStepResult {
+            step: step.to_string(),
+            outcome,
+            output,
+        });
+        if !outcome.is_success() {
+            break;
+        }
+    }
+
+    let outcome = overall_outcome(&results);
+    let output = results.iter().map(|r| r.output.as_str()).collect::<Vec<&str>>().join("\n");
+    if outcome.is_success() {
+        let file_updates = match assess_changes(&repo) {
+            Ok(file_updates)=>file_updates,
+            Err(e)=>return failed_patch(target, results, e.as_ref()),
+        };
+        info!("👌 Patch {}; {} files were updated", outcome, file_updates);
+
+        Box::new(PatchedRepo {
+            repo: target,
+            changes: file_updates,
+            success: true,
+            output,
+            outcome: Some(outcome),
+            steps: results,
+            error: None,
+            review: None,
+        })
+    } else {
+        info!("😞 Patch {}", outcome);
+        if let Err(e) = discard_changes(&repo) {
+            return failed_patch(target, results, e.as_ref());
+        }
+        Box::new(PatchedRepo {
+            repo: target,
+            changes: 0,
+            success: false,
+            output,
+            outcome: Some(outcome),
+            steps: results,
+            error: None,
+            review: None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::data::RepoDefn;
+    use crate::replace::ReplaceSpec;
+    use crate::gitutils::test::repo_with_commit;
+    use std::fs;
+    use tempfile::TempDir;
+
+    fn step_result(outcome: PatchOutcome) -> PatchStepResult {
+        PatchStepResult {
+            step: "test".to_string(),
+            outcome,
+            output: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_overall_outcome() {
+        assert_eq!(overall_outcome(&[step_result(PatchOutcome::AlreadyApplied), step_result(PatchOutcome::AlreadyApplied)]), PatchOutcome::AlreadyApplied);
+        assert_eq!(overall_outcome(&[step_result(PatchOutcome::AlreadyApplied), step_result(PatchOutcome::Clean)]), PatchOutcome::Clean);
+        assert_eq!(overall_outcome(&[step_result(PatchOutcome::ThreeWay), step_result(PatchOutcome::Clean)]), PatchOutcome::ThreeWay);
+        assert_eq!(overall_outcome(&[step_result(PatchOutcome::ThreeWay), step_result(PatchOutcome::Fuzzy)]), PatchOutcome::Fuzzy);
+        assert_eq!(overall_outcome(&[step_result(PatchOutcome::Clean), step_result(PatchOutcome::Conflicted)]), PatchOutcome::Conflicted);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_patch_diff_new_files() -> Result<(), Box<dyn Error>> {
+        let dir = TempDir::new()?;
+        let repo = repo_with_commit(dir.path(), &[("version.txt", "1.0\n")])?;
+        fs::write(dir.path().join("version.txt"), "2.0\n")?;
+        fs::create_dir(dir.path().join("docs"))?;
+        fs::write(dir.path().join("docs").join("CHANGES.md"), "2.0\n")?;
+
+        assert_eq!(assess_changes(&repo)?, 2);
+
+        //a patch that only adds files still has changes to commit
+        fs::write(dir.path().join("version.txt"), "1.0\n")?;
+        assert_eq!(assess_changes(&repo)?, 1);
+        Ok( () )
+    }
+
+    #[test]
+    fn test_failed_step_discards_changes() -> Result<(), Box<dyn Error>> {
+        let dir = TempDir::new()?;
+        repo_with_commit(dir.path(), &[("version.txt", "1.0\n")])?;
+        let script_dir = TempDir::new()?;
+        let script = script_dir.path().join("fail.sh");
+        fs::write(&script, "#!/bin/sh\ntouch new-file.txt\nexit 1\n")?;
+        fs::set_permissions(&script, std::os::unix::fs::PermissionsExt::from_mode(0o755))?;
+        let steps = vec![
+            PatchSource::Replace(ReplaceSpec { globs: vec!["version.txt".to_string()], pattern: "1.0".to_string(), replacement: "2.0".to_string() }),
+            PatchSource::ScriptFile(script.clone()),
+        ];
+        let target = LocalRepo { defn: RepoDefn::new("test/repo")?, local_path: dir.path().into(), last_error: None };
+
+        let patched = run_patch(&steps, target);
+        assert!(!patched.success);
+        assert_eq!(patched.steps.len(), 2);
+        assert_eq!(patched.steps[0].outcome, PatchOutcome::Clean);
+        assert_eq!(fs::read_to_string(dir.path().join("version.txt"))?, "1.0\n");
+        assert!(!dir.path().join("new-file.txt").exists());
+        Ok( () )
+    }
+}
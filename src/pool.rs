@@ -0,0 +1,263 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::any::Any;
+use std::error::Error;
+use std::io::{self, IsTerminal, Write};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::{mpsc, Mutex};
+use std::thread;
+use std::time::Instant;
+use colog::format::{default_prefix_token, CologStyle};
+use colored::Colorize;
+use log::{error, Level};
+
+use crate::data::{write_datafile, BaseStateDefn, DataElement};
+use crate::error::{ErrorKind, RepoError, Stage};
+use crate::history::RepoEvent;
+use crate::progress::{finish_transfer, set_stage};
+use crate::ratelimit::unix_now;
+
+thread_local! {
+    //The repo that this thread is working on, so that log lines can say which repo they are about
+    static CURRENT_REPO: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/**
+ * The usual colog style, but with the repo that the current thread is working on added after the level
+ */
+struct RepoLogStyle;
+
+impl CologStyle for RepoLogStyle {
+    fn prefix_token(&self, level: &Level) -> String {
+        let prefix = default_prefix_token(self, level);
+        CURRENT_REPO.with(|current| match current.borrow().as_ref() {
+            Some(repo)=>format!("{} {}", prefix, format!("{}:", repo).bold()),
+            None=>prefix,
+        })
+    }
+}
+
+//The repo that this thread is working on, if it is working on one
+pub fn current_repo() -> Option<String> {
+    CURRENT_REPO.with(|current| current.borrow().clone())
+}
+
+//How many log lines are kept while they are being captured, which is plenty to fill a screen
+const CAPTURED_LINES: usize = 500;
+
+//Set while the log is being captured instead of written out, i.e. while the dashboard has the terminal
+static CAPTURE: Mutex<Option<VecDeque<String>>> = Mutex::new(None);
+
+/**
+ * Where the log goes.  Normally that is stderr, but while the log is being captured the lines are kept so that they
+ * can be shown on the dashboard instead.
+ */
+struct LogSink;
+
+impl Write for LogSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match CAPTURE.lock().unwrap().as_mut() {
+            Some(lines)=>{
+                lines.extend(String::from_utf8_lossy(buf).lines().map(|line| line.to_string()));
+                while lines.len() > CAPTURED_LINES {
+                    lines.pop_front();
+                }
+                Ok(buf.len())
+            },
+            None=>io::stderr().write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        io::stderr().flush()
+    }
+}
+
+pub fn init_logging() {
+    let mut builder = colog::default_builder();
+    builder.format(colog::formatter(RepoLogStyle));
+    builder.target(env_logger::Target::Pipe(Box::new(LogSink)));
+    //a pipe is never coloured unless we say so, but this one usually ends up on the terminal
+    builder.write_style(if io::stderr().is_terminal() { env_logger::WriteStyle::Always } else { env_logger::WriteStyle::Never });
+    builder.init();
+}
+
+//Starts or stops keeping log lines instead of writing them out.  Anything that was kept is dropped when it stops.
+pub fn capture_log(capture:bool) {
+    *CAPTURE.lock().unwrap() = if capture { Some(VecDeque::new()) } else { None };
+}
+
+//The last few log lines that were captured, oldest first
+pub fn captured_log() -> Vec<String> {
+    CAPTURE.lock().unwrap().as_ref().map(|lines| lines.iter().cloned().collect()).unwrap_or_default()
+}
+
+//Runs `f` with log lines on this thread labelled with the given repo
+fn with_repo_label<T>(repo:String, f: impl FnOnce() -> T) -> T {
+    CURRENT_REPO.with(|current| *current.borrow_mut() = Some(repo));
+    let result = f();
+    CURRENT_REPO.with(|current| *current.borrow_mut() = None);
+    result
+}
+
+//Gets the message out of a panic, which is nearly always a string of some sort
+fn panic_message(payload:&(dyn Any + Send)) -> String {
+    match (payload.downcast_ref::<&str>(), payload.downcast_ref::<String>()) {
+        (Some(msg), _)=>msg.to_string(),
+        (_, Some(msg))=>msg.to_owned(),
+        (None, None)=>"unknown panic".to_string(),
+    }
+}
+
+/**
+ * Runs `work` on the element, and if it panics then records that against the repo instead of taking the whole run down
+ */
+fn work_on<F>(elmt:DataElement, stage:Stage, work:&F) -> DataElement
+where
+    F: Fn(DataElement) -> DataElement,
+{
+    let original = elmt.clone();
+    match catch_unwind(AssertUnwindSafe(|| work(elmt))) {
+        Ok(updated)=>updated,
+        Err(payload)=>{
+            let msg = panic_message(payload.as_ref());
+            error!("💥 Internal error in the {} stage for {}: {}", stage, original.defn(), msg);
+            original.fail(RepoError::new(stage, ErrorKind::Internal, msg))
+        },
+    }
+}
+
+/**
+ * Runs one stage of the pipeline over all of the repos that `selected` picks out, with up to `jobs` of them at once.
+ * Repos that have been skipped are never picked.
+ * Each repo is replaced in the state by whatever `work` returns for it, and the other repos are left alone.  If `work`
+ * panics then that is recorded as an internal error against the repo, and the stage carries on with the others.
+ * Either way, how it went is added to the repo's history.
+ *
+ * Only this thread touches the state, and it is written out after each repo is done, so the state file is always a
+ * complete snapshot that a later run can resume from.  Repos keep their order in the state, whatever order they
+ * finish in.
+ */
+pub fn run_stage<S, F>(state:&mut BaseStateDefn, state_file:&Path, jobs:usize, stage:Stage, selected:S, work:F) -> Result<(), Box<dyn Error>>
+where
+    S: Fn(&DataElement) -> bool,
+    F: Fn(DataElement) -> DataElement + Sync,
+{
+    let queue:VecDeque<(usize, DataElement)> = state.data.repos.iter()
+        .enumerate()
+        .filter(|(_, elmt)| !elmt.defn().skipped && selected(elmt))
+        .map(|(i, elmt)| (i, elmt.clone()))
+        .collect();
+    if queue.is_empty() {
+        return Ok( () );
+    }
+    set_stage(Some(stage));
+
+    let workers = jobs.clamp(1, queue.len());
+    let queue = Mutex::new(queue);
+    let (tx, rx) = mpsc::channel::<(usize, DataElement, RepoEvent)>();
+
+    let result = thread::scope(|scope| {
+        for _ in 0..workers {
+            let tx = tx.clone();
+            let queue = &queue;
+            let work = &work;
+            scope.spawn(move || loop {
+                let next = queue.lock().unwrap().pop_front();
+                match next {
+                    Some((i, elmt))=>{
+                        let (at, started, repo) = (unix_now(), Instant::now(), elmt.defn().to_string());
+                        let updated = with_repo_label(repo.to_owned(), || work_on(elmt, stage, work));
+                        finish_transfer(&repo);
+                        let event = RepoEvent::new(stage, &updated, at, started.elapsed());
+                        if tx.send((i, updated, event)).is_err() {
+                            break;
+                        }
+                    },
+                    None=>break,
+                }
+            });
+        }
+        drop(tx);
+
+        //If a write fails then we stop taking results, which makes the workers stop once they finish their current repo
+        for (i, updated, event) in rx {
+            state.record_event(updated.defn(), event);
+            state.data.repos[i] = updated;
+            write_datafile(state_file, state)?;
+        }
+        Ok( () )
+    });
+    set_stage(None);
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::NamedTempFile;
+    use crate::data::{load_datafile, RepoDefn};
+    use crate::history::EventOutcome;
+
+    #[test]
+    fn test_run_stage() -> Result<(), Box<dyn Error>> {
+        let state_file = NamedTempFile::new()?;
+        let mut state = BaseStateDefn::new(vec![]);
+        let names:Vec<String> = (0..20).map(|n| format!("org/repo{}", n)).collect();
+        state.add_remote_repos(names.iter().map(|n| RepoDefn::new(n)).collect::<Result<_, _>>()?);
+
+        //give every other repo a default branch, out of order, with several workers
+        run_stage(&mut state, state_file.path(), 4, Stage::Clone,
+            |elmt| elmt.defn().name.ends_with(['0', '2', '4', '6', '8']),
+            |elmt| match elmt {
+                DataElement::RemoteRepo(mut defn)=>{
+                    std::thread::sleep(std::time::Duration::from_millis(20 - defn.name.len() as u64));
+                    defn.main_branch_name = Some("main".to_string());
+                    DataElement::RemoteRepo(defn)
+                },
+                other=>other,
+            })?;
+
+        let saved = load_datafile(state_file.path())?;
+        for (i, elmt) in saved.data.repos.iter().enumerate() {
+            assert_eq!(elmt.defn().name, format!("repo{}", i));
+            assert_eq!(elmt.defn().main_branch_name.is_some(), i % 2 == 0);
+        }
+        Ok( () )
+    }
+
+    #[test]
+    fn test_run_stage_records_panics() -> Result<(), Box<dyn Error>> {
+        let state_file = NamedTempFile::new()?;
+        let mut state = BaseStateDefn::new(vec![]);
+        state.add_remote_repos(vec![RepoDefn::new("org/fine")?, RepoDefn::new("org/broken")?]);
+
+        run_stage(&mut state, state_file.path(), 2, Stage::Clone,
+            |_| true,
+            |elmt| match elmt {
+                DataElement::RemoteRepo(defn) if defn.name=="broken"=>panic!("something went badly wrong"),
+                DataElement::RemoteRepo(mut defn)=>{
+                    defn.main_branch_name = Some("main".to_string());
+                    DataElement::RemoteRepo(defn)
+                },
+                other=>other,
+            })?;
+
+        let saved = load_datafile(state_file.path())?;
+        assert!(matches!(&saved.data.repos[0], DataElement::RemoteRepo(defn) if defn.main_branch_name.is_some()));
+        match &saved.data.repos[1] {
+            DataElement::LocalRepo(repo)=>{
+                let err = repo.last_error.as_ref().unwrap();
+                assert_eq!(err.message, "something went badly wrong");
+                assert_eq!((err.stage, err.kind, err.retryable), (Stage::Clone, ErrorKind::Internal, false));
+            },
+            other=>panic!("expected a failed LocalRepo, got {:?}", other),
+        }
+        assert_eq!(saved.history_of(saved.data.repos[0].defn())[0].outcome, EventOutcome::Succeeded);
+        let broken = saved.history_of(saved.data.repos[1].defn());
+        assert_eq!(broken.len(), 1);
+        assert_eq!((broken[0].stage, broken[0].outcome), (Stage::Clone, EventOutcome::Failed));
+        Ok( () )
+    }
+}
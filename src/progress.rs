@@ -0,0 +1,86 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::sync::Mutex;
+
+use crate::error::Stage;
+use crate::pool::current_repo;
+
+/**
+ * How far a clone or push has got, as git reports it.  For a clone `done` and `total` count objects; for a push they
+ * count the objects that have been sent.
+ */
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transfer {
+    pub stage: Stage,
+    pub done: usize,
+    pub total: usize,
+    pub bytes: usize,
+}
+
+impl Transfer {
+    pub fn percent(&self) -> usize {
+        match self.total {
+            0=>0,
+            total=>self.done * 100 / total,
+        }
+    }
+}
+
+//Sizes in the units that git itself uses when it shows progress
+fn format_bytes(bytes:usize) -> String {
+    match bytes {
+        b if b >= 1024 * 1024=>format!("{:.1} MiB", b as f64 / (1024.0 * 1024.0)),
+        b if b >= 1024=>format!("{:.1} KiB", b as f64 / 1024.0),
+        b=>format!("{} B", b),
+    }
+}
+
+impl fmt::Display for Transfer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}% ({}/{} objects, {})", self.stage, self.percent(), self.done, self.total, format_bytes(self.bytes))
+    }
+}
+
+//The stage that is running now, and the transfers that are going on in it, keyed by the repo they are for
+static STAGE: Mutex<Option<Stage>> = Mutex::new(None);
+static TRANSFERS: Mutex<BTreeMap<String, Transfer>> = Mutex::new(BTreeMap::new());
+
+pub fn set_stage(stage:Option<Stage>) {
+    *STAGE.lock().unwrap() = stage;
+}
+
+pub fn current_stage() -> Option<Stage> {
+    *STAGE.lock().unwrap()
+}
+
+/**
+ * Records how far a transfer has got, against the repo that this thread is working on.  This is called from git's
+ * progress callbacks, which are called very often, so it does as little as it can.
+ */
+pub fn report_transfer(stage:Stage, done:usize, total:usize, bytes:usize) {
+    if let Some(repo) = current_repo() {
+        TRANSFERS.lock().unwrap().insert(repo, Transfer { stage, done, total, bytes });
+    }
+}
+
+pub fn finish_transfer(repo:&str) {
+    TRANSFERS.lock().unwrap().remove(repo);
+}
+
+//The transfers that are going on now
+pub fn transfers() -> BTreeMap<String, Transfer> {
+    TRANSFERS.lock().unwrap().clone()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_transfer_display() {
+        let clone = Transfer { stage: Stage::Clone, done: 117, total: 260, bytes: 1258291 };
+        assert_eq!(clone.to_string(), "clone 45% (117/260 objects, 1.2 MiB)");
+        let push = Transfer { stage: Stage::Push, done: 0, total: 0, bytes: 0 };
+        assert_eq!(push.to_string(), "push 0% (0/0 objects, 0 B)");
+    }
+}
@@ -0,0 +1,105 @@
+use std::error::Error;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use octorust::ClientError;
+
+//How long to back off for when Github says we hit a secondary rate limit but doesn't say for how long
+const DEFAULT_SECONDARY_WAIT:Duration = Duration::from_secs(60);
+
+pub fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or_default()
+}
+
+/**
+ * Works out how long to wait from a failed response.  `Retry-After` is used if it is there (secondary rate limits),
+ * then `X-RateLimit-Reset` if we have run out of requests (the primary rate limit).  Returns None if the response
+ * was not a rate limit at all.
+ */
+fn wait_from_response(status:u16, header:&dyn Fn(&str) -> Option<String>, body:&str, now:u64) -> Option<Duration> {
+    if let Some(secs) = header("retry-after").and_then(|v| v.trim().parse::<u64>().ok()) {
+        return Some(Duration::from_secs(secs.max(1)));
+    }
+
+    let remaining = header("x-ratelimit-remaining").and_then(|v| v.trim().parse::<u64>().ok());
+    let reset = header("x-ratelimit-reset").and_then(|v| v.trim().parse::<u64>().ok());
+    match (remaining, reset) {
+        (Some(0), Some(reset))=>Some(Duration::from_secs(reset.saturating_sub(now).max(1))),
+        _ if status==429 || (status==403 && body.to_lowercase().contains("rate limit"))=>Some(DEFAULT_SECONDARY_WAIT),
+        _=>None,
+    }
+}
+
+/**
+ * If the error is Github telling us that we have been rate limited, returns how long we should wait before trying again
+ */
+pub fn rate_limit_wait(err:&(dyn Error + 'static)) -> Option<Duration> {
+    match err.downcast_ref::<ClientError>()? {
+        ClientError::RateLimited{duration}=>Some(Duration::from_secs((*duration).max(1))),
+        ClientError::HttpError{status, headers, error}=>{
+            let header = |name:&str| headers.get(name).and_then(|v| v.to_str().ok()).map(|v| v.to_string());
+            wait_from_response(status.as_u16(), &header, error, unix_now())
+        },
+        _=>None,
+    }
+}
+
+/**
+ * Shared between all of the PR creation tasks, so that when one of them is rate limited they all hold off rather than
+ * carrying on and making it worse
+ */
+#[derive(Default)]
+pub struct RateLimiter {
+    paused_until: Mutex<Option<Instant>>,
+}
+
+impl RateLimiter {
+    pub fn pause_for(&self, wait:Duration) {
+        let until = Instant::now() + wait;
+        let mut paused_until = self.paused_until.lock().unwrap();
+        if paused_until.map(|current| current < until).unwrap_or(true) {
+            *paused_until = Some(until);
+        }
+    }
+
+    //Returns once any pause that is in force has finished
+    pub async fn wait(&self) {
+        loop {
+            let until = *self.paused_until.lock().unwrap();
+            match until {
+                Some(until) if until > Instant::now()=>tokio::time::sleep_until(until.into()).await,
+                _=>return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn headers(values:&[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let map:HashMap<String, String> = values.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        move |name| map.get(name).cloned()
+    }
+
+    #[test]
+    fn test_wait_from_response() {
+        let now = 1_700_000_000;
+        assert_eq!(wait_from_response(403, &headers(&[("retry-after", "30")]), "", now), Some(Duration::from_secs(30)));
+        assert_eq!(wait_from_response(403, &headers(&[("x-ratelimit-remaining", "0"), ("x-ratelimit-reset", "1700000090")]), "", now), Some(Duration::from_secs(90)));
+        assert_eq!(wait_from_response(403, &headers(&[("x-ratelimit-remaining", "0"), ("x-ratelimit-reset", "1699999990")]), "", now), Some(Duration::from_secs(1)));
+        assert_eq!(wait_from_response(403, &headers(&[]), "You have exceeded a secondary rate limit", now), Some(DEFAULT_SECONDARY_WAIT));
+        assert_eq!(wait_from_response(429, &headers(&[]), "", now), Some(DEFAULT_SECONDARY_WAIT));
+        assert_eq!(wait_from_response(403, &headers(&[("x-ratelimit-remaining", "4000")]), "Resource not accessible by integration", now), None);
+        assert_eq!(wait_from_response(422, &headers(&[]), "A pull request already exists", now), None);
+    }
+
+    #[test]
+    fn test_rate_limit_wait() {
+        let limited:Box<dyn Error> = Box::new(ClientError::RateLimited{duration: 12});
+        assert_eq!(rate_limit_wait(limited.as_ref()), Some(Duration::from_secs(12)));
+        let other:Box<dyn Error> = Box::from("something else");
+        assert_eq!(rate_limit_wait(other.as_ref()), None);
+    }
+}
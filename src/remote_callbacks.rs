@@ -1,4 +1,6 @@
 use crate::data::{homedir, CloneMode, ConfigFile};
+use crate::error::Stage;
+use crate::progress::report_transfer;
 use git2::RemoteCallbacks;
 use log::{debug, info};
 use std::path::{Path, PathBuf};
@@ -26,14 +28,14 @@ pub fn configure_callbacks<'a>(mode:Option<&'a CloneMode>, app_config:&ConfigFil
             git2::Cred::username(user)
         } else {
             debug!("Invoking credential helper for {}...", url);
-            match git2::Cred::credential_helper(&config, &url, Some(user)) {
+            match git2::Cred::credential_helper(&config, url, Some(user)) {
                 success @ Ok(_)=>success,
                 Err(e)=>{
                     debug!("Credential helper returned an error: {}. Trying own auth...", e);
                     match mode {
                         Some(CloneMode::Ssh)=>git_ssh_auth(user, maybe_ssh_key.as_ref()),
                         Some(CloneMode::Https)=>match &maybe_access_token {
-                            Some(tok)=>git2::Cred::userpass_plaintext(user, &tok),
+                            Some(tok)=>git2::Cred::userpass_plaintext(user, tok),
                             None=>Err( git2::Error::from_str("There is no access token configured for push :(") )
                         },
                         None=>Err( git2::Error::from_str("The URL was not recognised"))
@@ -43,6 +45,15 @@ pub fn configure_callbacks<'a>(mode:Option<&'a CloneMode>, app_config:&ConfigFil
         }
     });
 
+    //so that the dashboard can show how far clones and pushes have got
+    callbacks.transfer_progress(|stats| {
+        report_transfer(Stage::Clone, stats.received_objects(), stats.total_objects(), stats.received_bytes());
+        true
+    });
+    callbacks.push_transfer_progress(|current, total, bytes| {
+        report_transfer(Stage::Push, current, total, bytes);
+    });
+
     callbacks
 }
 
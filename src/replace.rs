@@ -0,0 +1,190 @@
+use std::error::Error;
+use std::fmt::Write as _;
+use std::fs;
+use std::path::Path;
+use git2::Repository;
+use regex::Regex;
+use log::debug;
+
+/**
+ * A find-and-replace across the files of a repo.  `pattern` is a regex, and `replacement` can refer to its
+ * capture groups as $1 or ${name}.
+ */
+#[derive(Debug, Clone)]
+pub struct ReplaceSpec {
+    pub globs: Vec<String>,
+    pub pattern: String,
+    pub replacement: String,
+}
+
+/**
+ * Converts a glob into a regex that matches paths relative to the repo root.  `**` matches any number of directories,
+ * `*` and `?` don't cross a `/`, and `{a,b}` matches either alternative.  A glob without a `/` in it is matched against the
+ * file name, wherever it is in the repo (like .gitignore does).
+ */
+pub fn glob_to_regex(glob:&str) -> Result<Regex, Box<dyn Error>> {
+    let anchored = glob.contains('/');
+    let glob = glob.trim_start_matches('/');
+    let chars:Vec<char> = glob.chars().collect();
+
+    let mut re = String::from(if anchored { "^" } else { "^(?:.*/)?" });
+    let mut in_braces = false;
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '*' if chars.get(i+1)==Some(&'*') => {
+                if chars.get(i+2)==Some(&'/') {
+                    re.push_str("(?:.*/)?");
+                    i += 2;
+                } else {
+                    re.push_str(".*");
+                    i += 1;
+                }
+            },
+            '*'=>re.push_str("[^/]*"),
+            '?'=>re.push_str("[^/]"),
+            '['=>{
+                let end = chars[i..].iter().position(|c| *c==']').map(|p| p + i).ok_or_else(|| format!("unclosed [ in glob {}", glob))?;
+                let class:String = chars[i+1..end].iter().collect();
+                let class = class.strip_prefix('!').map(|c| format!("^{}", c)).unwrap_or(class);
+                re.push('[');
+                re.push_str(&class);
+                re.push(']');
+                i = end;
+            },
+            '{'=>{
+                in_braces = true;
+                re.push_str("(?:");
+            },
+            '}' if in_braces => {
+                in_braces = false;
+                re.push(')');
+            },
+            ',' if in_braces => re.push('|'),
+            other=>re.push_str(&regex::escape(&other.to_string())),
+        }
+        i += 1;
+    }
+    re.push('$');
+    Ok(Regex::new(&re)?)
+}
+
+//Lists the files in the repo's working directory, relative to the root, leaving out anything that git ignores
+pub fn list_files(repo:&Repository, root:&Path, dir:&Path, into:&mut Vec<String>) -> Result<(), Box<dyn Error>> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let relative = path.strip_prefix(root)?;
+        let file_type = entry.file_type()?;
+
+        if relative==Path::new(".git") || file_type.is_symlink() {
+            continue;
+        }
+        if repo.is_path_ignored(relative)? {
+            debug!("Skipping ignored path {}", relative.display());
+            continue;
+        }
+
+        if file_type.is_dir() {
+            list_files(repo, root, &path, into)?;
+        } else if let Some(relative_str) = relative.to_str() {
+            into.push(relative_str.replace('\\', "/"));
+        }
+    }
+    Ok( () )
+}
+
+/**
+ * Runs the find-and-replace over every file in the repo that matches one of the globs.  Files that git ignores, and files
+ * that are not valid UTF-8, are left alone.  Returns a description of how many matches were replaced in each file.
+ */
+pub fn apply_replace(spec:&ReplaceSpec, repo:&Repository) -> Result<String, Box<dyn Error>> {
+    let root = repo.workdir().ok_or("the repo has no working directory")?;
+    let pattern = Regex::new(&spec.pattern)?;
+    let globs = spec.globs.iter().map(|g| glob_to_regex(g)).collect::<Result<Vec<Regex>, Box<dyn Error>>>()?;
+
+    let mut files:Vec<String> = vec![];
+    list_files(repo, root, root, &mut files)?;
+    files.sort();
+
+    let mut msg = String::new();
+    let mut total_matches = 0;
+    let mut changed_files = 0;
+    for file in files.iter().filter(|f| globs.iter().any(|g| g.is_match(f))) {
+        let full_path = root.join(file);
+        let content = match fs::read_to_string(&full_path) {
+            Ok(content)=>content,
+            Err(_)=>{
+                debug!("Skipping {} as it is not text", file);
+                continue;
+            }
+        };
+
+        let matches = pattern.find_iter(&content).count();
+        if matches > 0 {
+            let updated = pattern.replace_all(&content, spec.replacement.as_str());
+            fs::write(&full_path, updated.as_bytes())?;
+            writeln!(msg, "{}: {} matches", file, matches)?;
+            total_matches += matches;
+            changed_files += 1;
+        }
+    }
+    write!(msg, "{} matches replaced in {} files", total_matches, changed_files)?;
+    Ok(msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn test_glob_to_regex() -> Result<(), Box<dyn Error>> {
+        let yaml = glob_to_regex("*.yml")?;
+        assert!(yaml.is_match("ci.yml"));
+        assert!(yaml.is_match(".github/workflows/ci.yml"));
+        assert!(!yaml.is_match("ci.yml.bak"));
+
+        let workflows = glob_to_regex(".github/workflows/*.{yml,yaml}")?;
+        assert!(workflows.is_match(".github/workflows/ci.yaml"));
+        assert!(!workflows.is_match(".github/workflows/nested/ci.yml"));
+        assert!(!workflows.is_match("other/.github/workflows/ci.yml"));
+
+        let deep = glob_to_regex("src/**/*.rs")?;
+        assert!(deep.is_match("src/main.rs"));
+        assert!(deep.is_match("src/a/b/lib.rs"));
+        assert!(!deep.is_match("tests/main.rs"));
+
+        let class = glob_to_regex("file[0-9].txt")?;
+        assert!(class.is_match("file1.txt"));
+        assert!(!class.is_match("filea.txt"));
+        Ok( () )
+    }
+
+    #[test]
+    fn test_apply_replace() -> Result<(), Box<dyn Error>> {
+        let dir = TempDir::new()?;
+        let repo = Repository::init(dir.path())?;
+        fs::create_dir_all(dir.path().join(".github/workflows"))?;
+        fs::create_dir_all(dir.path().join("build"))?;
+        fs::write(dir.path().join(".gitignore"), "build/\n")?;
+        fs::write(dir.path().join(".github/workflows/ci.yml"), "steps:\n  - uses: actions/checkout@v2\n  - uses: actions/setup-node@v2\n")?;
+        fs::write(dir.path().join("build/ci.yml"), "uses: actions/checkout@v2\n")?;
+        fs::write(dir.path().join("README.md"), "uses: actions/checkout@v2\n")?;
+
+        let spec = ReplaceSpec {
+            globs: vec!["*.yml".to_string()],
+            pattern: r"uses: actions/([\w-]+)@v2".to_string(),
+            replacement: "uses: actions/${1}@v4".to_string(),
+        };
+        let msg = apply_replace(&spec, &repo)?;
+
+        assert_eq!(fs::read_to_string(dir.path().join(".github/workflows/ci.yml"))?, "steps:\n  - uses: actions/checkout@v4\n  - uses: actions/setup-node@v4\n");
+        //ignored by .gitignore
+        assert_eq!(fs::read_to_string(dir.path().join("build/ci.yml"))?, "uses: actions/checkout@v2\n");
+        //doesn't match the glob
+        assert_eq!(fs::read_to_string(dir.path().join("README.md"))?, "uses: actions/checkout@v2\n");
+        assert_eq!(msg, ".github/workflows/ci.yml: 2 matches\n2 matches replaced in 1 files");
+        Ok( () )
+    }
+}
@@ -0,0 +1,290 @@
+use std::fmt::{self, Write};
+use clap::ValueEnum;
+
+use crate::data::{BaseStateDefn, DataElement};
+use crate::status::{changes, failed_stage, last_error, pr_url, status_text, RepoStatus};
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum ReportFormat {
+    Markdown,
+    Csv,
+    Html,
+    Junit,
+}
+
+/**
+ * One row of the report.  All of the formats have the same information in them, just laid out differently.
+ */
+struct ReportRow {
+    repo: String,
+    status: RepoStatus,
+    status_text: String,
+    changes: Option<usize>,
+    //the files that matched the discovery search, if the repo was found by one
+    search_matches: Vec<String>,
+    pr_url: Option<String>,
+    error: Option<String>,
+}
+
+impl ReportRow {
+    fn new(elmt:&DataElement) -> ReportRow {
+        ReportRow {
+            repo: elmt.defn().to_string(),
+            status: RepoStatus::of(elmt),
+            status_text: status_text(elmt),
+            changes: changes(elmt),
+            search_matches: elmt.defn().search_matches.to_owned(),
+            pr_url: pr_url(elmt).map(|u| u.to_string()),
+            error: last_error(elmt),
+        }
+    }
+
+    fn changes_text(&self) -> String {
+        self.changes.map(|c| c.to_string()).unwrap_or_default()
+    }
+}
+
+//Puts a value in a Markdown table cell, where pipes and line breaks would break the table
+fn markdown_cell(value:&str) -> String {
+    value.trim().replace('|', "\\|").replace('\n', "<br>")
+}
+
+fn write_markdown(out:&mut String, rows:&[ReportRow]) -> fmt::Result {
+    writeln!(out, "| Repo | Stage | Changes | Search matches | Pull request | Error |")?;
+    writeln!(out, "| --- | --- | --- | --- | --- | --- |")?;
+    for row in rows {
+        writeln!(out, "| {} | {} | {} | {} | {} | {} |",
+            markdown_cell(&row.repo),
+            markdown_cell(&row.status_text),
+            row.changes_text(),
+            markdown_cell(&row.search_matches.iter().map(|m| format!("`{}`", m)).collect::<Vec<String>>().join("\n")),
+            //PR URLs end in the PR number, which makes a tidier link
+            row.pr_url.as_ref().map(|u| format!("[#{}]({})", u.rsplit('/').next().unwrap_or_default(), u)).unwrap_or_default(),
+            markdown_cell(row.error.as_deref().unwrap_or_default()),
+        )?;
+    }
+    Ok( () )
+}
+
+//Quotes a CSV field if it needs it, as RFC 4180 says
+fn csv_field(value:&str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn write_csv(out:&mut String, rows:&[ReportRow]) -> fmt::Result {
+    writeln!(out, "repo,stage,changes,search_matches,pr_url,error")?;
+    for row in rows {
+        writeln!(out, "{},{},{},{},{},{}",
+            csv_field(&row.repo),
+            csv_field(&row.status_text),
+            row.changes_text(),
+            csv_field(&row.search_matches.join(";")),
+            csv_field(row.pr_url.as_deref().unwrap_or_default()),
+            csv_field(row.error.as_deref().unwrap_or_default().trim()),
+        )?;
+    }
+    Ok( () )
+}
+
+//Escapes text for HTML and XML, including in attributes
+fn escape_xml(value:&str) -> String {
+    value.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+const HTML_STYLE:&str = "body { font-family: sans-serif; margin: 2em; }
+table { border-collapse: collapse; margin-bottom: 2em; }
+th, td { border: 1px solid #ccc; padding: 0.3em 0.6em; text-align: left; vertical-align: top; }
+th { background: #f0f0f0; }
+td.error { white-space: pre-wrap; font-family: monospace; }
+tr.failed { background: #fde8e8; }
+tr.prd { background: #e8f6e8; }
+tr.no-changes { color: #777; }";
+
+/**
+ * A page with everything it needs in it, so that it can be attached to a ticket or dropped on a file share
+ */
+fn write_html(out:&mut String, rows:&[ReportRow]) -> fmt::Result {
+    writeln!(out, "<!DOCTYPE html>")?;
+    writeln!(out, "<html>\n<head>\n<meta charset=\"utf-8\">\n<title>batchpatch report</title>\n<style>\n{}\n</style>\n</head>\n<body>", HTML_STYLE)?;
+    writeln!(out, "<h1>batchpatch report</h1>")?;
+
+    writeln!(out, "<table>\n<tr><th>Stage</th><th>Repos</th></tr>")?;
+    for status in RepoStatus::ALL {
+        let count = rows.iter().filter(|r| r.status==status).count();
+        if count>0 {
+            writeln!(out, "<tr><td>{}</td><td>{}</td></tr>", escape_xml(&status.to_string()), count)?;
+        }
+    }
+    writeln!(out, "<tr><th>Total</th><th>{}</th></tr>\n</table>", rows.len())?;
+
+    writeln!(out, "<table>\n<tr><th>Repo</th><th>Stage</th><th>Changes</th><th>Search matches</th><th>Pull request</th><th>Error</th></tr>")?;
+    for row in rows {
+        let class = match row.status {
+            RepoStatus::Failed=>"failed",
+            RepoStatus::PRd=>"prd",
+            RepoStatus::NoChanges=>"no-changes",
+            _=>"",
+        };
+        let pr = row.pr_url.as_ref()
+            .map(|u| format!("<a href=\"{}\">{}</a>", escape_xml(u), escape_xml(u)))
+            .unwrap_or_default();
+        let matches = row.search_matches.iter().map(|m| escape_xml(m)).collect::<Vec<String>>().join("<br>");
+        writeln!(out, "<tr class=\"{}\"><td><a href=\"https://github.com/{}\">{}</a></td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td class=\"error\">{}</td></tr>",
+            class,
+            escape_xml(&row.repo),
+            escape_xml(&row.repo),
+            escape_xml(&row.status_text),
+            row.changes_text(),
+            matches,
+            pr,
+            escape_xml(row.error.as_deref().unwrap_or_default().trim()),
+        )?;
+    }
+    writeln!(out, "</table>\n</body>\n</html>")?;
+    Ok( () )
+}
+
+//The search matches for a test case, as properties, or nothing if there weren't any
+fn junit_properties(row:&ReportRow) -> String {
+    if row.search_matches.is_empty() {
+        return String::new();
+    }
+    let mut out = String::from("      <properties>\n");
+    for path in row.search_matches.iter() {
+        let _ = writeln!(out, "        <property name=\"search_match\" value=\"{}\"/>", escape_xml(path));
+    }
+    out.push_str("      </properties>\n");
+    out
+}
+
+/**
+ * One test case per repo.  Repos that failed are failures, and repos that are still part way through (or were never
+ * pushed because of --no-push) are skipped; the rest passed.
+ */
+fn write_junit(out:&mut String, rows:&[ReportRow], elmts:&[DataElement]) -> fmt::Result {
+    let failures = rows.iter().filter(|r| r.status==RepoStatus::Failed).count();
+    let skipped = rows.iter().filter(|r| !matches!(r.status, RepoStatus::Failed | RepoStatus::PRd | RepoStatus::NoChanges)).count();
+
+    writeln!(out, "<?xml version=\"1.0\" encoding=\"UTF-8\"?>")?;
+    writeln!(out, "<testsuites name=\"batchpatch\" tests=\"{}\" failures=\"{}\" skipped=\"{}\">", rows.len(), failures, skipped)?;
+    writeln!(out, "  <testsuite name=\"batchpatch\" tests=\"{}\" failures=\"{}\" errors=\"0\" skipped=\"{}\">", rows.len(), failures, skipped)?;
+    for (row, elmt) in rows.iter().zip(elmts) {
+        let defn = elmt.defn();
+        let name = format!("name=\"{}\" classname=\"{}\"", escape_xml(&row.repo), escape_xml(&defn.owner));
+        let properties = junit_properties(row);
+        match row.status {
+            RepoStatus::Failed=>{
+                let stage = failed_stage(elmt).map(|s| s.to_string()).unwrap_or("unknown".to_string());
+                let error = row.error.as_deref().unwrap_or_default().trim();
+                writeln!(out, "    <testcase {}>", name)?;
+                out.push_str(&properties);
+                writeln!(out, "      <failure type=\"{}\" message=\"{}\">{}</failure>",
+                    escape_xml(&stage),
+                    escape_xml(error.lines().next().unwrap_or_default()),
+                    escape_xml(error))?;
+                writeln!(out, "    </testcase>")?;
+            },
+            RepoStatus::PRd=>{
+                writeln!(out, "    <testcase {}>", name)?;
+                out.push_str(&properties);
+                writeln!(out, "      <system-out>{}</system-out>", escape_xml(row.pr_url.as_deref().unwrap_or_default()))?;
+                writeln!(out, "    </testcase>")?;
+            },
+            RepoStatus::NoChanges if properties.is_empty()=>writeln!(out, "    <testcase {}/>", name)?,
+            RepoStatus::NoChanges=>write!(out, "    <testcase {}>\n{}    </testcase>\n", name, properties)?,
+            status=>{
+                writeln!(out, "    <testcase {}>", name)?;
+                out.push_str(&properties);
+                writeln!(out, "      <skipped message=\"{}\"/>", escape_xml(&format!("stopped at {}", status)))?;
+                writeln!(out, "    </testcase>")?;
+            },
+        }
+    }
+    writeln!(out, "  </testsuite>")?;
+    writeln!(out, "</testsuites>")?;
+    Ok( () )
+}
+
+/**
+ * Writes out how every repo in the campaign got on, in the given format
+ */
+pub fn render_report(state:&BaseStateDefn, format:ReportFormat) -> Result<String, fmt::Error> {
+    let rows:Vec<ReportRow> = state.data.repos.iter().map(ReportRow::new).collect();
+    let mut out = String::new();
+    match format {
+        ReportFormat::Markdown=>write_markdown(&mut out, &rows)?,
+        ReportFormat::Csv=>write_csv(&mut out, &rows)?,
+        ReportFormat::Html=>write_html(&mut out, &rows)?,
+        ReportFormat::Junit=>write_junit(&mut out, &rows, &state.data.repos)?,
+    }
+    Ok( out )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::status::test;
+
+    //The test state, with the PR'd repo having been found by a code search
+    fn test_state() -> BaseStateDefn {
+        let mut state = test::test_state();
+        if let DataElement::PRdRepo(done) = &mut state.data.repos[4] {
+            done.branched.patched.repo.defn.search_matches = vec!["src/main.rs".to_string(), "lib/a|b.rs".to_string()];
+        }
+        state
+    }
+
+    #[test]
+    fn test_markdown() -> Result<(), fmt::Error> {
+        let report = render_report(&test_state(), ReportFormat::Markdown)?;
+        let lines:Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "| Repo | Stage | Changes | Search matches | Pull request | Error |");
+        assert_eq!(lines[2], "| org/remote | remote |  |  |  |  |");
+        assert_eq!(lines[5], "| org/unpushed | failed (push) | 2 |  |  | push failed (authentication error): denied<br>more detail |");
+        assert_eq!(lines[6], "| org/done | PR'd | 2 | `src/main.rs`<br>`lib/a\\|b.rs` | [#1](https://github.com/org/done/pull/1) |  |");
+        Ok( () )
+    }
+
+    #[test]
+    fn test_csv() -> Result<(), fmt::Error> {
+        let report = render_report(&test_state(), ReportFormat::Csv)?;
+        let lines:Vec<&str> = report.lines().collect();
+        assert_eq!(lines[0], "repo,stage,changes,search_matches,pr_url,error");
+        assert_eq!(lines[2], "org/unchanged,no changes,0,,,");
+        assert_eq!(lines[4], "org/unpushed,failed (push),2,,,\"push failed (authentication error): denied");
+        assert_eq!(lines[5], "more detail\"");
+        assert_eq!(lines[6], "org/done,PR'd,2,src/main.rs;lib/a|b.rs,https://github.com/org/done/pull/1,");
+        assert_eq!(csv_field("say \"hi\", then"), "\"say \"\"hi\"\", then\"");
+        Ok( () )
+    }
+
+    #[test]
+    fn test_html() -> Result<(), fmt::Error> {
+        let report = render_report(&test_state(), ReportFormat::Html)?;
+        assert!(report.starts_with("<!DOCTYPE html>"));
+        assert!(report.contains("<tr class=\"failed\"><td><a href=\"https://github.com/org/unpushed\">org/unpushed</a></td><td>failed (push)</td>"));
+        assert!(report.contains("<a href=\"https://github.com/org/done/pull/1\">"));
+        assert!(report.contains("<td>src/main.rs<br>lib/a|b.rs</td>"));
+        assert_eq!(escape_xml("<a href=\"x\">&</a>"), "&lt;a href=&quot;x&quot;&gt;&amp;&lt;/a&gt;");
+        Ok( () )
+    }
+
+    #[test]
+    fn test_junit() -> Result<(), fmt::Error> {
+        let report = render_report(&test_state(), ReportFormat::Junit)?;
+        assert!(report.contains("<testsuite name=\"batchpatch\" tests=\"5\" failures=\"1\" errors=\"0\" skipped=\"2\">"));
+        assert!(report.contains("<testcase name=\"org/unchanged\" classname=\"org\"/>"));
+        assert!(report.contains("<failure type=\"push\" message=\"push failed (authentication error): denied\">push failed (authentication error): denied\nmore detail</failure>"));
+        assert!(report.contains("<skipped message=\"stopped at committed\"/>"));
+        assert!(report.contains("      <properties>\n        <property name=\"search_match\" value=\"src/main.rs\"/>\n        <property name=\"search_match\" value=\"lib/a|b.rs\"/>\n      </properties>\n      <system-out>"));
+        Ok( () )
+    }
+}
@@ -0,0 +1,393 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use git2::Repository;
+use log::{info, warn};
+
+use crate::data::{BaseStateDefn, DataElement, LocalRepo, RepoDefn};
+use crate::error::Stage;
+use crate::gitutils::{clean_repo, delete_branch, detect_default_branch};
+use crate::history::{EventOutcome, RepoEvent};
+use crate::ratelimit::unix_now;
+use crate::status::{failed_stage, status_text, RepoStatus};
+
+//Puts the clone back to its default branch, without any of the changes that the patch made
+fn clean_clone(repo:&LocalRepo) -> Result<(), Box<dyn Error>> {
+    let repo_ref = Repository::open(&repo.local_path)?;
+    let branch = match repo.defn.main_branch_name.as_ref() {
+        Some(branch)=>branch.to_owned(),
+        None=>detect_default_branch(&repo_ref)?,
+    };
+    clean_repo(&repo_ref, &branch, true)
+}
+
+/**
+ * Gets a repo that failed ready to go through the stage that it failed at again on the next run, whatever the error
+ * was.  A clone is started again from scratch, and a patch is run again on a clean clone.  Retrying a skipped repo
+ * stops it being skipped.  It is an error to retry a repo that has neither failed nor been skipped.
+ */
+pub fn retry_repo(elmt:DataElement) -> Result<DataElement, Box<dyn Error>> {
+    let stage = failed_stage(&elmt);
+    if stage.is_none() && !elmt.defn().skipped {
+        return Err(Box::from(format!("{} has not failed, so there is nothing to retry", elmt.defn())));
+    }
+    let mut retried = match (elmt, stage) {
+        (elmt, None)=>elmt,
+        (DataElement::LocalRepo(repo), _)=>DataElement::RemoteRepo(repo.defn),
+        (DataElement::PatchedRepo(patched), _)=>{
+            clean_clone(&patched.repo)?;
+            DataElement::LocalRepo(LocalRepo { last_error: None, ..patched.repo })
+        },
+        //the branch was never made, so it has to be made again; later stages carry on from the branch that is there
+        (DataElement::BranchedRepo(branched), Some(Stage::Branch))=>DataElement::PatchedRepo(branched.patched),
+        (DataElement::BranchedRepo(mut branched), _)=>{
+            branched.last_error = None;
+            branched.rate_limited_until = None;
+            DataElement::BranchedRepo(branched)
+        },
+        (elmt, _)=>elmt,
+    };
+    set_skipped(&mut retried, false);
+    info!("🔁 {} will be tried again", retried.defn());
+    Ok( retried )
+}
+
+fn set_skipped(elmt:&mut DataElement, skipped:bool) {
+    match elmt {
+        DataElement::PRdRepo(repo)=>repo.branched.patched.repo.defn.skipped = skipped,
+        DataElement::BranchedRepo(repo)=>repo.patched.repo.defn.skipped = skipped,
+        DataElement::PatchedRepo(repo)=>repo.repo.defn.skipped = skipped,
+        DataElement::LocalRepo(repo)=>repo.defn.skipped = skipped,
+        DataElement::RemoteRepo(defn)=>defn.skipped = skipped,
+    }
+}
+
+/**
+ * Stops the repo going any further.  It is left as it is, so retrying it later carries on from where it got to.
+ */
+pub fn skip_repo(mut elmt:DataElement) -> DataElement {
+    set_skipped(&mut elmt, true);
+    info!("⏭️ {} will be skipped", elmt.defn());
+    elmt
+}
+
+fn local_of(elmt:&DataElement) -> Option<&LocalRepo> {
+    match elmt {
+        DataElement::PRdRepo(repo)=>Some(&repo.branched.patched.repo),
+        DataElement::BranchedRepo(repo)=>Some(&repo.patched.repo),
+        DataElement::PatchedRepo(repo)=>Some(&repo.repo),
+        DataElement::LocalRepo(repo)=>Some(repo),
+        DataElement::RemoteRepo(_)=>None,
+    }
+}
+
+//The branch that we made in the repo's clone, if we have got that far
+fn branch_of(elmt:&DataElement) -> Option<&str> {
+    match elmt {
+        DataElement::PRdRepo(repo)=>Some(&repo.branched.branch_name),
+        DataElement::BranchedRepo(repo)=>Some(&repo.branch_name),
+        _=>None,
+    }
+}
+
+//The last stage that the repo got through, if it has got through any
+fn last_stage_done(elmt:&DataElement) -> Option<Stage> {
+    match elmt {
+        DataElement::RemoteRepo(_)=>None,
+        DataElement::LocalRepo(repo) if repo.is_failed()=>None,
+        DataElement::LocalRepo(_)=>Some(Stage::Clone),
+        DataElement::PatchedRepo(repo) if !repo.success || repo.error.is_some()=>Some(Stage::Clone),
+        DataElement::PatchedRepo(_)=>Some(Stage::Patch),
+        DataElement::BranchedRepo(repo) if repo.pushed=>Some(Stage::Push),
+        DataElement::BranchedRepo(repo) if repo.committed=>Some(Stage::Commit),
+        DataElement::BranchedRepo(repo) if repo.last_error.as_ref().is_some_and(|e| e.stage==Stage::Branch)=>Some(Stage::Patch),
+        DataElement::BranchedRepo(_)=>Some(Stage::Branch),
+        DataElement::PRdRepo(_)=>Some(Stage::PullRequest),
+    }
+}
+
+//The stage that the repo goes through next, or the PR stage if it has been through all of them
+fn next_stage(elmt:&DataElement) -> Stage {
+    match last_stage_done(elmt) {
+        None=>Stage::Clone,
+        Some(Stage::Clone)=>Stage::Patch,
+        Some(Stage::Patch)=>Stage::Branch,
+        Some(Stage::Branch)=>Stage::Commit,
+        Some(Stage::Commit)=>Stage::Push,
+        Some(Stage::Push | Stage::PullRequest)=>Stage::PullRequest,
+    }
+}
+
+/**
+ * Takes the repo back to just before the given stage, so that it goes through that stage and the ones after it again
+ * on the next run.  Going back to the clone removes the clone, and going back to the patch cleans the clone and deletes
+ * the branch that we made in it.  A commit can't be taken back out again, so once a repo has been committed it can only
+ * go back as far as the patch, or forward from the push.  Once the branch has been pushed it can only go forward from
+ * the push, as a new branch couldn't be pushed over the one on the remote.  Resetting a skipped repo stops it being
+ * skipped.
+ */
+pub fn reset_repo(elmt:DataElement, to:Stage) -> Result<DataElement, Box<dyn Error>> {
+    let defn = elmt.defn().to_string();
+    let done = last_stage_done(&elmt);
+    let next = done.map(|done| done as usize + 1).unwrap_or(0);
+    if to as usize > next {
+        return Err(Box::from(format!("{} has not got as far as the {} stage", defn, to)));
+    }
+    if to < Stage::Push && done.is_some_and(|done| done >= Stage::Push) {
+        let (instead, tidy_up) = match done {
+            Some(Stage::PullRequest)=>("pr", "close the PR, delete the remote branch"),
+            _=>("push", "delete the remote branch"),
+        };
+        return Err(Box::from(format!("{} has been {}, so a new branch made at the {} stage would clash with the one on the remote; reset it to {} instead, or {} and remove it from the state",
+            defn, status_text(&elmt), to, instead, tidy_up)));
+    }
+
+    let mut reset = match (to, elmt) {
+        (Stage::Clone, elmt)=>{
+            if let Some(local) = local_of(&elmt).filter(|local| local.local_path.exists()) {
+                info!("🪓 Removing the clone at {}", local.local_path.display());
+                fs::remove_dir_all(&local.local_path)?;
+            }
+            DataElement::RemoteRepo(elmt.defn().clone())
+        },
+        (Stage::Patch, elmt)=>{
+            let local = local_of(&elmt).ok_or(format!("{} has not been cloned", defn))?.clone();
+            clean_clone(&local)?;
+            if let Some(branch) = branch_of(&elmt) {
+                delete_branch(&Repository::open(&local.local_path)?, branch)?;
+            }
+            DataElement::LocalRepo(LocalRepo { last_error: None, ..local })
+        },
+        (Stage::Branch, DataElement::PatchedRepo(patched))=>DataElement::PatchedRepo(patched),
+        (Stage::Branch, DataElement::BranchedRepo(branched)) if !branched.committed=>{
+            delete_branch(&Repository::open(&branched.patched.repo.local_path)?, &branched.branch_name)?;
+            DataElement::PatchedRepo(branched.patched)
+        },
+        (Stage::Commit | Stage::Push | Stage::PullRequest, DataElement::BranchedRepo(mut branched)) if to > Stage::Commit || !branched.committed=>{
+            branched.last_error = None;
+            branched.rate_limited_until = None;
+            if to <= Stage::Push {
+                branched.pushed = false;
+                branched.pushed_ref = None;
+            }
+            DataElement::BranchedRepo(branched)
+        },
+        (Stage::PullRequest, DataElement::PRdRepo(prd))=>{
+            warn!("⚠️ The PR for {} at {} is still open on Github, close it if you want a new one to be made", defn, prd.url);
+            let mut branched = prd.branched;
+            branched.last_error = None;
+            branched.rate_limited_until = None;
+            branched.pr_attempts = 0;
+            DataElement::BranchedRepo(branched)
+        },
+        (stage, elmt)=>return Err(Box::from(format!("{} has been {}, which can't be undone back to the {} stage; reset it to the patch stage instead",
+            defn, status_text(&elmt), stage))),
+    };
+    set_skipped(&mut reset, false);
+    info!("⏪ {} will go through the {} stage again", reset.defn(), to);
+    Ok( reset )
+}
+
+/**
+ * What the retry, reset, skip and remove commands do to each of the repos that they are given
+ */
+#[derive(Debug, Clone, Copy)]
+pub enum RepoAction {
+    Retry,
+    Reset(Stage),
+    Skip,
+    Remove,
+}
+
+impl fmt::Display for RepoAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RepoAction::Retry=>f.write_str("retried"),
+            RepoAction::Reset(stage)=>write!(f, "reset to the {} stage", stage),
+            RepoAction::Skip=>f.write_str("skipped"),
+            RepoAction::Remove=>f.write_str("removed"),
+        }
+    }
+}
+
+/**
+ * Picks out the repos with the given names (as owner/name) that are at the given status.  Leaving out the names picks
+ * out every repo at the status, and leaving out the status picks out every named repo.  It is an error to name a repo
+ * that isn't in the state.  The indexes of the repos in the state are returned, in order.
+ */
+pub fn select_repos(state:&BaseStateDefn, names:&[String], status:Option<RepoStatus>) -> Result<Vec<usize>, Box<dyn Error>> {
+    let wanted:Vec<String> = names.iter().map(|n| RepoDefn::new(n).map(|defn| defn.to_string())).collect::<Result<_, _>>()?;
+    if let Some(unknown) = wanted.iter().find(|name| !state.data.repos.iter().any(|elmt| elmt.defn().to_string()==**name)) {
+        return Err(Box::from(format!("{} is not in the state", unknown)));
+    }
+
+    Ok( state.data.repos.iter()
+        .enumerate()
+        .filter(|(_, elmt)| wanted.is_empty() || wanted.contains(&elmt.defn().to_string()))
+        .filter(|(_, elmt)| status.is_none_or(|status| RepoStatus::of(elmt)==status))
+        .map(|(i, _)| i)
+        .collect() )
+}
+
+//Takes the repos out of the state altogether, with their history.  Their clones are left where they are.
+fn remove_repos(state:&mut BaseStateDefn, selected:&[usize]) -> usize {
+    for i in selected.iter().rev() {
+        let removed = state.data.repos.remove(*i);
+        state.history.remove(&removed.defn().to_string());
+        info!("🗑️ Removed {}", removed.defn());
+    }
+    selected.len()
+}
+
+type RepoChange = Box<dyn Fn(DataElement) -> Result<DataElement, Box<dyn Error>>>;
+
+/**
+ * Does the action to each of the selected repos, and returns how many it was done to.  If it can't be done to a repo,
+ * e.g. retrying one that has not failed, then that repo is left alone and the others carry on.  Each repo that was
+ * changed has the action added to its history, so that the history shows why it went through a stage again.
+ */
+pub fn change_repos(state:&mut BaseStateDefn, selected:&[usize], action:RepoAction) -> usize {
+    let change:RepoChange = match action {
+        RepoAction::Retry=>Box::new(retry_repo),
+        RepoAction::Reset(stage)=>Box::new(move |elmt| reset_repo(elmt, stage)),
+        RepoAction::Skip=>Box::new(|elmt| Ok( skip_repo(elmt) )),
+        RepoAction::Remove=>return remove_repos(state, selected),
+    };
+
+    let outcome = match action {
+        RepoAction::Retry=>EventOutcome::Retried,
+        RepoAction::Reset(_)=>EventOutcome::Reset,
+        _=>EventOutcome::Skipped,
+    };
+    let mut changed = 0;
+    for i in selected {
+        match change(state.data.repos[*i].clone()) {
+            Ok(updated)=>{
+                let stage = match action {
+                    RepoAction::Reset(stage)=>stage,
+                    _=>next_stage(&state.data.repos[*i]),
+                };
+                state.record_event(updated.defn(), RepoEvent::changed(stage, outcome, unix_now()));
+                state.data.repos[*i] = updated;
+                changed += 1;
+            },
+            Err(e)=>warn!("⚠️ {}", e),
+        }
+    }
+    changed
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::Path;
+    use git2::BranchType;
+    use tempfile::TempDir;
+    use crate::data::{BranchedRepo, PatchedRepo};
+    use crate::error::{ErrorKind, RepoError};
+    use crate::gitutils::do_branch;
+    use crate::gitutils::test::repo_with_commit;
+    use crate::status::test::test_state;
+
+    #[test]
+    fn test_retry_and_skip() -> Result<(), Box<dyn Error>> {
+        let failed = DataElement::LocalRepo(LocalRepo {
+            defn: RepoDefn::new("org/failed")?,
+            local_path: Path::new("org/failed").into(),
+            last_error: Some(RepoError::new(Stage::Clone, ErrorKind::Auth, "denied".to_string())),
+        });
+        assert!(matches!(retry_repo(failed)?, DataElement::RemoteRepo(defn) if defn.name=="failed"));
+
+        let remote = DataElement::RemoteRepo(RepoDefn::new("org/remote")?);
+        assert!(retry_repo(remote.clone()).is_err());
+
+        let skipped = skip_repo(remote);
+        assert!(skipped.defn().skipped);
+        let unskipped = retry_repo(skipped)?;
+        assert!(matches!(&unskipped, DataElement::RemoteRepo(defn) if !defn.skipped));
+        Ok( () )
+    }
+
+    #[test]
+    fn test_reset_to_patch() -> Result<(), Box<dyn Error>> {
+        let dir = TempDir::new()?;
+        let repo = repo_with_commit(dir.path(), &[("README.md", "hello\n")])?;
+
+        let mut defn = RepoDefn::new("org/repo")?;
+        defn.main_branch_name = repo.head()?.shorthand().map(|b| b.to_string());
+        let local = LocalRepo { defn, local_path: dir.path().into(), last_error: None };
+        let base_commit = do_branch(&local, "campaign")?;
+        fs::write(dir.path().join("README.md"), "hello world\n")?;
+        let branched = DataElement::BranchedRepo(BranchedRepo {
+            patched: PatchedRepo {
+                repo: local,
+                changes: 1,
+                output: String::new(),
+                success: true,
+                outcome: None,
+                steps: vec![],
+                error: None,
+                review: None,
+            },
+            branch_name: "campaign".to_string(),
+            base_commit: Some(base_commit),
+            commit: None,
+            committed: false,
+            pushed: false,
+            pushed_ref: None,
+            last_error: Some(RepoError::new(Stage::Commit, ErrorKind::Other, "failed".to_string())),
+            pr_attempts: 0,
+            rate_limited_until: None,
+        });
+
+        //a repo that has not been committed can't have its PR made again
+        assert!(reset_repo(branched.clone(), Stage::PullRequest).is_err());
+        assert!(matches!(reset_repo(branched.clone(), Stage::Commit)?, DataElement::BranchedRepo(b) if b.last_error.is_none()));
+
+        let reset = reset_repo(branched, Stage::Patch)?;
+        assert!(matches!(&reset, DataElement::LocalRepo(local) if !local.is_failed()));
+        assert_eq!(fs::read_to_string(dir.path().join("README.md"))?, "hello\n");
+        assert!(repo.find_branch("campaign", BranchType::Local).is_err());
+        assert!(reset_repo(reset, Stage::Branch).is_err());
+        Ok( () )
+    }
+
+    #[test]
+    fn test_reset_prd() {
+        let prd = test_state().data.repos[4].clone();
+        for to in [Stage::Clone, Stage::Patch, Stage::Branch, Stage::Commit] {
+            let err = reset_repo(prd.clone(), to).unwrap_err().to_string();
+            assert!(err.contains("close the PR"), "{}", err);
+        }
+        assert!(reset_repo(prd.clone(), Stage::Push).is_err());
+        assert!(matches!(reset_repo(prd, Stage::PullRequest), Ok(DataElement::BranchedRepo(b)) if b.pushed));
+    }
+
+    #[test]
+    fn test_change_repos() -> Result<(), Box<dyn Error>> {
+        let mut state = BaseStateDefn::new(vec![]);
+        state.add_remote_repos(vec![RepoDefn::new("org/a")?, RepoDefn::new("org/b")?, RepoDefn::new("org/c")?]);
+        let names = vec!["org/a".to_string(), "https://github.com/org/b".to_string()];
+
+        assert!(select_repos(&state, &["org/missing".to_string()], None).is_err());
+        let selected = select_repos(&state, &names, None)?;
+        assert_eq!(selected, vec![0, 1]);
+        assert_eq!(change_repos(&mut state, &selected, RepoAction::Skip), 2);
+        assert_eq!(select_repos(&state, &[], Some(RepoStatus::Skipped))?, vec![0, 1]);
+        let skipped = state.history_of(state.data.repos[0].defn());
+        assert_eq!(skipped.len(), 1);
+        assert_eq!((skipped[0].stage, skipped[0].outcome), (Stage::Clone, EventOutcome::Skipped));
+        assert_eq!(select_repos(&state, &names[..1], Some(RepoStatus::Remote))?, Vec::<usize>::new());
+
+        assert_eq!(change_repos(&mut state, &[1], RepoAction::Remove), 1);
+        let left:Vec<String> = state.data.repos.iter().map(|elmt| elmt.defn().to_string()).collect();
+        assert_eq!(left, vec!["org/a", "org/c"]);
+        //org/c has not failed or been skipped, so there is nothing to retry
+        assert_eq!(change_repos(&mut state, &[0, 1], RepoAction::Retry), 1);
+        assert_eq!(state.history_of(state.data.repos[0].defn()).last().map(|e| e.outcome), Some(EventOutcome::Retried));
+        assert!(state.history_of(state.data.repos[1].defn()).is_empty());
+        assert_eq!(change_repos(&mut state, &[1], RepoAction::Reset(Stage::Clone)), 1);
+        assert_eq!(state.history_of(state.data.repos[1].defn())[0].outcome, EventOutcome::Reset);
+        Ok( () )
+    }
+}
@@ -0,0 +1,211 @@
+use std::error::Error;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use git2::Repository;
+use log::{info, warn};
+
+use crate::data::{write_datafile, BaseStateDefn, DataElement, PatchedRepo, ReviewDecision};
+use crate::dryrun::repo_diff;
+use crate::patcher::assess_changes;
+
+#[derive(Debug, PartialEq)]
+enum Choice {
+    Approve,
+    Skip,
+    Edit,
+    Shell,
+    View,
+    Abort,
+}
+
+fn parse_choice(input:&str) -> Option<Choice> {
+    match input.trim().to_lowercase().as_str() {
+        "a" | "approve"=>Some(Choice::Approve),
+        "s" | "skip"=>Some(Choice::Skip),
+        "e" | "edit"=>Some(Choice::Edit),
+        "!" | "shell"=>Some(Choice::Shell),
+        "v" | "view"=>Some(Choice::View),
+        "q" | "quit" | "abort"=>Some(Choice::Abort),
+        _=>None,
+    }
+}
+
+//Asks the user a question on the terminal.  Returns None if there is nobody there to answer, i.e. stdin has closed
+fn ask(prompt:&str) -> io::Result<Option<String>> {
+    print!("{}", prompt);
+    io::stdout().flush()?;
+    let mut answer = String::new();
+    match io::stdin().lock().read_line(&mut answer)? {
+        0=>Ok( None ),
+        _=>Ok( Some(answer) ),
+    }
+}
+
+//The first of the given environment variables that is set, e.g. $VISUAL then $EDITOR, or the fallback if none are
+fn program_from_env(vars:&[&str], fallback:&str) -> String {
+    vars.iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|value| !value.trim().is_empty())
+        .unwrap_or(fallback.to_string())
+}
+
+//Runs a program that the user gave us, which can have arguments of its own (e.g. EDITOR="code --wait"), in the clone
+fn run_user_program(program:&str, args:&[String], dir:&Path) -> Result<(), Box<dyn Error>> {
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(format!("{} \"$@\"", program))
+        .arg(program)
+        .args(args)
+        .current_dir(dir)
+        .status()?;
+    if status.success() {
+        Ok( () )
+    } else {
+        Err(Box::from(format!("{} exited with {}", program, status)))
+    }
+}
+
+fn show_in_pager(text:&str) -> Result<(), Box<dyn Error>> {
+    let pager = program_from_env(&["PAGER"], "less");
+    let mut child = Command::new("sh").arg("-c").arg(&pager).stdin(Stdio::piped()).spawn()?;
+    if let Some(mut stdin) = child.stdin.take() {
+        //the user can quit the pager before it has read everything, which is fine
+        let _ = stdin.write_all(text.as_bytes());
+    }
+    child.wait()?;
+    Ok( () )
+}
+
+//Runs the editor or shell in the clone, then counts the changes again, as the user could have changed any of the files
+fn change_by_hand(patched:&mut PatchedRepo, program:&str, args:&[String]) -> Result<(), Box<dyn Error>> {
+    let local_path = patched.repo.local_path.to_path_buf();
+    if let Err(e) = run_user_program(program, args, &local_path) {
+        warn!("⚠️ {}", e);
+    }
+    patched.changes = assess_changes(&Repository::open(&local_path)?)?;
+    info!("🔍 {} files are changed now", patched.changes);
+    Ok( () )
+}
+
+/**
+ * Shows the repo's changes and asks what to do with them, until the user approves or skips it.  Editing the changes
+ * (or changing them from a shell) shows them again afterwards.  Returns None if the user wants to stop reviewing.
+ */
+fn review_repo(patched:&mut PatchedRepo, n:usize, total:usize) -> Result<Option<ReviewDecision>, Box<dyn Error>> {
+    let defn = patched.repo.defn.to_string();
+    let local_path = patched.repo.local_path.to_path_buf();
+    let mut files:Vec<String> = vec![];
+    let mut show = true;
+
+    loop {
+        if show {
+            let diff = repo_diff(&patched.repo)?;
+            let text = format!("{} ({} of {})\n\n{}", defn, n, total, diff.to_patch_file());
+            if let Err(e) = show_in_pager(&text) {
+                warn!("⚠️ Unable to run the pager: {}", e);
+                print!("{}", text);
+            }
+            files = diff.files;
+            show = false;
+        }
+
+        let answer = ask(&format!("{} - [a]pprove, [s]kip, [e]dit, [!] shell, [v]iew again or [q]uit? ", defn))?;
+        let choice = match answer {
+            Some(answer)=>parse_choice(&answer),
+            None=>Some(Choice::Abort),
+        };
+        match choice {
+            Some(Choice::Approve)=>return Ok( Some(ReviewDecision::Approved) ),
+            Some(Choice::Skip)=>return Ok( Some(ReviewDecision::Skipped) ),
+            Some(Choice::Abort)=>return Ok( None ),
+            Some(Choice::View)=>show = true,
+            Some(Choice::Edit)=>{
+                //files that the patch deleted can't be edited
+                let existing:Vec<String> = files.iter().filter(|f| local_path.join(f).exists()).cloned().collect();
+                change_by_hand(patched, &program_from_env(&["VISUAL", "EDITOR"], "vi"), &existing)?;
+                show = true;
+            },
+            Some(Choice::Shell)=>{
+                println!("Starting a shell in {}; exit it to carry on reviewing", local_path.display());
+                change_by_hand(patched, &program_from_env(&["SHELL"], "sh"), &[])?;
+                show = true;
+            },
+            None=>println!("Please answer a, s, e, !, v or q"),
+        }
+    }
+}
+
+/**
+ * Asks the user to review the changes made to each patched repo that has not been reviewed yet, one at a time.  Each
+ * decision is written to the state as soon as it is made, so if the review is stopped part way through then only the
+ * repos that are left are asked about next time.
+ */
+pub fn review_repos(state:&mut BaseStateDefn, state_file:&Path) -> Result<(), Box<dyn Error>> {
+    let to_review:Vec<usize> = state.data.repos.iter()
+        .enumerate()
+        .filter(|(_, elmt)| matches!(elmt, DataElement::PatchedRepo(repo) if repo.is_ready_to_branch() && repo.review.is_none()))
+        .map(|(i, _)| i)
+        .collect();
+
+    for (n, i) in to_review.iter().enumerate() {
+        if let DataElement::PatchedRepo(patched) = &mut state.data.repos[*i] {
+            match review_repo(patched, n + 1, to_review.len())? {
+                Some(decision)=>{
+                    info!("📋 {} {:?}", patched.repo.defn, decision);
+                    patched.review = Some(decision);
+                },
+                None=>{
+                    info!("✋ Stopped reviewing; the {} repos that are left will be asked about next time", to_review.len() - n);
+                    return Err(Box::from("Review stopped"));
+                },
+            }
+        }
+        write_datafile(state_file, state)?;
+    }
+    Ok( () )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempfile::TempDir;
+    use crate::data::{LocalRepo, RepoDefn};
+    use crate::gitutils::test::repo_with_commit;
+
+    #[test]
+    fn test_parse_choice() {
+        assert_eq!(parse_choice("a\n"), Some(Choice::Approve));
+        assert_eq!(parse_choice(" Skip "), Some(Choice::Skip));
+        assert_eq!(parse_choice("!"), Some(Choice::Shell));
+        assert_eq!(parse_choice("q"), Some(Choice::Abort));
+        assert_eq!(parse_choice(""), None);
+        assert_eq!(parse_choice("yes"), None);
+    }
+
+    #[test]
+    fn test_change_by_hand() -> Result<(), Box<dyn Error>> {
+        let dir = TempDir::new()?;
+        repo_with_commit(dir.path(), &[("README.md", "hello\n")])?;
+        std::fs::write(dir.path().join("README.md"), "hello world\n")?;
+        let mut patched = PatchedRepo {
+            repo: LocalRepo { defn: RepoDefn::new("org/repo")?, local_path: dir.path().into(), last_error: None },
+            changes: 1,
+            output: String::new(),
+            success: true,
+            outcome: None,
+            steps: vec![],
+            error: None,
+            review: None,
+        };
+
+        //the user puts the patched file back and adds a new one instead, which still needs committing
+        change_by_hand(&mut patched, "echo hello > README.md && touch", &["NEW.md".to_string()])?;
+        assert_eq!(patched.changes, 1);
+        assert!(patched.is_ready_to_branch());
+
+        change_by_hand(&mut patched, "rm", &["NEW.md".to_string()])?;
+        assert_eq!(patched.changes, 0);
+        Ok( () )
+    }
+}
@@ -0,0 +1,273 @@
+use std::fmt::{self, Write};
+use clap::ValueEnum;
+
+use crate::data::{BaseStateDefn, DataElement, ReviewDecision};
+use crate::error::Stage;
+
+/**
+ * How far a repo has got through the pipeline.  A repo that failed at some stage is `Failed` whatever stage that was,
+ * and a repo that the patch made no changes to is `NoChanges`, as it goes no further.
+ */
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum RepoStatus {
+    Remote,
+    Cloned,
+    Patched,
+    NoChanges,
+    //The repo was skipped, or its changes were when they were reviewed
+    Skipped,
+    Branched,
+    Committed,
+    Pushed,
+    #[value(name = "prd", alias = "pr")]
+    PRd,
+    Failed,
+}
+
+impl RepoStatus {
+    pub const ALL:[RepoStatus; 10] = [
+        RepoStatus::Remote,
+        RepoStatus::Cloned,
+        RepoStatus::Patched,
+        RepoStatus::NoChanges,
+        RepoStatus::Skipped,
+        RepoStatus::Branched,
+        RepoStatus::Committed,
+        RepoStatus::Pushed,
+        RepoStatus::PRd,
+        RepoStatus::Failed,
+    ];
+
+    pub fn of(elmt:&DataElement) -> RepoStatus {
+        match elmt {
+            elmt if elmt.defn().skipped=>RepoStatus::Skipped,
+            DataElement::RemoteRepo(_)=>RepoStatus::Remote,
+            DataElement::LocalRepo(repo) if repo.is_failed()=>RepoStatus::Failed,
+            DataElement::LocalRepo(_)=>RepoStatus::Cloned,
+            DataElement::PatchedRepo(repo) if !repo.success || repo.error.is_some()=>RepoStatus::Failed,
+            DataElement::PatchedRepo(repo) if repo.changes==0=>RepoStatus::NoChanges,
+            DataElement::PatchedRepo(repo) if repo.review==Some(ReviewDecision::Skipped)=>RepoStatus::Skipped,
+            DataElement::PatchedRepo(_)=>RepoStatus::Patched,
+            DataElement::BranchedRepo(repo) if repo.last_error.is_some()=>RepoStatus::Failed,
+            DataElement::BranchedRepo(repo) if repo.pushed=>RepoStatus::Pushed,
+            DataElement::BranchedRepo(repo) if repo.committed=>RepoStatus::Committed,
+            DataElement::BranchedRepo(_)=>RepoStatus::Branched,
+            DataElement::PRdRepo(_)=>RepoStatus::PRd,
+        }
+    }
+}
+
+impl fmt::Display for RepoStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            RepoStatus::Remote=>"remote",
+            RepoStatus::Cloned=>"cloned",
+            RepoStatus::Patched=>"patched",
+            RepoStatus::NoChanges=>"no changes",
+            RepoStatus::Skipped=>"skipped",
+            RepoStatus::Branched=>"branched",
+            RepoStatus::Committed=>"committed",
+            RepoStatus::Pushed=>"pushed",
+            RepoStatus::PRd=>"PR'd",
+            RepoStatus::Failed=>"failed",
+        })
+    }
+}
+
+//The stage that a failed repo failed at, if it has failed
+pub fn failed_stage(elmt:&DataElement) -> Option<Stage> {
+    match elmt {
+        DataElement::LocalRepo(repo)=>repo.last_error.as_ref().map(|e| e.stage),
+        DataElement::PatchedRepo(repo) if !repo.success=>Some(Stage::Patch),
+        DataElement::PatchedRepo(repo)=>repo.error.as_ref().map(|e| e.stage),
+        DataElement::BranchedRepo(repo)=>repo.last_error.as_ref().map(|e| e.stage),
+        _=>None,
+    }
+}
+
+/**
+ * Describes why the repo failed, if it did.  A patch step failing is not an error as such, so for those we say which
+ * step it was.
+ */
+pub fn last_error(elmt:&DataElement) -> Option<String> {
+    match elmt {
+        DataElement::LocalRepo(repo)=>repo.last_error.as_ref().map(|e| e.to_string()),
+        DataElement::PatchedRepo(repo)=>match (repo.error.as_ref(), repo.success) {
+            (Some(e), _)=>Some(e.to_string()),
+            (None, true)=>None,
+            (None, false)=>Some(match repo.steps.iter().find(|s| !s.outcome.is_success()) {
+                Some(step)=>format!("patch {}: {} {}", repo.outcome.map(|o| o.to_string()).unwrap_or("failed".to_string()), step.step, step.output.trim()),
+                None=>format!("patch failed: {}", repo.output.trim()),
+            }),
+        },
+        DataElement::BranchedRepo(repo)=>repo.last_error.as_ref().map(|e| e.to_string()),
+        _=>None,
+    }
+}
+
+//The status, with the stage that it failed at if it failed
+pub fn status_text(elmt:&DataElement) -> String {
+    match (RepoStatus::of(elmt), failed_stage(elmt)) {
+        (RepoStatus::Failed, Some(stage))=>format!("failed ({})", stage),
+        (status, _)=>status.to_string(),
+    }
+}
+
+//How many files the patch changed, if it has been patched
+pub fn changes(elmt:&DataElement) -> Option<usize> {
+    match elmt {
+        DataElement::PRdRepo(repo)=>Some(repo.branched.patched.changes),
+        DataElement::BranchedRepo(repo)=>Some(repo.patched.changes),
+        DataElement::PatchedRepo(repo)=>Some(repo.changes),
+        _=>None,
+    }
+}
+
+pub fn pr_url(elmt:&DataElement) -> Option<&str> {
+    match elmt {
+        DataElement::PRdRepo(repo)=>Some(repo.url.as_str()),
+        _=>None,
+    }
+}
+
+//Lays out rows as columns, padded to the widest cell in each
+pub fn write_table(out:&mut String, rows:&[Vec<String>]) -> fmt::Result {
+    let columns = rows.iter().map(|r| r.len()).max().unwrap_or(0);
+    let widths:Vec<usize> = (0..columns)
+        .map(|c| rows.iter().filter_map(|r| r.get(c)).map(|cell| cell.chars().count()).max().unwrap_or(0))
+        .collect();
+
+    for row in rows {
+        let line = row.iter().zip(widths.iter())
+            .map(|(cell, width)| format!("{:width$}", cell, width = *width))
+            .collect::<Vec<String>>()
+            .join("  ");
+        writeln!(out, "{}", line.trim_end())?;
+    }
+    Ok( () )
+}
+
+/**
+ * Summarises the state as a table of how many repos are at each stage, then a row for each repo.  If `only` is given
+ * then only the repos with that status get a row.
+ */
+pub fn render_status(state:&BaseStateDefn, only:Option<RepoStatus>) -> Result<String, fmt::Error> {
+    let mut out = String::new();
+
+    let mut summary = vec![vec!["STAGE".to_string(), "REPOS".to_string()]];
+    for status in RepoStatus::ALL {
+        let count = state.data.repos.iter().filter(|elmt| RepoStatus::of(elmt)==status).count();
+        summary.push(vec![status.to_string(), count.to_string()]);
+    }
+    summary.push(vec!["total".to_string(), state.data.repos.len().to_string()]);
+    write_table(&mut out, &summary)?;
+    writeln!(out)?;
+
+    let mut repos = vec![vec!["REPO".to_string(), "STATUS".to_string(), "CHANGES".to_string(), "PR / LAST ERROR".to_string()]];
+    for elmt in state.data.repos.iter() {
+        let status = RepoStatus::of(elmt);
+        if only.is_some_and(|only| only!=status) {
+            continue;
+        }
+        let detail = match (pr_url(elmt), last_error(elmt)) {
+            (Some(url), _)=>url.to_string(),
+            //only the first line, so that the table stays readable
+            (None, Some(err))=>err.lines().next().unwrap_or_default().to_string(),
+            (None, None)=>String::new(),
+        };
+        repos.push(vec![
+            elmt.defn().to_string(),
+            status_text(elmt),
+            changes(elmt).map(|c| c.to_string()).unwrap_or_default(),
+            detail,
+        ]);
+    }
+
+    if repos.len()==1 {
+        writeln!(out, "No repos to show")?;
+    } else {
+        write_table(&mut out, &repos)?;
+    }
+    Ok( out )
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::data::{BranchedRepo, LocalRepo, PRdRepo, PatchOutcome, PatchedRepo, RepoDefn};
+    use crate::error::{ErrorKind, RepoError};
+
+    fn patched(name:&str, changes:usize) -> PatchedRepo {
+        PatchedRepo {
+            repo: LocalRepo {
+                defn: RepoDefn::new(name).unwrap(),
+                local_path: std::path::Path::new(name).into(),
+                last_error: None,
+            },
+            changes,
+            output: String::new(),
+            success: true,
+            outcome: Some(PatchOutcome::Clean),
+            steps: vec![],
+            error: None,
+            review: None,
+        }
+    }
+
+    fn branched(name:&str, committed:bool, pushed:bool, last_error:Option<RepoError>) -> BranchedRepo {
+        BranchedRepo {
+            patched: patched(name, 2),
+            branch_name: "fix".to_string(),
+            base_commit: Some("1111111111111111111111111111111111111111".to_string()),
+            commit: committed.then(|| "2222222222222222222222222222222222222222".to_string()),
+            committed,
+            pushed,
+            pushed_ref: pushed.then(|| "refs/heads/fix".to_string()),
+            last_error,
+            pr_attempts: 0,
+            rate_limited_until: None,
+        }
+    }
+
+    //One repo at each of several stages, which the report tests use too
+    pub(crate) fn test_state() -> BaseStateDefn {
+        BaseStateDefn::new(vec![
+            DataElement::RemoteRepo(RepoDefn::new("org/remote").unwrap()),
+            DataElement::PatchedRepo(patched("org/unchanged", 0)),
+            DataElement::BranchedRepo(branched("org/committed", true, false, None)),
+            DataElement::BranchedRepo(branched("org/unpushed", true, false, Some(RepoError::new(Stage::Push, ErrorKind::Auth, "denied\nmore detail".to_string())))),
+            DataElement::PRdRepo(PRdRepo {
+                branched: branched("org/done", true, true, None),
+                url: "https://github.com/org/done/pull/1".to_string(),
+                number: Some(1),
+                node_id: Some("PR_kwDOAAAAAc4AAAAB".to_string()),
+            }),
+        ])
+    }
+
+    #[test]
+    fn test_repo_status() {
+        let statuses:Vec<RepoStatus> = test_state().data.repos.iter().map(RepoStatus::of).collect();
+        assert_eq!(statuses, vec![RepoStatus::Remote, RepoStatus::NoChanges, RepoStatus::Committed, RepoStatus::Failed, RepoStatus::PRd]);
+
+        let mut skipped = patched("org/skipped", 2);
+        skipped.review = Some(ReviewDecision::Skipped);
+        assert_eq!(RepoStatus::of(&DataElement::PatchedRepo(skipped)), RepoStatus::Skipped);
+    }
+
+    #[test]
+    fn test_render_status() -> Result<(), fmt::Error> {
+        let state = test_state();
+        let all = render_status(&state, None)?;
+        assert!(all.contains("committed   1\n"));
+        assert!(all.contains("total       5\n"));
+        assert!(all.contains("org/done       PR'd           2        https://github.com/org/done/pull/1\n"));
+
+        let failed = render_status(&state, Some(RepoStatus::Failed))?;
+        assert!(failed.contains("org/unpushed  failed (push)  2        push failed (authentication error): denied\n"));
+        assert!(!failed.contains("org/done"));
+
+        assert!(render_status(&state, Some(RepoStatus::Cloned))?.ends_with("No repos to show\n"));
+        Ok( () )
+    }
+}
@@ -0,0 +1,1025 @@
+use std::error::Error;
+use std::fmt::{self, Display, Write as _};
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use git2::Repository;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::{ser::PrettyFormatter, Serializer, Value};
+use toml_edit::{ArrayOfTables, DocumentMut, InlineTable, Item, Key, Table, TableLike};
+use log::debug;
+
+use crate::replace::{glob_to_regex, list_files};
+use crate::yamledit::YamlDocument;
+
+/**
+ * A set of structured edits, loaded from a JSON file like this:
+ * {"edits": [{"file": "package.json", "op": "set", "path": "engines.node", "value": ">=20"}]}
+ */
+#[derive(Debug, Clone, Deserialize)]
+pub struct EditSpec {
+    pub edits: Vec<StructuredEdit>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileFormat {
+    Json,
+    Yaml,
+    Toml,
+}
+
+impl FileFormat {
+    fn from_path(path:&str) -> Option<FileFormat> {
+        match Path::new(path).extension().and_then(|e| e.to_str()).map(|e| e.to_lowercase()).as_deref() {
+            Some("json")=>Some(FileFormat::Json),
+            Some("yml") | Some("yaml")=>Some(FileFormat::Yaml),
+            Some("toml")=>Some(FileFormat::Toml),
+            _=>None,
+        }
+    }
+}
+
+/**
+ * What to do at the path.  `set` creates any missing parent mappings, `append` creates the array if it is not there,
+ * and `delete` and `rename` do nothing if the path does not exist.
+ */
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum EditOp {
+    Set { value: Value },
+    Delete,
+    Append { value: Value },
+    Rename { to: String },
+}
+
+/**
+ * A single edit.  `file` is a glob in the same form as --replace-glob, and the format is taken from the file extension
+ * unless `format` is given.
+ */
+#[derive(Debug, Clone, Deserialize)]
+pub struct StructuredEdit {
+    pub file: String,
+    #[serde(default)]
+    pub format: Option<FileFormat>,
+    pub path: String,
+    #[serde(flatten)]
+    pub op: EditOp,
+}
+
+impl Display for StructuredEdit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.op {
+            EditOp::Set{..}=>write!(f, "set {}", self.path),
+            EditOp::Delete=>write!(f, "delete {}", self.path),
+            EditOp::Append{..}=>write!(f, "append to {}", self.path),
+            EditOp::Rename{to}=>write!(f, "rename {} to {}", self.path, to),
+        }
+    }
+}
+
+/**
+ * One step of a path into a document.  Paths are written as dot-separated keys, with [n] for array indices and double
+ * quotes around keys that contain dots, e.g. `jobs.build.steps[0].uses` or `dependencies."serde.json"`.
+ */
+#[derive(Debug, Clone, PartialEq)]
+pub enum PathSegment {
+    Key(String),
+    Index(usize),
+}
+
+pub fn parse_path(path:&str) -> Result<Vec<PathSegment>, Box<dyn Error>> {
+    let chars:Vec<char> = path.chars().collect();
+    let mut segments:Vec<PathSegment> = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '"'=>{
+                let mut key = String::new();
+                i += 1;
+                while i < chars.len() && chars[i] != '"' {
+                    if chars[i]=='\\' && i+1 < chars.len() {
+                        i += 1;
+                    }
+                    key.push(chars[i]);
+                    i += 1;
+                }
+                if i >= chars.len() {
+                    return Err(Box::from(format!("unclosed quote in path {}", path)));
+                }
+                i += 1;
+                segments.push(PathSegment::Key(key));
+            },
+            '['=>{
+                let end = chars[i..].iter().position(|c| *c==']').map(|p| p + i).ok_or_else(|| format!("unclosed [ in path {}", path))?;
+                let index:String = chars[i+1..end].iter().collect();
+                let index = index.trim().parse::<usize>().map_err(|_| format!("'{}' is not an array index in path {}", index, path))?;
+                segments.push(PathSegment::Index(index));
+                i = end + 1;
+            },
+            _=>{
+                let end = chars[i..].iter().position(|c| *c=='.' || *c=='[').map(|p| p + i).unwrap_or(chars.len());
+                let key:String = chars[i..end].iter().collect();
+                if key.is_empty() {
+                    return Err(Box::from(format!("empty key in path {}", path)));
+                }
+                segments.push(PathSegment::Key(key));
+                i = end;
+            }
+        }
+
+        //after a segment we need a separator, or the end
+        match chars.get(i) {
+            Some('.')=>{
+                i += 1;
+                if i >= chars.len() {
+                    return Err(Box::from(format!("path {} ends with a '.'", path)));
+                }
+            },
+            Some('[') | None=>(),
+            Some(other)=>return Err(Box::from(format!("unexpected '{}' in path {}", other, path))),
+        }
+    }
+
+    if segments.is_empty() {
+        Err(Box::from("the path is empty"))
+    } else {
+        Ok(segments)
+    }
+}
+
+pub fn format_path(path:&[PathSegment]) -> String {
+    let mut out = String::new();
+    for segment in path {
+        match segment {
+            PathSegment::Key(k) if k.contains(['.', '[', '"']) => {
+                if !out.is_empty() {
+                    out.push('.');
+                }
+                out.push_str(&format!("{:?}", k));
+            },
+            PathSegment::Key(k)=>{
+                if !out.is_empty() {
+                    out.push('.');
+                }
+                out.push_str(k);
+            },
+            PathSegment::Index(i)=>out.push_str(&format!("[{}]", i)),
+        }
+    }
+    out
+}
+
+//Turns the rest of a path into nested objects around the value, for when `set` needs to create the parents
+pub fn nest_value(path:&[PathSegment], value:&Value) -> Result<Value, Box<dyn Error>> {
+    let mut nested = value.clone();
+    for segment in path.iter().rev() {
+        match segment {
+            PathSegment::Key(k)=>{
+                let mut map = serde_json::Map::new();
+                map.insert(k.to_owned(), nested);
+                nested = Value::Object(map);
+            },
+            PathSegment::Index(i)=>return Err(Box::from(format!("there is no item [{}] to set", i))),
+        }
+    }
+    Ok(nested)
+}
+
+pub fn load_edit_file(p:&Path) -> Result<EditSpec, Box<dyn Error>> {
+    let file = File::open(p)?;
+    let spec:EditSpec = serde_json::from_reader(file)?;
+    for edit in spec.edits.iter() {
+        parse_path(&edit.path)?;
+        glob_to_regex(&edit.file)?;
+    }
+    Ok(spec)
+}
+
+/* ---------------------------------------- JSON ---------------------------------------- */
+
+fn json_child<'v>(value:&'v mut Value, segment:&PathSegment) -> Option<&'v mut Value> {
+    match (value, segment) {
+        (Value::Object(map), PathSegment::Key(k))=>map.get_mut(k),
+        (Value::Array(arr), PathSegment::Index(i))=>arr.get_mut(*i),
+        _=>None,
+    }
+}
+
+fn json_find<'v>(value:&'v mut Value, path:&[PathSegment]) -> Option<&'v mut Value> {
+    path.iter().try_fold(value, |current, segment| json_child(current, segment))
+}
+
+fn json_set(doc:&mut Value, path:&[PathSegment], value:&Value) -> Result<(), Box<dyn Error>> {
+    let (last, parents) = path.split_last().ok_or("the path is empty")?;
+    let mut current = doc;
+    for (depth, segment) in parents.iter().enumerate() {
+        if current.is_null() {
+            *current = Value::Object(serde_json::Map::new());
+        }
+        current = match (current, segment) {
+            (Value::Object(map), PathSegment::Key(k))=>map.entry(k.to_owned()).or_insert(Value::Null),
+            (Value::Array(arr), PathSegment::Index(i))=>arr.get_mut(*i).ok_or_else(|| format!("{} does not exist", format_path(&path[..=depth])))?,
+            _=>return Err(Box::from(format!("{} is not an object or array", format_path(&path[..depth])))),
+        };
+    }
+
+    if current.is_null() {
+        *current = Value::Object(serde_json::Map::new());
+    }
+    match (current, last) {
+        (Value::Object(map), PathSegment::Key(k))=>{
+            map.insert(k.to_owned(), value.clone());
+            Ok( () )
+        },
+        (Value::Array(arr), PathSegment::Index(i)) if *i < arr.len() =>{
+            arr[*i] = value.clone();
+            Ok( () )
+        },
+        _=>Err(Box::from(format!("{} can't be set", format_path(path)))),
+    }
+}
+
+fn json_edit(doc:&mut Value, path:&[PathSegment], op:&EditOp) -> Result<(), Box<dyn Error>> {
+    match op {
+        EditOp::Set{value}=>json_set(doc, path, value),
+        EditOp::Delete=>{
+            let (last, parents) = path.split_last().ok_or("the path is empty")?;
+            match (json_find(doc, parents), last) {
+                (Some(Value::Object(map)), PathSegment::Key(k))=>{ map.shift_remove(k); },
+                (Some(Value::Array(arr)), PathSegment::Index(i)) if *i < arr.len() =>{ arr.remove(*i); },
+                _=>(),
+            }
+            Ok( () )
+        },
+        EditOp::Append{value}=>match json_find(doc, path) {
+            Some(Value::Array(arr))=>{
+                arr.push(value.clone());
+                Ok( () )
+            },
+            None | Some(Value::Null)=>json_set(doc, path, &Value::Array(vec![value.clone()])),
+            Some(_)=>Err(Box::from(format!("{} is not an array", format_path(path)))),
+        },
+        EditOp::Rename{to}=>{
+            let (last, parents) = path.split_last().ok_or("the path is empty")?;
+            match (json_find(doc, parents), last) {
+                (Some(Value::Object(map)), PathSegment::Key(k)) if map.contains_key(k) =>{
+                    if map.contains_key(to) {
+                        return Err(Box::from(format!("can't rename {} as {} already exists", format_path(path), to)));
+                    }
+                    let position = map.keys().position(|existing| existing==k).unwrap_or(0);
+                    let value = map.shift_remove(k).unwrap_or_default();
+                    map.shift_insert(position, to.to_owned(), value);
+                    Ok( () )
+                },
+                _=>Ok( () ),
+            }
+        },
+    }
+}
+
+//Works out the indent that a JSON file uses, from the first indented line. None means the file is all on one line.
+fn json_indent(content:&str) -> Option<String> {
+    if !content.trim().contains('\n') {
+        return None;
+    }
+    let indent = content.lines()
+        .skip(1)
+        .map(|line| line.chars().take_while(|c| *c==' ' || *c=='\t').collect::<String>())
+        .find(|indent| !indent.is_empty());
+    Some(indent.unwrap_or_else(|| "  ".to_string()))
+}
+
+//Where a value is in the original text, and where its members or items are if it is an object or array
+#[derive(Debug)]
+struct JsonSpan {
+    start: usize,
+    end: usize,
+    kind: SpanKind,
+}
+
+#[derive(Debug)]
+enum SpanKind {
+    Scalar,
+    Object(Vec<MemberSpan>),
+    Array(Vec<JsonSpan>),
+}
+
+#[derive(Debug)]
+struct MemberSpan {
+    key: String,
+    key_start: usize,
+    key_end: usize,
+    value: JsonSpan,
+}
+
+fn skip_whitespace(text:&[u8], i:&mut usize) {
+    while text.get(*i).is_some_and(|c| matches!(c, b' ' | b'\t' | b'\n' | b'\r')) {
+        *i += 1;
+    }
+}
+
+fn next_byte(text:&[u8], i:usize) -> Result<u8, Box<dyn Error>> {
+    text.get(i).copied().ok_or_else(|| Box::from("unexpected end of the JSON"))
+}
+
+fn scan_string(text:&[u8], i:&mut usize) -> Result<(), Box<dyn Error>> {
+    *i += 1;
+    loop {
+        match next_byte(text, *i)? {
+            b'\\'=>*i += 2,
+            b'"'=>{
+                *i += 1;
+                return Ok( () );
+            },
+            _=>*i += 1,
+        }
+    }
+}
+
+//After a member or item, moves past the comma, and returns true if that was the last one before `close`
+fn scan_separator(text:&[u8], i:&mut usize, close:u8) -> Result<bool, Box<dyn Error>> {
+    skip_whitespace(text, i);
+    match next_byte(text, *i)? {
+        b','=>{
+            *i += 1;
+            Ok( false )
+        },
+        c if c==close=>{
+            *i += 1;
+            Ok( true )
+        },
+        c=>Err(Box::from(format!("unexpected '{}' in the JSON", c as char))),
+    }
+}
+
+/**
+ * Finds where each value in a JSON document is.  The document has already been parsed by serde_json, so this only
+ * has to find the edges of things rather than check them.
+ */
+fn scan_json(text:&[u8], i:&mut usize) -> Result<JsonSpan, Box<dyn Error>> {
+    skip_whitespace(text, i);
+    let start = *i;
+    let kind = match next_byte(text, *i)? {
+        b'{'=>{
+            *i += 1;
+            let mut members = vec![];
+            skip_whitespace(text, i);
+            if next_byte(text, *i)?==b'}' {
+                *i += 1;
+            } else {
+                loop {
+                    skip_whitespace(text, i);
+                    let key_start = *i;
+                    scan_string(text, i)?;
+                    let key_end = *i;
+                    let key:String = serde_json::from_slice(&text[key_start..key_end])?;
+                    skip_whitespace(text, i);
+                    if next_byte(text, *i)? != b':' {
+                        return Err(Box::from(format!("expected ':' after {} in the JSON", key)));
+                    }
+                    *i += 1;
+                    let value = scan_json(text, i)?;
+                    members.push(MemberSpan { key, key_start, key_end, value });
+                    if scan_separator(text, i, b'}')? {
+                        break;
+                    }
+                }
+            }
+            SpanKind::Object(members)
+        },
+        b'['=>{
+            *i += 1;
+            let mut items = vec![];
+            skip_whitespace(text, i);
+            if next_byte(text, *i)?==b']' {
+                *i += 1;
+            } else {
+                loop {
+                    items.push(scan_json(text, i)?);
+                    if scan_separator(text, i, b']')? {
+                        break;
+                    }
+                }
+            }
+            SpanKind::Array(items)
+        },
+        b'"'=>{
+            scan_string(text, i)?;
+            SpanKind::Scalar
+        },
+        _=>{
+            while text.get(*i).is_some_and(|c| !matches!(c, b',' | b'}' | b']' | b' ' | b'\t' | b'\n' | b'\r')) {
+                *i += 1;
+            }
+            SpanKind::Scalar
+        },
+    };
+    Ok( JsonSpan { start, end: *i, kind } )
+}
+
+/**
+ * Writes an edited JSON document back out in the shape of the original.  Anything that the edits didn't change is
+ * copied from the original text as it was, so only the edited values are formatted by us, and they follow the
+ * indent and separators that their neighbours use.
+ */
+struct JsonLayout<'c> {
+    content: &'c str,
+    //None if the file is all on one line
+    indent: Option<String>,
+    newline: &'static str,
+}
+
+impl JsonLayout<'_> {
+    //A value that wasn't in the file before, starting on a line that is indented by `line_indent`
+    fn fresh(&self, value:&Value, line_indent:&str) -> Result<String, Box<dyn Error>> {
+        match self.indent.as_ref() {
+            Some(indent)=>{
+                let mut out:Vec<u8> = vec![];
+                let mut ser = Serializer::with_formatter(&mut out, PrettyFormatter::with_indent(indent.as_bytes()));
+                value.serialize(&mut ser)?;
+                Ok( String::from_utf8(out)?.replace('\n', &format!("{}{}", self.newline, line_indent)) )
+            },
+            None=>Ok( serde_json::to_string(value)? ),
+        }
+    }
+
+    fn render(&self, span:&JsonSpan, original:&Value, updated:&Value, line_indent:&str) -> Result<String, Box<dyn Error>> {
+        if original==updated {
+            return Ok( self.content[span.start..span.end].to_string() );
+        }
+        match (&span.kind, original, updated) {
+            (SpanKind::Object(members), Value::Object(original), Value::Object(updated))=>self.render_object(span, members, original, updated, line_indent),
+            (SpanKind::Array(items), Value::Array(original), Value::Array(updated))=>self.render_array(span, items, original, updated, line_indent),
+            _=>self.fresh(updated, line_indent),
+        }
+    }
+
+    /**
+     * The whitespace after the opening bracket, between each of the children and before the closing bracket, taken
+     * from the original where it has them.  `children` are where the members or items were in the original.
+     */
+    fn container_spacing(&self, span:&JsonSpan, children:&[(usize, usize)], line_indent:&str) -> (String, Vec<String>, String) {
+        let (Some(first), Some(last)) = (children.first(), children.last()) else {
+            return match self.indent.as_ref() {
+                Some(indent)=>{
+                    let inner = format!("{}{}{}", self.newline, line_indent, indent);
+                    (inner.clone(), vec![format!(",{}", inner)], format!("{}{}", self.newline, line_indent))
+                },
+                None=>(String::new(), vec![",".to_string()], String::new()),
+            };
+        };
+        let leading = self.content[span.start + 1..first.0].to_string();
+        let trailing = self.content[last.1..span.end - 1].to_string();
+        let mut separators:Vec<String> = children.windows(2).map(|pair| self.content[pair[0].1..pair[1].0].to_string()).collect();
+        if separators.is_empty() {
+            separators.push(match (leading.contains('\n'), self.indent.is_some()) {
+                (true, _)=>format!(",{}", leading),
+                (false, true)=>", ".to_string(),
+                (false, false)=>",".to_string(),
+            });
+        }
+        (leading, separators, trailing)
+    }
+
+    fn join_children(open:char, close:char, pieces:Vec<String>, (leading, separators, trailing):(String, Vec<String>, String)) -> String {
+        if pieces.is_empty() {
+            return format!("{}{}", open, close);
+        }
+        let mut out = format!("{}{}", open, leading);
+        for (i, piece) in pieces.iter().enumerate() {
+            if i > 0 {
+                out.push_str(separators.get(i - 1).or(separators.last()).map(|s| s.as_str()).unwrap_or(","));
+            }
+            out.push_str(piece);
+        }
+        out.push_str(&trailing);
+        out.push(close);
+        out
+    }
+
+    //The indent of the lines that the children of a container start on
+    fn child_indent(leading:&str, line_indent:&str) -> String {
+        match leading.rfind('\n') {
+            Some(newline)=>leading[newline + 1..].to_string(),
+            None=>line_indent.to_string(),
+        }
+    }
+
+    fn render_object(&self, span:&JsonSpan, members:&[MemberSpan], original:&serde_json::Map<String, Value>, updated:&serde_json::Map<String, Value>, line_indent:&str) -> Result<String, Box<dyn Error>> {
+        let children:Vec<(usize, usize)> = members.iter().map(|m| (m.key_start, m.value.end)).collect();
+        let spacing = self.container_spacing(span, &children, line_indent);
+        let indent = Self::child_indent(&spacing.0, line_indent);
+        //the space around the colon, as the other members have it
+        let colon = members.first().map(|m| &self.content[m.key_end..m.value.start]).unwrap_or(if self.indent.is_some() { ": " } else { ":" });
+
+        let mut pieces = vec![];
+        for (n, (key, value)) in updated.iter().enumerate() {
+            let same_key = members.iter().find(|m| m.key==*key);
+            //a renamed key stays where it was, with the same value
+            let renamed = members.get(n).filter(|m| !updated.contains_key(&m.key) && original.get(&m.key)==Some(value));
+            pieces.push(match (same_key, renamed, original.get(key)) {
+                (Some(member), _, Some(was))=>format!("{}{}", &self.content[member.key_start..member.value.start], self.render(&member.value, was, value, &indent)?),
+                (_, Some(member), _)=>format!("{}{}{}", serde_json::to_string(key)?, &self.content[member.key_end..member.value.start], &self.content[member.value.start..member.value.end]),
+                _=>format!("{}{}{}", serde_json::to_string(key)?, colon, self.fresh(value, &indent)?),
+            });
+        }
+        Ok( Self::join_children('{', '}', pieces, spacing) )
+    }
+
+    fn render_array(&self, span:&JsonSpan, items:&[JsonSpan], original:&[Value], updated:&[Value], line_indent:&str) -> Result<String, Box<dyn Error>> {
+        let children:Vec<(usize, usize)> = items.iter().map(|item| (item.start, item.end)).collect();
+        let spacing = self.container_spacing(span, &children, line_indent);
+        let indent = Self::child_indent(&spacing.0, line_indent);
+
+        //items are only ever set, removed or added on the end, so we can line the two arrays up as we go
+        let mut at = 0;
+        let mut pieces = vec![];
+        for value in updated {
+            if original.get(at + 1)==Some(value) && original.get(at) != Some(value) {
+                at += 1;
+            }
+            pieces.push(match (items.get(at), original.get(at)) {
+                (Some(item), Some(was))=>self.render(item, was, value, &indent)?,
+                _=>self.fresh(value, &indent)?,
+            });
+            at += 1;
+        }
+        Ok( Self::join_children('[', ']', pieces, spacing) )
+    }
+}
+
+/**
+ * JSON has no comments, but a file that is re-serialised comes back formatted our way rather than its own, so the
+ * edited document is written back out over the original text.  Only the values that the edits changed are written
+ * afresh, using the file's own indent; everything else, including key order, is kept as it was.
+ */
+fn edit_json(content:&str, edits:&[&StructuredEdit], changes:&mut Vec<String>) -> Result<String, Box<dyn Error>> {
+    let original:Value = serde_json::from_str(content)?;
+    let mut doc = original.clone();
+    for edit in edits {
+        let before = doc.clone();
+        json_edit(&mut doc, &parse_path(&edit.path)?, &edit.op)?;
+        if doc != before {
+            changes.push(edit.to_string());
+        }
+    }
+    if doc==original {
+        return Ok( content.to_string() );
+    }
+
+    let span = scan_json(content.as_bytes(), &mut 0)?;
+    let layout = JsonLayout {
+        content,
+        indent: json_indent(content),
+        newline: if content.contains("\r\n") { "\r\n" } else { "\n" },
+    };
+    let rendered = layout.render(&span, &original, &doc, "")?;
+    Ok( format!("{}{}{}", &content[..span.start], rendered, &content[span.end..]) )
+}
+
+/* ---------------------------------------- TOML ---------------------------------------- */
+
+//A container in a TOML document.  toml_edit has different types for each kind of table and array, so we need to
+//keep track of which one we are looking at.
+enum TomlNode<'d> {
+    Table(&'d mut Table),
+    Inline(&'d mut InlineTable),
+    Array(&'d mut toml_edit::Array),
+    Tables(&'d mut ArrayOfTables),
+}
+
+impl<'d> TomlNode<'d> {
+    fn from_item(item:&'d mut Item) -> Option<TomlNode<'d>> {
+        match item {
+            Item::Table(t)=>Some(TomlNode::Table(t)),
+            Item::ArrayOfTables(a)=>Some(TomlNode::Tables(a)),
+            Item::Value(value)=>TomlNode::from_value(value),
+            Item::None=>None,
+        }
+    }
+
+    fn from_value(value:&'d mut toml_edit::Value) -> Option<TomlNode<'d>> {
+        match value {
+            toml_edit::Value::InlineTable(t)=>Some(TomlNode::Inline(t)),
+            toml_edit::Value::Array(a)=>Some(TomlNode::Array(a)),
+            _=>None,
+        }
+    }
+
+    fn table_like(&mut self) -> Option<&mut dyn TableLike> {
+        match self {
+            TomlNode::Table(t)=>Some(*t),
+            TomlNode::Inline(t)=>Some(*t),
+            _=>None,
+        }
+    }
+
+    //Finds the child at the given segment.  If `create` is set then missing tables are created along the way.
+    fn child(self, segment:&PathSegment, create:bool) -> Result<Option<TomlNode<'d>>, Box<dyn Error>> {
+        match (self, segment) {
+            (TomlNode::Table(t), PathSegment::Key(k))=>{
+                if create && !t.contains_key(k) {
+                    let mut new_table = Table::new();
+                    new_table.set_implicit(true);
+                    t.insert(k, Item::Table(new_table));
+                }
+                match t.get_mut(k) {
+                    Some(item)=>TomlNode::from_item(item).map(Some).ok_or_else(|| Box::from(format!("{} is not a table or array", k))),
+                    None=>Ok(None),
+                }
+            },
+            (TomlNode::Inline(t), PathSegment::Key(k))=>{
+                if create && !t.contains_key(k) {
+                    t.insert(k, toml_edit::Value::InlineTable(InlineTable::new()));
+                }
+                match t.get_mut(k) {
+                    Some(value)=>TomlNode::from_value(value).map(Some).ok_or_else(|| Box::from(format!("{} is not a table or array", k))),
+                    None=>Ok(None),
+                }
+            },
+            (TomlNode::Array(a), PathSegment::Index(i))=>match a.get_mut(*i) {
+                Some(value)=>TomlNode::from_value(value).map(Some).ok_or_else(|| Box::from(format!("[{}] is not a table or array", i))),
+                None=>Ok(None),
+            },
+            (TomlNode::Tables(a), PathSegment::Index(i))=>Ok(a.get_mut(*i).map(TomlNode::Table)),
+            (_, segment)=>Err(Box::from(format!("{} does not match the type of the document", format_path(std::slice::from_ref(segment))))),
+        }
+    }
+}
+
+fn toml_find<'d>(doc:&'d mut DocumentMut, path:&[PathSegment], create:bool) -> Result<Option<TomlNode<'d>>, Box<dyn Error>> {
+    let mut current = TomlNode::Table(doc.as_table_mut());
+    for segment in path {
+        match current.child(segment, create)? {
+            Some(next)=>current = next,
+            None=>return Ok(None),
+        }
+    }
+    Ok(Some(current))
+}
+
+fn json_to_toml(value:&Value) -> Result<toml_edit::Value, Box<dyn Error>> {
+    match value {
+        Value::Null=>Err(Box::from("TOML does not have null values, use delete instead")),
+        Value::Bool(b)=>Ok(toml_edit::Value::from(*b)),
+        Value::Number(n)=>match n.as_i64() {
+            Some(i)=>Ok(toml_edit::Value::from(i)),
+            None=>Ok(toml_edit::Value::from(n.as_f64().unwrap_or_default())),
+        },
+        Value::String(s)=>Ok(toml_edit::Value::from(s.as_str())),
+        Value::Array(items)=>{
+            let mut arr = toml_edit::Array::new();
+            for item in items {
+                arr.push(json_to_toml(item)?);
+            }
+            Ok(toml_edit::Value::Array(arr))
+        },
+        Value::Object(map)=>{
+            let mut table = InlineTable::new();
+            for (k, v) in map {
+                table.insert(k, json_to_toml(v)?);
+            }
+            Ok(toml_edit::Value::InlineTable(table))
+        },
+    }
+}
+
+//Sets a key in a table, keeping the whitespace and comments around the old value if there was one
+fn toml_set_in_table(table:&mut dyn TableLike, key:&str, value:&Value) -> Result<(), Box<dyn Error>> {
+    let mut new_value = json_to_toml(value)?;
+    match table.get_mut(key) {
+        Some(Item::Value(old))=>{
+            *new_value.decor_mut() = old.decor().clone();
+            *old = new_value;
+        },
+        Some(old @ Item::Table(_))=>{
+            *old = match new_value {
+                toml_edit::Value::InlineTable(inline)=>Item::Table(inline.into_table()),
+                other=>Item::Value(other),
+            };
+        },
+        _=>{
+            table.insert(key, Item::Value(new_value));
+        }
+    }
+    Ok( () )
+}
+
+//Renames a key while keeping it in the same place, by moving it and every key after it to the end in turn
+fn toml_rename(table:&mut dyn TableLike, from:&str, to:&str) -> Result<(), Box<dyn Error>> {
+    if !table.contains_key(from) {
+        return Ok( () );
+    }
+    if table.contains_key(to) {
+        return Err(Box::from(format!("can't rename {} as {} already exists", from, to)));
+    }
+    let keys:Vec<String> = table.iter().map(|(k, _)| k.to_owned()).collect();
+    let position = keys.iter().position(|k| k==from).unwrap_or(0);
+    for k in keys[position..].iter() {
+        let old_key = table.key(k).cloned().unwrap_or_else(|| Key::new(k.as_str()));
+        if let Some(item) = table.remove(k) {
+            let new_key = if k==from { Key::new(to).with_leaf_decor(old_key.leaf_decor().clone()) } else { old_key };
+            table.entry_format(&new_key).or_insert(item);
+        }
+    }
+    Ok( () )
+}
+
+fn toml_edit_doc(doc:&mut DocumentMut, path:&[PathSegment], op:&EditOp) -> Result<(), Box<dyn Error>> {
+    let (last, parents) = path.split_last().ok_or("the path is empty")?;
+    match op {
+        EditOp::Set{value}=>{
+            let parent = toml_find(doc, parents, true)?.ok_or_else(|| format!("{} does not exist", format_path(parents)))?;
+            match (parent, last) {
+                (mut table @ (TomlNode::Table(_) | TomlNode::Inline(_)), PathSegment::Key(k))=>toml_set_in_table(table.table_like().unwrap(), k, value),
+                (TomlNode::Array(arr), PathSegment::Index(i)) if *i < arr.len() =>{
+                    arr.replace(*i, json_to_toml(value)?);
+                    Ok( () )
+                },
+                (TomlNode::Tables(tables), PathSegment::Index(i)) if *i < tables.len() =>match json_to_toml(value)? {
+                    toml_edit::Value::InlineTable(inline)=>{
+                        if let Some(table) = tables.get_mut(*i) {
+                            *table = inline.into_table();
+                        }
+                        Ok( () )
+                    },
+                    _=>Err(Box::from(format!("{} is a table, so it can only be set to an object", format_path(path)))),
+                },
+                _=>Err(Box::from(format!("{} can't be set", format_path(path)))),
+            }
+        },
+        EditOp::Delete=>{
+            match (toml_find(doc, parents, false)?, last) {
+                (Some(TomlNode::Table(t)), PathSegment::Key(k))=>{ t.remove(k); },
+                (Some(TomlNode::Inline(t)), PathSegment::Key(k))=>{ t.remove(k); },
+                (Some(TomlNode::Array(a)), PathSegment::Index(i)) if *i < a.len() =>{ a.remove(*i); },
+                (Some(TomlNode::Tables(a)), PathSegment::Index(i)) if *i < a.len() =>a.remove(*i),
+                _=>(),
+            }
+            Ok( () )
+        },
+        EditOp::Append{value}=>{
+            match toml_find(doc, path, false)? {
+                Some(TomlNode::Array(arr))=>{
+                    let mut new_value = json_to_toml(value)?;
+                    match arr.get(arr.len().wrapping_sub(1)) {
+                        //copy the formatting of the last item, so that multi-line arrays stay that way
+                        Some(last_item)=>{
+                            *new_value.decor_mut() = last_item.decor().clone();
+                            arr.push_formatted(new_value);
+                        },
+                        None=>arr.push(new_value),
+                    }
+                    Ok( () )
+                },
+                Some(TomlNode::Tables(tables))=>match json_to_toml(value)? {
+                    toml_edit::Value::InlineTable(inline)=>{
+                        tables.push(inline.into_table());
+                        Ok( () )
+                    },
+                    _=>Err(Box::from(format!("{} is an array of tables, so only objects can be appended", format_path(path)))),
+                },
+                Some(_)=>Err(Box::from(format!("{} is not an array", format_path(path)))),
+                None=>toml_edit_doc(doc, path, &EditOp::Set { value: Value::Array(vec![value.clone()]) }),
+            }
+        },
+        EditOp::Rename{to}=>{
+            match (toml_find(doc, parents, false)?, last) {
+                (Some(mut table @ (TomlNode::Table(_) | TomlNode::Inline(_))), PathSegment::Key(k))=>toml_rename(table.table_like().unwrap(), k, to),
+                _=>Ok( () ),
+            }
+        },
+    }
+}
+
+fn edit_toml(content:&str, edits:&[&StructuredEdit], changes:&mut Vec<String>) -> Result<String, Box<dyn Error>> {
+    let mut doc:DocumentMut = content.parse()?;
+    for edit in edits {
+        let before = doc.to_string();
+        toml_edit_doc(&mut doc, &parse_path(&edit.path)?, &edit.op)?;
+        if doc.to_string() != before {
+            changes.push(edit.to_string());
+        }
+    }
+    Ok(doc.to_string())
+}
+
+/* ---------------------------------------- YAML ---------------------------------------- */
+
+fn edit_yaml(content:&str, edits:&[&StructuredEdit], changes:&mut Vec<String>) -> Result<String, Box<dyn Error>> {
+    let mut doc = YamlDocument::new(content);
+    for edit in edits {
+        let before = doc.to_string();
+        let path = parse_path(&edit.path)?;
+        match &edit.op {
+            EditOp::Set{value}=>doc.set(&path, value)?,
+            EditOp::Delete=>doc.delete(&path)?,
+            EditOp::Append{value}=>doc.append(&path, value)?,
+            EditOp::Rename{to}=>doc.rename(&path, to)?,
+        }
+        if doc.to_string() != before {
+            changes.push(edit.to_string());
+        }
+    }
+    Ok(doc.to_string())
+}
+
+/**
+ * Runs the edits over every file in the repo that they match.  Every file is edited in memory before anything is
+ * written, so if one of them fails then the repo is left alone.  Returns a description of the edits that made a change.
+ */
+pub fn apply_edits(spec:&EditSpec, repo:&Repository) -> Result<String, Box<dyn Error>> {
+    let root = repo.workdir().ok_or("the repo has no working directory")?;
+    let globs = spec.edits.iter().map(|e| glob_to_regex(&e.file)).collect::<Result<Vec<Regex>, Box<dyn Error>>>()?;
+
+    let mut files:Vec<String> = vec![];
+    list_files(repo, root, root, &mut files)?;
+    files.sort();
+
+    let mut msg = String::new();
+    for (edit, glob) in spec.edits.iter().zip(globs.iter()) {
+        if !files.iter().any(|f| glob.is_match(f)) {
+            writeln!(msg, "{}: no files matched", edit.file)?;
+        }
+    }
+
+    let mut updates:Vec<(PathBuf, String)> = vec![];
+    let mut total_changes = 0;
+    for file in files.iter() {
+        let edits:Vec<&StructuredEdit> = spec.edits.iter().zip(globs.iter())
+            .filter(|(_, glob)| glob.is_match(file))
+            .map(|(edit, _)| edit)
+            .collect();
+        if edits.is_empty() {
+            continue;
+        }
+
+        let format = edits.iter().find_map(|e| e.format).or_else(|| FileFormat::from_path(file))
+            .ok_or_else(|| format!("{}: can't tell what format this is, add \"format\" to the edit", file))?;
+        let full_path = root.join(file);
+        let content = fs::read_to_string(&full_path)?;
+
+        let mut changes:Vec<String> = vec![];
+        let result = match format {
+            FileFormat::Json=>edit_json(&content, &edits, &mut changes),
+            FileFormat::Yaml=>edit_yaml(&content, &edits, &mut changes),
+            FileFormat::Toml=>edit_toml(&content, &edits, &mut changes),
+        };
+        let updated = result.map_err(|e| format!("{}: {}", file, e))?;
+
+        if !changes.is_empty() && updated != content {
+            debug!("{} edits changed {}", changes.len(), file);
+            for change in changes.iter() {
+                writeln!(msg, "{}: {}", file, change)?;
+            }
+            total_changes += changes.len();
+            updates.push((full_path, updated));
+        }
+    }
+
+    for (path, updated) in updates.iter() {
+        fs::write(path, updated.as_bytes())?;
+    }
+    write!(msg, "{} edits made to {} files", total_changes, updates.len())?;
+    Ok(msg)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use tempfile::TempDir;
+
+    fn edit(file:&str, path:&str, op:EditOp) -> StructuredEdit {
+        StructuredEdit {
+            file: file.to_string(),
+            format: None,
+            path: path.to_string(),
+            op,
+        }
+    }
+
+    #[test]
+    fn test_parse_path() -> Result<(), Box<dyn Error>> {
+        assert_eq!(parse_path("jobs.build.steps[0].uses")?, vec![
+            PathSegment::Key("jobs".to_string()),
+            PathSegment::Key("build".to_string()),
+            PathSegment::Key("steps".to_string()),
+            PathSegment::Index(0),
+            PathSegment::Key("uses".to_string()),
+        ]);
+        assert_eq!(parse_path("dependencies.\"serde.json\"")?, vec![
+            PathSegment::Key("dependencies".to_string()),
+            PathSegment::Key("serde.json".to_string()),
+        ]);
+        assert_eq!(format_path(&parse_path("a.\"b.c\"[2]")?), "a.\"b.c\"[2]");
+        assert!(parse_path("a..b").is_err());
+        assert!(parse_path("a[x]").is_err());
+        assert!(parse_path("").is_err());
+        Ok( () )
+    }
+
+    #[test]
+    fn test_edit_json() -> Result<(), Box<dyn Error>> {
+        let content = "{\n    \"name\": \"thing\",\n    \"scripts\": {\n        \"test\": \"jest\",\n        \"build\": \"tsc\"\n    },\n    \"files\": [\n        \"lib\"\n    ]\n}\n";
+        let edits = [
+            edit("package.json", "engines.node", EditOp::Set { value: json!(">=20") }),
+            edit("package.json", "scripts.test", EditOp::Rename { to: "check".to_string() }),
+            edit("package.json", "files", EditOp::Append { value: json!("dist") }),
+            edit("package.json", "missing", EditOp::Delete),
+        ];
+        let mut changes = vec![];
+        let updated = edit_json(content, &edits.iter().collect::<Vec<_>>(), &mut changes)?;
+
+        assert_eq!(updated, "{\n    \"name\": \"thing\",\n    \"scripts\": {\n        \"check\": \"jest\",\n        \"build\": \"tsc\"\n    },\n    \"files\": [\n        \"lib\",\n        \"dist\"\n    ],\n    \"engines\": {\n        \"node\": \">=20\"\n    }\n}\n");
+        assert_eq!(changes, vec!["set engines.node", "rename scripts.test to check", "append to files"]);
+        Ok( () )
+    }
+
+    #[test]
+    fn test_edit_json_keeps_formatting() -> Result<(), Box<dyn Error>> {
+        let content = "{\n  \"name\": \"thing\",\n  \"version\": \"1.0.0\",\n  \"files\": [\"dist\"],\n  \"keywords\": [\"a\", \"b\"],\n  \"size\": 1.50,\n  \"home\": \"https:\\/\\/example.com\\u00e9\",\n  \"engines\": { \"node\": \">=16\" }\n}\n";
+        let edits = [edit("package.json", "version", EditOp::Set { value: json!("2.0.0") })];
+        let updated = edit_json(content, &edits.iter().collect::<Vec<_>>(), &mut vec![])?;
+        assert_eq!(updated, content.replace("1.0.0", "2.0.0"));
+
+        //compact arrays and objects stay compact when they are edited
+        let edits = [
+            edit("package.json", "keywords", EditOp::Append { value: json!("c") }),
+            edit("package.json", "engines.npm", EditOp::Set { value: json!(">=8") }),
+            edit("package.json", "files[0]", EditOp::Delete),
+        ];
+        let updated = edit_json(content, &edits.iter().collect::<Vec<_>>(), &mut vec![])?;
+        assert_eq!(updated, content
+            .replace("[\"a\", \"b\"]", "[\"a\", \"b\", \"c\"]")
+            .replace("{ \"node\": \">=16\" }", "{ \"node\": \">=16\", \"npm\": \">=8\" }")
+            .replace("[\"dist\"]", "[]"));
+
+        let one_line = "{\"a\":[1,2],\"b\":{}}";
+        let edits = [edit("x.json", "b.c", EditOp::Set { value: json!(true) }), edit("x.json", "a[0]", EditOp::Delete)];
+        assert_eq!(edit_json(one_line, &edits.iter().collect::<Vec<_>>(), &mut vec![])?, "{\"a\":[2],\"b\":{\"c\":true}}");
+
+        let crlf = "{\r\n  \"a\": 1\r\n}\r\n";
+        let edits = [edit("x.json", "b.c", EditOp::Set { value: json!(2) })];
+        assert_eq!(edit_json(crlf, &edits.iter().collect::<Vec<_>>(), &mut vec![])?, "{\r\n  \"a\": 1,\r\n  \"b\": {\r\n    \"c\": 2\r\n  }\r\n}\r\n");
+        Ok( () )
+    }
+
+    //Relies on serde_json's preserve_order feature; without it the keys would come out sorted
+    #[test]
+    fn test_edit_json_key_order() -> Result<(), Box<dyn Error>> {
+        let content = "{\n  \"version\": \"1.0.0\",\n  \"name\": \"thing\",\n  \"dependencies\": {\n    \"zod\": \"^3\",\n    \"axios\": \"^1\"\n  }\n}\n";
+        let edits = [edit("package.json", "dependencies.lodash", EditOp::Set { value: json!("^4") })];
+        let updated = edit_json(content, &edits.iter().collect::<Vec<_>>(), &mut vec![])?;
+        assert_eq!(updated, "{\n  \"version\": \"1.0.0\",\n  \"name\": \"thing\",\n  \"dependencies\": {\n    \"zod\": \"^3\",\n    \"axios\": \"^1\",\n    \"lodash\": \"^4\"\n  }\n}\n");
+        Ok( () )
+    }
+
+    #[test]
+    fn test_edit_toml() -> Result<(), Box<dyn Error>> {
+        let content = "[package]\nname = \"thing\" # the name\nversion = \"0.1.0\"\n\n[dependencies]\nregex = \"1.10\"\nlog = \"0.4\"\nold = \"1\"\n\n[features]\ndefault = [\n    \"a\",\n]\n";
+        let edits = [
+            edit("Cargo.toml", "package.name", EditOp::Set { value: json!("other") }),
+            edit("Cargo.toml", "dependencies.regex", EditOp::Rename { to: "fancy-regex".to_string() }),
+            edit("Cargo.toml", "dependencies.old", EditOp::Delete),
+            edit("Cargo.toml", "features.default", EditOp::Append { value: json!("b") }),
+            edit("Cargo.toml", "package.metadata.docs.all", EditOp::Set { value: json!(true) }),
+        ];
+        let mut changes = vec![];
+        let updated = edit_toml(content, &edits.iter().collect::<Vec<_>>(), &mut changes)?;
+
+        assert_eq!(updated, "[package]\nname = \"other\" # the name\nversion = \"0.1.0\"\n\n[package.metadata.docs]\nall = true\n\n[dependencies]\nfancy-regex = \"1.10\"\nlog = \"0.4\"\n\n[features]\ndefault = [\n    \"a\",\n    \"b\",\n]\n");
+        assert_eq!(changes.len(), 5);
+
+        let mut doc:DocumentMut = content.parse()?;
+        assert!(toml_edit_doc(&mut doc, &parse_path("package.name")?, &EditOp::Set { value: Value::Null }).is_err());
+        Ok( () )
+    }
+
+    #[test]
+    fn test_apply_edits() -> Result<(), Box<dyn Error>> {
+        let dir = TempDir::new()?;
+        let repo = Repository::init(dir.path())?;
+        fs::create_dir_all(dir.path().join(".github/workflows"))?;
+        fs::write(dir.path().join("package.json"), "{\n  \"engines\": {\n    \"node\": \">=16\"\n  }\n}\n")?;
+        fs::write(dir.path().join(".github/workflows/ci.yml"), "# CI\non: push\njobs:\n  build:\n    runs-on: ubuntu-20.04 # old\n")?;
+        fs::write(dir.path().join(".github/workflows/other.yml"), "jobs:\n  lint:\n    runs-on: ubuntu-22.04\n")?;
+
+        let spec:EditSpec = serde_json::from_value(json!({
+            "edits": [
+                {"file": "package.json", "op": "set", "path": "engines.node", "value": ">=20"},
+                {"file": ".github/workflows/*.yml", "op": "set", "path": "jobs.build.runs-on", "value": "ubuntu-latest"},
+                {"file": "*.toml", "op": "delete", "path": "anything"}
+            ]
+        }))?;
+        let msg = apply_edits(&spec, &repo)?;
+
+        assert_eq!(fs::read_to_string(dir.path().join("package.json"))?, "{\n  \"engines\": {\n    \"node\": \">=20\"\n  }\n}\n");
+        assert_eq!(fs::read_to_string(dir.path().join(".github/workflows/ci.yml"))?, "# CI\non: push\njobs:\n  build:\n    runs-on: ubuntu-latest # old\n");
+        //other.yml has no jobs.build, so set adds it
+        assert_eq!(fs::read_to_string(dir.path().join(".github/workflows/other.yml"))?, "jobs:\n  lint:\n    runs-on: ubuntu-22.04\n  build:\n    runs-on: ubuntu-latest\n");
+        assert_eq!(msg, "*.toml: no files matched\n.github/workflows/ci.yml: set jobs.build.runs-on\n.github/workflows/other.yml: set jobs.build.runs-on\npackage.json: set engines.node\n3 edits made to 3 files");
+        Ok( () )
+    }
+}
@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+use std::error::Error;
+use regex::{Captures, Regex};
+
+/**
+ * The placeholders that can be used in the PR title and description.  They are written like `{{repo}}`, and are filled
+ * in separately for each repo when its PR is created.
+ */
+pub const PLACEHOLDERS:[&str; 9] = [
+    "owner",
+    "name",
+    "repo",
+    "default_branch",
+    "branch",
+    "files_changed",
+    "files_changed_count",
+    "patch_summary",
+    "patch_output",
+];
+
+fn placeholder_re() -> Regex {
+    Regex::new(r"\{\{\s*([A-Za-z_]+)\s*\}\}").unwrap()
+}
+
+/**
+ * Checks that a template only uses placeholders that we know how to fill in, so that a typo is caught before
+ * anything is done rather than ending up in a load of PRs
+ */
+pub fn check_template(template:&str) -> Result<(), Box<dyn Error>> {
+    let unknown:Vec<String> = placeholder_re().captures_iter(template)
+        .map(|caps| caps[1].to_string())
+        .filter(|name| !PLACEHOLDERS.contains(&name.as_str()))
+        .collect();
+
+    if unknown.is_empty() {
+        Ok( () )
+    } else {
+        Err(Box::from(format!("unknown placeholder(s) {}; the ones available are {}", unknown.join(", "), PLACEHOLDERS.join(", "))))
+    }
+}
+
+/**
+ * Fills in the placeholders in the template.  Any that there is no value for are left as they are.
+ */
+pub fn render_template(template:&str, values:&HashMap<&str, String>) -> String {
+    placeholder_re().replace_all(template, |caps:&Captures| {
+        match values.get(&caps[1]) {
+            Some(value)=>value.to_owned(),
+            None=>caps[0].to_string(),
+        }
+    }).to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render_template() {
+        let values = HashMap::from([
+            ("repo", "my-org/thing".to_string()),
+            ("files_changed_count", "2".to_string()),
+        ]);
+
+        assert_eq!(render_template("Fix {{repo}} ({{ files_changed_count }} files)", &values), "Fix my-org/thing (2 files)");
+        assert_eq!(render_template("{{branch}} stays", &values), "{{branch}} stays");
+        assert_eq!(render_template("no placeholders {here}", &values), "no placeholders {here}");
+    }
+
+    #[test]
+    fn test_check_template() {
+        assert!(check_template("Upgrade {{repo}} on {{default_branch}}\n\n{{patch_summary}}").is_ok());
+        let err = check_template("Upgrade {{repository}}").unwrap_err();
+        assert!(err.to_string().contains("repository"));
+    }
+}
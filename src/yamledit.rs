@@ -0,0 +1,787 @@
+use std::error::Error;
+use std::fmt::{self, Display};
+use regex::Regex;
+use serde_json::Value;
+
+use crate::structured::{format_path, nest_value, PathSegment};
+
+/**
+ * A YAML document that we edit as lines of text, so that comments, blank lines, quoting and indentation all survive.
+ * Only block-style mappings and sequences can be navigated into; flow collections (`[a, b]`, `{a: b}`) and multi-line
+ * scalars are treated as single values, although an item can be appended to a flow sequence that is on one line.
+ * Anything we can't edit safely, like a value with an anchor that other parts of the document may refer to, is an error
+ * rather than a guess.
+ * The structure is re-parsed after every edit, which is plenty fast enough for the size of file we deal with.
+ */
+pub struct YamlDocument {
+    lines: Vec<String>,
+    crlf: bool,
+    trailing_newline: bool,
+}
+
+#[derive(Debug)]
+enum Node {
+    //a value that starts at `col` on the given line, and runs to `end_col` (any trailing comment is after that)
+    Scalar { line: usize, col: usize, end_col: usize },
+    //a key or dash with nothing after it
+    Empty,
+    Mapping(Vec<Slot>),
+    Sequence(Vec<Slot>),
+}
+
+/**
+ * A place that holds a value: either a `key: value` entry in a mapping or a `- value` item in a sequence.
+ * `col` is the column of the key or dash, which may be after a `- ` on the same line for the first key of a mapping
+ * inside a sequence.  `end` is the line after the last significant line that belongs to the slot.
+ */
+#[derive(Debug)]
+struct Slot {
+    key: Option<KeySpan>,
+    line: usize,
+    col: usize,
+    //the column just after the colon or dash
+    value_col: usize,
+    end: usize,
+    value: Node,
+}
+
+#[derive(Debug)]
+struct KeySpan {
+    name: String,
+    end_col: usize,
+}
+
+fn indent_of(line:&str) -> usize {
+    line.len() - line.trim_start_matches(' ').len()
+}
+
+//Blank lines, comments and document markers don't affect the structure
+fn is_significant(line:&str) -> bool {
+    let trimmed = line.trim();
+    !(trimmed.is_empty() || trimmed.starts_with('#') || trimmed=="---" || trimmed=="..." || line.starts_with('%'))
+}
+
+//Whether the line ends with the indicator for a literal or folded block scalar, e.g. `run: |` or `- >-`
+fn is_block_scalar_header(line:&str) -> bool {
+    let header_re = Regex::new(r"(^|\s)[|>][1-9+-]{0,2}$").unwrap();
+    header_re.is_match(line[..comment_start(line, 0)].trim_end())
+}
+
+//Whether the line defines an anchor (`&name`), ignoring any comment
+fn defines_anchor(line:&str) -> bool {
+    let anchor_re = Regex::new(r"(^|[\s\[{,])&[^\s\[\]{},]+").unwrap();
+    anchor_re.is_match(&line[..comment_start(line, 0)])
+}
+
+fn is_dash(text:&str) -> bool {
+    text=="-" || text.starts_with("- ") || text.starts_with("-\t")
+}
+
+//Returns the column where a trailing comment starts, or the end of the line if there isn't one
+fn comment_start(line:&str, from:usize) -> usize {
+    let bytes = line.as_bytes();
+    let mut quote:Option<u8> = None;
+    let quotable = matches!(bytes.get(from), Some(b'"') | Some(b'\'') | Some(b'[') | Some(b'{'));
+    let mut i = from;
+    while i < bytes.len() {
+        let c = bytes[i];
+        match quote {
+            Some(b'"') if c==b'\\' => i += 1,
+            Some(q) if c==q => quote = None,
+            Some(_)=>(),
+            None if quotable && (c==b'"' || c==b'\'') => quote = Some(c),
+            None if c==b'#' && (i==from || bytes[i-1]==b' ' || bytes[i-1]==b'\t') => return i,
+            None=>(),
+        }
+        i += 1;
+    }
+    bytes.len()
+}
+
+//The end of the value that starts at `from`, leaving out any comment and trailing whitespace
+fn value_end(line:&str, from:usize) -> usize {
+    let end = comment_start(line, from);
+    from + line[from..end].trim_end().len()
+}
+
+/**
+ * Checks whether the text starts with a mapping key, and if so returns the key, the column after the raw key text
+ * and the column of the colon (all relative to the start of `text`)
+ */
+fn match_key(text:&str) -> Option<(String, usize, usize)> {
+    let (name, raw_end) = match text.chars().next()? {
+        '"'=>{
+            let bytes = text.as_bytes();
+            let mut i = 1;
+            while i < bytes.len() && bytes[i] != b'"' {
+                if bytes[i]==b'\\' {
+                    i += 1;
+                }
+                i += 1;
+            }
+            if i >= bytes.len() {
+                return None;
+            }
+            (serde_json::from_str::<String>(&text[..=i]).ok()?, i + 1)
+        },
+        '\''=>{
+            let bytes = text.as_bytes();
+            let mut i = 1;
+            loop {
+                if i >= bytes.len() {
+                    return None;
+                }
+                if bytes[i]==b'\'' {
+                    if bytes.get(i+1)==Some(&b'\'') {
+                        i += 2;
+                        continue;
+                    }
+                    break;
+                }
+                i += 1;
+            }
+            (text[1..i].replace("''", "'"), i + 1)
+        },
+        '[' | '{' | '#' | '&' | '*' | '!' | '|' | '>' | '%' | '@' | '`' | '-' | '?' =>return None,
+        _=>{
+            let comment = comment_start(text, 0);
+            let colon = text[..comment].match_indices(':')
+                .map(|(idx, _)| idx)
+                .find(|idx| matches!(text.as_bytes().get(idx + 1), None | Some(b' ') | Some(b'\t')))?;
+            let name = text[..colon].trim_end();
+            if name.is_empty() {
+                return None;
+            }
+            (name.to_string(), name.len())
+        }
+    };
+
+    let after = &text[raw_end..];
+    let colon = raw_end + after.len() - after.trim_start().len();
+    if text[colon..].starts_with(':') && matches!(text.as_bytes().get(colon + 1), None | Some(b' ') | Some(b'\t')) {
+        Some((name, raw_end, colon))
+    } else {
+        None
+    }
+}
+
+fn quoted(s:&str) -> String {
+    serde_json::to_string(s).unwrap_or_else(|_| format!("\"{}\"", s))
+}
+
+//Strings that would be read back as something else (a number, bool or null) or that can't be written plain need quoting
+fn needs_quotes(s:&str) -> bool {
+    let special_re = Regex::new(r"(?i)^(true|false|yes|no|on|off|y|n|null|~|[-+]?\.(inf|nan)|[-+]?(0x[0-9a-f_]+|0o[0-7_]+|[0-9][0-9_:]*(\.[0-9_]*)?(e[-+]?[0-9]+)?))$").unwrap();
+    s.is_empty()
+        || s.trim() != s
+        || special_re.is_match(s)
+        || s.starts_with(['-', '?', ':', ',', '[', ']', '{', '}', '#', '&', '*', '!', '|', '>', '\'', '"', '%', '@', '`'])
+        || s.contains(": ") || s.contains(" #") || s.ends_with(':')
+        || s.contains(['\n', '\t', '\r'])
+}
+
+/**
+ * Writes a single value in a form that fits on one line.  If we are replacing an existing string then its quoting
+ * style is kept where we can.
+ */
+fn render_scalar(value:&Value, old:Option<&str>) -> String {
+    match value {
+        Value::String(s)=>match old.and_then(|o| o.chars().next()) {
+            Some('\'') if !s.contains(['\n', '\r']) => format!("'{}'", s.replace('\'', "''")),
+            Some('"')=>quoted(s),
+            _ if needs_quotes(s) => quoted(s),
+            _=>s.to_owned(),
+        },
+        Value::Null=>"null".to_string(),
+        Value::Bool(_) | Value::Number(_)=>value.to_string(),
+        //JSON is valid YAML flow style
+        Value::Array(_) | Value::Object(_)=>value.to_string(),
+    }
+}
+
+fn render_key(key:&str) -> String {
+    if needs_quotes(key) {
+        quoted(key)
+    } else {
+        key.to_owned()
+    }
+}
+
+fn is_block_collection(value:&Value) -> bool {
+    match value {
+        Value::Array(items)=>!items.is_empty(),
+        Value::Object(map)=>!map.is_empty(),
+        _=>false,
+    }
+}
+
+//How the document lays out nested blocks
+#[derive(Debug, Clone, Copy)]
+struct Style {
+    step: usize,
+    //whether a sequence under a key is indented (`key:\n  - a`) or not (`key:\n- a`)
+    indent_sequences: bool,
+}
+
+//Writes a mapping or sequence as block-style lines at the given indent
+fn render_block(value:&Value, indent:usize, style:Style) -> Vec<String> {
+    let pad = " ".repeat(indent);
+    let mut lines:Vec<String> = vec![];
+    match value {
+        Value::Object(map)=>for (k, v) in map {
+            if is_block_collection(v) {
+                lines.push(format!("{}{}:", pad, render_key(k)));
+                let child_indent = if v.is_array() && !style.indent_sequences { indent } else { indent + style.step };
+                lines.extend(render_block(v, child_indent, style));
+            } else {
+                lines.push(format!("{}{}: {}", pad, render_key(k), render_scalar(v, None)));
+            }
+        },
+        Value::Array(items)=>for item in items {
+            lines.extend(render_item(item, indent, style));
+        },
+        other=>lines.push(format!("{}{}", pad, render_scalar(other, None))),
+    }
+    lines
+}
+
+//Writes a sequence item with its dash at the given indent.  Collections start on the same line as the dash.
+fn render_item(value:&Value, indent:usize, style:Style) -> Vec<String> {
+    let pad = " ".repeat(indent);
+    if is_block_collection(value) {
+        let mut lines = render_block(value, indent + 2, style);
+        lines[0] = format!("{}- {}", pad, lines[0].trim_start());
+        lines
+    } else {
+        vec![format!("{}- {}", pad, render_scalar(value, None))]
+    }
+}
+
+impl YamlDocument {
+    pub fn new(content:&str) -> YamlDocument {
+        let trailing_newline = content.ends_with('\n');
+        let body = content.strip_suffix('\n').unwrap_or(content);
+        let lines = if content.is_empty() {
+            vec![]
+        } else {
+            body.split('\n').map(|l| l.strip_suffix('\r').unwrap_or(l).to_string()).collect()
+        };
+        YamlDocument {
+            lines,
+            crlf: content.contains("\r\n"),
+            trailing_newline: trailing_newline || content.is_empty(),
+        }
+    }
+
+    /**
+     * Whether a non-blank line is part of the content of a block scalar.  Walks back up through lines that are less and
+     * less indented until it finds a block scalar header (it is content) or some other line that can't be one.
+     */
+    fn in_block_scalar(&self, line:usize) -> bool {
+        if self.lines[line].trim().is_empty() {
+            return false;
+        }
+        let mut indent = indent_of(&self.lines[line]);
+        for i in (0..line).rev() {
+            let text = &self.lines[i];
+            if text.trim().is_empty() || indent_of(text) >= indent {
+                continue;
+            }
+            if is_block_scalar_header(text) {
+                return true;
+            }
+            indent = indent_of(text);
+            if indent==0 {
+                break;
+            }
+        }
+        false
+    }
+
+    //Content lines of a block scalar count even if they look like comments or document markers
+    fn is_significant_line(&self, line:usize) -> bool {
+        is_significant(&self.lines[line]) || self.in_block_scalar(line)
+    }
+
+    fn next_significant(&self, from:usize, to:usize) -> Option<usize> {
+        (from..to).find(|i| self.is_significant_line(*i))
+    }
+
+    //The line after the last significant line in the range
+    fn significant_end(&self, from:usize, to:usize) -> usize {
+        (from..to).rev().find(|i| self.is_significant_line(*i)).map(|i| i + 1).unwrap_or(from + 1)
+    }
+
+    //Deleting or replacing an anchor would leave any aliases to it pointing at nothing
+    fn check_no_anchor(&self, slot:&Slot, path:&[PathSegment]) -> Result<(), Box<dyn Error>> {
+        match (slot.line..slot.end).any(|i| defines_anchor(&self.lines[i])) {
+            true=>Err(Box::from(format!("{} defines a YAML anchor that other values may refer to, so it can't be changed", format_path(path)))),
+            false=>Ok( () ),
+        }
+    }
+
+    fn parse(&self) -> Node {
+        match self.next_significant(0, self.lines.len()) {
+            Some(first)=>self.parse_block(first, indent_of(&self.lines[first]), self.lines.len()),
+            None=>Node::Empty,
+        }
+    }
+
+    //Parses the block that starts at (line, col) and carries on up to `end`
+    fn parse_block(&self, line:usize, col:usize, end:usize) -> Node {
+        let text = &self.lines[line][col..];
+        if is_dash(text) {
+            self.parse_sequence(line, col, end)
+        } else if match_key(text).is_some() {
+            self.parse_mapping(line, col, end)
+        } else {
+            Node::Scalar { line, col, end_col: value_end(&self.lines[line], col) }
+        }
+    }
+
+    //Finds the lines that start a sibling at `col`, after the first one at `first`
+    fn sibling_starts(&self, first:usize, col:usize, end:usize, is_start:impl Fn(&str)->bool) -> Vec<usize> {
+        let mut starts = vec![first];
+        for i in first+1..end {
+            let line = &self.lines[i];
+            if self.is_significant_line(i) && indent_of(line)==col && is_start(&line[col..]) {
+                starts.push(i);
+            }
+        }
+        starts
+    }
+
+    //Parses whatever comes after a key or dash: either something on the same line, or a nested block on the following lines
+    fn parse_value(&self, line:usize, value_col:usize, end:usize, is_item:bool) -> Node {
+        let text = &self.lines[line];
+        let content_col = value_col + text[value_col..].len() - text[value_col..].trim_start().len();
+        if content_col < value_end(text, content_col) {
+            if is_item {
+                //a sequence item can hold a mapping or sequence that starts on the same line
+                self.parse_block(line, content_col, end)
+            } else {
+                Node::Scalar { line, col: content_col, end_col: value_end(text, content_col) }
+            }
+        } else {
+            match self.next_significant(line + 1, end) {
+                Some(child)=>self.parse_block(child, indent_of(&self.lines[child]), end),
+                None=>Node::Empty,
+            }
+        }
+    }
+
+    fn parse_mapping(&self, first:usize, col:usize, end:usize) -> Node {
+        let starts = self.sibling_starts(first, col, end, |text| !is_dash(text) && match_key(text).is_some());
+        let mut slots = vec![];
+        for (n, start) in starts.iter().enumerate() {
+            let block_end = starts.get(n + 1).copied().unwrap_or(end);
+            let slot_end = self.significant_end(*start, block_end);
+            if let Some((name, raw_end, colon)) = match_key(&self.lines[*start][col..]) {
+                slots.push(Slot {
+                    key: Some(KeySpan { name, end_col: col + raw_end }),
+                    line: *start,
+                    col,
+                    value_col: col + colon + 1,
+                    end: slot_end,
+                    value: self.parse_value(*start, col + colon + 1, slot_end, false),
+                });
+            }
+        }
+        Node::Mapping(slots)
+    }
+
+    fn parse_sequence(&self, first:usize, col:usize, end:usize) -> Node {
+        let starts = self.sibling_starts(first, col, end, is_dash);
+        let mut slots = vec![];
+        for (n, start) in starts.iter().enumerate() {
+            let block_end = starts.get(n + 1).copied().unwrap_or(end);
+            let slot_end = self.significant_end(*start, block_end);
+            slots.push(Slot {
+                key: None,
+                line: *start,
+                col,
+                value_col: col + 1,
+                end: slot_end,
+                value: self.parse_value(*start, col + 1, slot_end, true),
+            });
+        }
+        Node::Sequence(slots)
+    }
+
+    fn style(&self, root:&Node) -> Style {
+        let step = self.lines.iter()
+            .filter(|l| is_significant(l))
+            .map(|l| indent_of(l))
+            .filter(|i| *i > 0)
+            .min()
+            .unwrap_or(2);
+
+        //look for the first sequence under a key to see how it is indented
+        fn find_sequence(node:&Node) -> Option<bool> {
+            match node {
+                Node::Mapping(slots)=>slots.iter().find_map(|s| match &s.value {
+                    Node::Sequence(items)=>items.first().map(|item| item.col > s.col),
+                    other=>find_sequence(other),
+                }),
+                Node::Sequence(slots)=>slots.iter().find_map(|s| find_sequence(&s.value)),
+                _=>None,
+            }
+        }
+
+        Style { step, indent_sequences: find_sequence(root).unwrap_or(true) }
+    }
+
+    /**
+     * Follows the path as far as it exists.  Returns the slots along the way, so if all of them were found then the
+     * result has the same length as the path.
+     */
+    fn resolve<'n>(&self, root:&'n Node, path:&[PathSegment]) -> Result<Vec<&'n Slot>, Box<dyn Error>> {
+        let mut chain:Vec<&Slot> = vec![];
+        for (depth, segment) in path.iter().enumerate() {
+            let current = chain.last().map(|s| &s.value).unwrap_or(root);
+            let next = match (current, segment) {
+                (Node::Mapping(slots), PathSegment::Key(k))=>slots.iter().find(|s| s.key.as_ref().map(|key| &key.name)==Some(k)),
+                (Node::Sequence(slots), PathSegment::Index(i))=>slots.get(*i),
+                (node, _) if self.is_null(node) => None,
+                (node@Node::Scalar{..}, _)=>{
+                    let msg = match self.scalar_text(node).and_then(|t| t.chars().next()) {
+                        Some('&') | Some('*')=>"uses a YAML anchor or alias, which can't be edited through",
+                        Some('{')=>"is a flow mapping, which can't be edited inside",
+                        Some('[')=>"is a flow sequence, which can't be edited inside",
+                        _=>"is not a mapping or sequence",
+                    };
+                    return Err(Box::from(format!("{} {}", format_path(&path[..depth]), msg)));
+                },
+                _=>return Err(Box::from(format!("{} does not match the type of the document", format_path(&path[..=depth])))),
+            };
+            match next {
+                Some(slot)=>chain.push(slot),
+                None=>break,
+            }
+        }
+        Ok(chain)
+    }
+
+    fn splice(&mut self, from:usize, to:usize, replacement:Vec<String>) {
+        self.lines.splice(from..to, replacement);
+    }
+
+    fn scalar_text(&self, node:&Node) -> Option<&str> {
+        match node {
+            Node::Scalar{line, col, end_col}=>Some(&self.lines[*line][*col..*end_col]),
+            _=>None,
+        }
+    }
+
+    fn is_null(&self, node:&Node) -> bool {
+        match node {
+            Node::Empty=>true,
+            other=>matches!(self.scalar_text(other), Some("null") | Some("~")),
+        }
+    }
+
+    //Replaces whatever is in the slot with the new value
+    fn replace_value(&mut self, slot:&Slot, value:&Value, style:Style) {
+        let line = &self.lines[slot.line];
+        let head = line[..slot.value_col].to_string();
+        //anything after the colon or dash is either the old value, or a comment that we want to keep
+        let rest = line[slot.value_col..].trim_end();
+        let (old_text, comment) = match &slot.value {
+            Node::Scalar{line:l, col, end_col} if *l==slot.line => (Some(line[*col..*end_col].to_string()), line[*end_col..].to_string()),
+            _ if rest.trim_start().starts_with('#') => (None, rest.to_string()),
+            _=>(None, String::new()),
+        };
+
+        let replacement = if !is_block_collection(value) {
+            vec![format!("{} {}{}", head, render_scalar(value, old_text.as_deref()), comment)]
+        } else if slot.key.is_some() {
+            let child_indent = if value.is_array() && !style.indent_sequences { slot.col } else { slot.col + style.step };
+            let mut lines = vec![format!("{}{}", head, comment)];
+            lines.extend(render_block(value, child_indent, style));
+            lines
+        } else {
+            let mut lines = render_block(value, slot.col + 2, style);
+            lines[0] = format!("{} {}", head, lines[0].trim_start());
+            lines
+        };
+        self.splice(slot.line, slot.end, replacement);
+    }
+
+    //Adds a new key to the end of a mapping
+    fn insert_entry(&mut self, slots:&[Slot], key:&str, value:&Value, style:Style) {
+        let (at, indent) = match slots.last() {
+            Some(last)=>(last.end, slots[0].col),
+            None=>(self.lines.len(), 0),
+        };
+        let mut map = serde_json::Map::new();
+        map.insert(key.to_owned(), value.clone());
+        let lines = render_block(&Value::Object(map), indent, style);
+        self.splice(at, at, lines);
+    }
+
+    pub fn set(&mut self, path:&[PathSegment], value:&Value) -> Result<(), Box<dyn Error>> {
+        let root = self.parse();
+        let style = self.style(&root);
+        let chain = self.resolve(&root, path)?;
+
+        if chain.len()==path.len() {
+            self.check_no_anchor(chain[chain.len()-1], path)?;
+            self.replace_value(chain[chain.len()-1], value, style);
+            return Ok( () );
+        }
+
+        let key = match &path[chain.len()] {
+            PathSegment::Key(k)=>k,
+            PathSegment::Index(i)=>return Err(Box::from(format!("there is no item [{}] in {}", i, format_path(&path[..chain.len()])))),
+        };
+        let nested = nest_value(&path[chain.len()+1..], value)?;
+        let parent = chain.last().map(|s| &s.value).unwrap_or(&root);
+        match (parent, chain.last()) {
+            (Node::Mapping(slots), _)=>self.insert_entry(slots, key, &nested, style),
+            (Node::Empty, None)=>self.insert_entry(&[], key, &nested, style),
+            (other, Some(slot)) if self.is_null(other) => {
+                let mut map = serde_json::Map::new();
+                map.insert(key.to_owned(), nested);
+                self.replace_value(slot, &Value::Object(map), style);
+            },
+            _=>return Err(Box::from(format!("{} is not a mapping", format_path(&path[..chain.len()])))),
+        }
+        Ok( () )
+    }
+
+    /**
+     * Removes the lines for a slot.  If the slot starts part way along a line (like the first key of a mapping inside
+     * a sequence item) then the next sibling is pulled up onto that line.  Returns false if that can't be done because
+     * there is no next sibling, in which case the parent needs to be removed instead.
+     */
+    fn remove_slot(&mut self, slot:&Slot, next:Option<&Slot>) -> bool {
+        if slot.col==indent_of(&self.lines[slot.line]) {
+            self.splice(slot.line, slot.end, vec![]);
+            true
+        } else if let Some(next) = next {
+            let joined = format!("{}{}", &self.lines[slot.line][..slot.col], &self.lines[next.line][next.col..]);
+            self.splice(slot.line, next.line + 1, vec![joined]);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn delete(&mut self, path:&[PathSegment]) -> Result<(), Box<dyn Error>> {
+        let root = self.parse();
+        let chain = self.resolve(&root, path)?;
+        if chain.len() < path.len() {
+            return Ok( () );
+        }
+
+        //walk back up the chain until we find something that can be removed on its own
+        for depth in (0..chain.len()).rev() {
+            let siblings = match depth {
+                0=>&root,
+                _=>&chain[depth-1].value,
+            };
+            let next = match siblings {
+                Node::Mapping(slots) | Node::Sequence(slots)=>slots.iter()
+                    .position(|s| std::ptr::eq(s, chain[depth]))
+                    .and_then(|idx| slots.get(idx + 1)),
+                _=>None,
+            };
+            self.check_no_anchor(chain[depth], &path[..=depth])?;
+            if self.remove_slot(chain[depth], next) {
+                return Ok( () );
+            }
+        }
+        Err(Box::from(format!("could not work out how to delete {}", format_path(path))))
+    }
+
+    pub fn append(&mut self, path:&[PathSegment], value:&Value) -> Result<(), Box<dyn Error>> {
+        let root = self.parse();
+        let style = self.style(&root);
+        let chain = self.resolve(&root, path)?;
+        if chain.len() < path.len() {
+            return self.set(path, &Value::Array(vec![value.clone()]));
+        }
+
+        let slot = chain[chain.len()-1];
+        match &slot.value {
+            Node::Sequence(items)=>{
+                let last = &items[items.len()-1];
+                let lines = render_item(value, last.col, style);
+                self.splice(last.end, last.end, lines);
+                Ok( () )
+            },
+            Node::Scalar{line, col, end_col} if self.lines[*line][*col..*end_col].starts_with('[') && self.lines[*line][*col..*end_col].ends_with(']') =>{
+                let text = &self.lines[*line];
+                let inner = text[col+1..end_col-1].trim();
+                let rendered = match value {
+                    Value::String(s) if s.contains([',', '[', ']', '{', '}']) => quoted(s),
+                    other=>render_scalar(other, None),
+                };
+                let flow = if inner.is_empty() {
+                    format!("[{}]", rendered)
+                } else {
+                    format!("[{}, {}]", inner, rendered)
+                };
+                self.lines[*line] = format!("{}{}{}", &text[..*col], flow, &text[*end_col..]);
+                Ok( () )
+            },
+            other if self.is_null(other) => {
+                self.replace_value(slot, &Value::Array(vec![value.clone()]), style);
+                Ok( () )
+            },
+            _=>Err(Box::from(format!("{} is not a sequence", format_path(path)))),
+        }
+    }
+
+    pub fn rename(&mut self, path:&[PathSegment], to:&str) -> Result<(), Box<dyn Error>> {
+        let root = self.parse();
+        let chain = self.resolve(&root, path)?;
+        if chain.len() < path.len() {
+            return Ok( () );
+        }
+        let slot = chain[chain.len()-1];
+        let key = slot.key.as_ref().ok_or_else(|| format!("{} is a sequence item, not a key", format_path(path)))?;
+        if key.name==to {
+            return Ok( () );
+        }
+
+        let siblings = match chain.len() {
+            1=>&root,
+            n=>&chain[n-2].value,
+        };
+        if let Node::Mapping(slots) = siblings {
+            if slots.iter().any(|s| s.key.as_ref().map(|k| k.name.as_str())==Some(to)) {
+                return Err(Box::from(format!("can't rename {} as {} already exists", format_path(path), to)));
+            }
+        }
+
+        let line = &self.lines[slot.line];
+        self.lines[slot.line] = format!("{}{}{}", &line[..slot.col], render_key(to), &line[key.end_col..]);
+        Ok( () )
+    }
+}
+
+impl Display for YamlDocument {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let newline = if self.crlf { "\r\n" } else { "\n" };
+        f.write_str(&self.lines.join(newline))?;
+        if self.trailing_newline && !self.lines.is_empty() {
+            f.write_str(newline)?;
+        }
+        Ok( () )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use crate::structured::parse_path;
+
+    const WORKFLOW:&str = "# Build the thing\nname: CI\n\non:\n  push:\n    branches: [main]\n\njobs:\n  build:\n    runs-on: ubuntu-20.04  # pinned\n    steps:\n      - uses: actions/checkout@v2\n      - name: Setup\n        uses: actions/setup-node@v2\n        with:\n          node-version: '16'\n      - run: |\n          npm ci\n          npm test\n";
+
+    fn edited(content:&str, f:impl Fn(&mut YamlDocument)->Result<(), Box<dyn Error>>) -> Result<String, Box<dyn Error>> {
+        let mut doc = YamlDocument::new(content);
+        f(&mut doc)?;
+        Ok(doc.to_string())
+    }
+
+    #[test]
+    fn test_untouched_roundtrip() {
+        assert_eq!(YamlDocument::new(WORKFLOW).to_string(), WORKFLOW);
+        assert_eq!(YamlDocument::new("a: 1\r\nb: 2").to_string(), "a: 1\r\nb: 2");
+    }
+
+    #[test]
+    fn test_set() -> Result<(), Box<dyn Error>> {
+        let result = edited(WORKFLOW, |doc| {
+            doc.set(&parse_path("jobs.build.runs-on")?, &json!("ubuntu-latest"))?;
+            doc.set(&parse_path("jobs.build.steps[1].with.node-version")?, &json!("20"))?;
+            doc.set(&parse_path("jobs.build.steps[0].uses")?, &json!("actions/checkout@v4"))?;
+            doc.set(&parse_path("jobs.build.timeout-minutes")?, &json!(10))?;
+            doc.set(&parse_path("permissions.contents")?, &json!("read"))
+        })?;
+        assert_eq!(result, "# Build the thing\nname: CI\n\non:\n  push:\n    branches: [main]\n\njobs:\n  build:\n    runs-on: ubuntu-latest  # pinned\n    steps:\n      - uses: actions/checkout@v4\n      - name: Setup\n        uses: actions/setup-node@v2\n        with:\n          node-version: '20'\n      - run: |\n          npm ci\n          npm test\n    timeout-minutes: 10\npermissions:\n  contents: read\n");
+
+        let nested = edited("a:\n  b: 1\n", |doc| doc.set(&parse_path("a.b")?, &json!({"c": [1, 2], "d": "on"})))?;
+        assert_eq!(nested, "a:\n  b:\n    c:\n      - 1\n      - 2\n    d: \"on\"\n");
+        Ok( () )
+    }
+
+    #[test]
+    fn test_delete() -> Result<(), Box<dyn Error>> {
+        let result = edited(WORKFLOW, |doc| {
+            doc.delete(&parse_path("jobs.build.steps[1].name")?)?;
+            doc.delete(&parse_path("jobs.build.steps[2]")?)?;
+            doc.delete(&parse_path("on.pull_request")?)
+        })?;
+        assert_eq!(result, "# Build the thing\nname: CI\n\non:\n  push:\n    branches: [main]\n\njobs:\n  build:\n    runs-on: ubuntu-20.04  # pinned\n    steps:\n      - uses: actions/checkout@v2\n      - uses: actions/setup-node@v2\n        with:\n          node-version: '16'\n");
+
+        let whole_item = edited("steps:\n- uses: a\n- uses: b\n", |doc| doc.delete(&parse_path("steps[0].uses")?))?;
+        assert_eq!(whole_item, "steps:\n- uses: b\n");
+        Ok( () )
+    }
+
+    #[test]
+    fn test_append_and_rename() -> Result<(), Box<dyn Error>> {
+        let result = edited(WORKFLOW, |doc| {
+            doc.append(&parse_path("on.push.branches")?, &json!("release/*"))?;
+            doc.append(&parse_path("jobs.build.steps")?, &json!({"run": "npm run lint"}))?;
+            doc.rename(&parse_path("jobs.build")?, "test")
+        })?;
+        assert_eq!(result, "# Build the thing\nname: CI\n\non:\n  push:\n    branches: [main, release/*]\n\njobs:\n  test:\n    runs-on: ubuntu-20.04  # pinned\n    steps:\n      - uses: actions/checkout@v2\n      - name: Setup\n        uses: actions/setup-node@v2\n        with:\n          node-version: '16'\n      - run: |\n          npm ci\n          npm test\n      - run: npm run lint\n");
+
+        let unindented = edited("list:\n- a\nother:\n", |doc| {
+            doc.append(&parse_path("list")?, &json!("b"))?;
+            doc.append(&parse_path("other")?, &json!("c"))
+        })?;
+        assert_eq!(unindented, "list:\n- a\n- b\nother:\n- c\n");
+
+        assert!(edited(WORKFLOW, |doc| doc.append(&parse_path("name")?, &json!("x"))).is_err());
+        assert!(edited(WORKFLOW, |doc| doc.rename(&parse_path("jobs.build")?, "build")).is_ok());
+        assert!(edited("a: 1\nb: 2\n", |doc| doc.rename(&parse_path("a")?, "b")).is_err());
+        Ok( () )
+    }
+
+    #[test]
+    fn test_block_scalars() -> Result<(), Box<dyn Error>> {
+        //the lines in a block scalar that look like comments or document markers are part of its value
+        let script = "script: |\n  make\n  # not a comment\n  ---\nnext: 1\n";
+        assert_eq!(edited(script, |doc| doc.set(&parse_path("script")?, &json!("make all")))?, "script: make all\nnext: 1\n");
+        assert_eq!(edited(script, |doc| doc.delete(&parse_path("script")?))?, "next: 1\n");
+
+        let steps = "steps:\n  - run: >-\n      echo hi\n      # still the command\n";
+        assert_eq!(edited(steps, |doc| doc.append(&parse_path("steps")?, &json!({"run": "make"})))?,
+            "steps:\n  - run: >-\n      echo hi\n      # still the command\n  - run: make\n");
+        assert!(edited(script, |doc| doc.set(&parse_path("script.x")?, &json!(1))).is_err());
+        Ok( () )
+    }
+
+    #[test]
+    fn test_anchors_and_aliases() -> Result<(), Box<dyn Error>> {
+        let doc = "base: &base\n  image: node\njob:\n  <<: *base\n  name: test\n";
+        for result in [
+            edited(doc, |d| d.delete(&parse_path("base")?)),
+            edited(doc, |d| d.set(&parse_path("base")?, &json!("x"))),
+            edited(doc, |d| d.set(&parse_path("base.image")?, &json!("python"))),
+            edited(doc, |d| d.set(&parse_path("job.<<.image")?, &json!("python"))),
+        ] {
+            assert!(result.is_err());
+        }
+        //the rest of the document can still be edited
+        assert_eq!(edited(doc, |d| d.set(&parse_path("job.name")?, &json!("lint")))?, "base: &base\n  image: node\njob:\n  <<: *base\n  name: lint\n");
+        Ok( () )
+    }
+
+    #[test]
+    fn test_flow_collections() -> Result<(), Box<dyn Error>> {
+        let doc = "with: {node: 16, cache: npm}\nbranches: [main,\n  dev]\n";
+        let err = edited(doc, |d| d.set(&parse_path("with.node")?, &json!(20))).unwrap_err();
+        assert!(err.to_string().contains("flow mapping"));
+        assert!(edited(doc, |d| d.append(&parse_path("with")?, &json!("x"))).is_err());
+        //only a flow sequence on a single line can be appended to
+        assert!(edited(doc, |d| d.append(&parse_path("branches")?, &json!("x"))).is_err());
+        assert_eq!(edited(doc, |d| d.set(&parse_path("with")?, &json!({"node": 20})))?, "with:\n  node: 20\nbranches: [main,\n  dev]\n");
+        Ok( () )
+    }
+}